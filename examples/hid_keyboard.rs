@@ -10,8 +10,9 @@ async fn main() {
         Box::new(usbip::hid::UsbHidKeyboardHandler::new_keyboard())
             as Box<dyn usbip::UsbInterfaceHandler + Send>,
     ));
-    let server = Arc::new(usbip::UsbIpServer::new_simulated(vec![
-        usbip::UsbDevice::new(0).with_interface(
+    let device = usbip::UsbDevice::new(0)
+        .with_remote_wakeup(true)
+        .with_interface(
             usbip::ClassCode::HID as u8,
             0x00,
             0x00,
@@ -21,10 +22,12 @@ async fn main() {
                 attributes: 0x03,      // Interrupt
                 max_packet_size: 0x08, // 8 bytes
                 interval: 10,
+                ..Default::default()
             }],
             handler.clone(),
-        ),
-    ]));
+        );
+    let remote_wakeup = device.remote_wakeup_handle();
+    let server = Arc::new(usbip::UsbIpServer::new_simulated(vec![device]));
     let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 3240);
     tokio::spawn(usbip::server(addr, server));
 
@@ -39,6 +42,8 @@ async fn main() {
             hid.pending_key_events
                 .push_back(usbip::hid::UsbHidKeyboardReport::from_ascii(b'1'));
             info!("Simulate a key event");
+            // recorded only, the key report is what reaches the client
+            remote_wakeup.signal();
         }
     }
 }
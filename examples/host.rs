@@ -5,7 +5,13 @@ use std::time::Duration;
 #[tokio::main]
 async fn main() {
     env_logger::init();
-    let server = Arc::new(usbip::UsbIpServer::new_from_host());
+    // export devices as they are plugged in, falling back to the ones present now
+    let server = Arc::new(usbip::UsbIpServer::new_simulated(vec![]));
+    let (server, _hotplug) =
+        match server.watch_host_devices(|_| true, |_, _| true, Default::default()) {
+            Ok(hotplug) => (server, Some(hotplug)),
+            Err(_) => (Arc::new(usbip::UsbIpServer::new_from_host()), None),
+        };
     let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 3240);
     tokio::spawn(usbip::server(addr, server));
 
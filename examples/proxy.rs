@@ -0,0 +1,27 @@
+use std::net::*;
+use std::sync::Arc;
+use std::time::Duration;
+
+#[tokio::main]
+async fn main() {
+    env_logger::init();
+    // re-export the devices of the server given as argument, e.g. 192.168.1.2:3240
+    let upstream: SocketAddr = std::env::args()
+        .nth(1)
+        .expect("usage: proxy <upstream address>")
+        .parse()
+        .expect("invalid upstream address");
+    let server = Arc::new(usbip::UsbIpServer::new_simulated(vec![]));
+    let count = server
+        .add_upstream_devices(&usbip::UsbIpBackend::new(upstream))
+        .await
+        .expect("list upstream devices");
+    println!("Re-exporting {} devices of {}", count, upstream);
+    let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 3240);
+    tokio::spawn(usbip::server(addr, server));
+
+    loop {
+        // sleep 1s
+        tokio::time::sleep(Duration::new(1, 0)).await;
+    }
+}
@@ -0,0 +1,337 @@
+//! Inspect and manage the devices and sessions of a running server
+use super::*;
+
+/// A device of a [UsbIpServer], see [UsbIpServer::list_devices]
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct UsbIpDeviceInfo {
+    pub bus_id: String,
+    pub vendor_id: u16,
+    pub product_id: u16,
+    /// The session which imported the device, `None` while it is available
+    pub session: Option<UsbIpSessionInfo>,
+}
+
+/// A client session which imported a device
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct UsbIpSessionInfo {
+    /// Address of the client, unknown for sessions started with [handler]
+    pub peer: Option<SocketAddr>,
+    pub attached_at: SystemTime,
+}
+
+impl UsbIpServer {
+    /// List the available devices, then the devices in use along with the session which imported them
+    pub async fn list_devices(&self) -> Vec<UsbIpDeviceInfo> {
+        let mut devices: Vec<_> = self
+            .available_devices
+            .read()
+            .await
+            .iter()
+            .map(|dev| UsbIpDeviceInfo {
+                bus_id: dev.bus_id.clone(),
+                vendor_id: dev.vendor_id,
+                product_id: dev.product_id,
+                session: None,
+            })
+            .collect();
+        let sessions = self.sessions.read().await;
+        let used_devices = self.used_devices.read().await;
+        let mut used: Vec<_> = used_devices
+            .values()
+            .map(|dev| UsbIpDeviceInfo {
+                bus_id: dev.bus_id.clone(),
+                vendor_id: dev.vendor_id,
+                product_id: dev.product_id,
+                session: sessions.get(&dev.bus_id).map(|session| UsbIpSessionInfo {
+                    peer: session.peer,
+                    attached_at: session.attached_at,
+                }),
+            })
+            .collect();
+        used.sort_by(|a, b| a.bus_id.cmp(&b.bus_id));
+        devices.extend(used);
+        devices
+    }
+
+    /// Close the connection of the session which imported the device, giving the device back
+    ///
+    /// Returns once the device is available again, or withdrawn if it got unplugged meanwhile.
+    /// The client sees its connection closed, like when the server shuts down.
+    pub async fn detach_device(&self, bus_id: &str) -> std::result::Result<(), Error> {
+        let sessions = self.sessions.read().await;
+        let session = sessions
+            .get(bus_id)
+            .ok_or_else(|| Error::DeviceNotAttached(bus_id.to_string()))?;
+        // registered while the session is listed, so before it ends
+        let ended_signal = session.ended.clone();
+        let ended = ended_signal.notified();
+        session.close.notify_one();
+        std::mem::drop(sessions);
+        info!("Detaching device {} from its session", bus_id);
+        ended.await;
+        Ok(())
+    }
+
+    /// Unplug a device, as if its cable got pulled
+    ///
+    /// URBs pending on the device fail with `-ENODEV` and the session which imported it is closed. The device
+    /// is removed from the server and returned, to plug it back in with [UsbIpServer::add_device], see
+    /// [UsbIpServer::replug_device].
+    pub async fn unplug_device(&self, bus_id: &str) -> std::result::Result<UsbDevice, Error> {
+        {
+            let mut available_devices = self.available_devices.write().await;
+            if let Some(index) = available_devices.iter().position(|d| d.bus_id == bus_id) {
+                info!("Unplugging available device {}", bus_id);
+                return Ok(available_devices.remove(index));
+            }
+        }
+
+        let sessions = self.sessions.read().await;
+        let session = sessions
+            .get(bus_id)
+            .ok_or_else(|| Error::DeviceNotFound(bus_id.to_string()))?;
+        // a copy sharing the handlers, as the session drops the device once detached
+        let device = self.used_devices.read().await.get(bus_id).cloned();
+        // registered while the session is listed, so before it ends
+        let ended_signal = session.ended.clone();
+        let ended = ended_signal.notified();
+        session.remove.notify_one();
+        std::mem::drop(sessions);
+        info!("Unplugging device {} from its session", bus_id);
+        ended.await;
+        device.ok_or_else(|| Error::DeviceNotFound(bus_id.to_string()))
+    }
+
+    /// Unplug a device, then plug it back in after `delay`, changed by `change` first
+    ///
+    /// Changing its descriptors makes clients enumerate it as a new device, e.g. a device rebooting in DFU mode
+    /// with another product id.
+    pub async fn replug_device<F: FnOnce(&mut UsbDevice)>(
+        &self,
+        bus_id: &str,
+        delay: std::time::Duration,
+        change: F,
+    ) -> std::result::Result<(), Error> {
+        let mut device = self.unplug_device(bus_id).await?;
+        tokio::time::sleep(delay).await;
+        change(&mut device);
+        info!("Plugging device {} back in", device.bus_id);
+        self.add_device(device).await.map(|_| ())
+    }
+}
+
+/// A command of the admin endpoint, see [serve_admin]
+#[cfg(feature = "admin")]
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum AdminRequest {
+    /// List the devices, replying with `{"devices": [...]}`
+    List,
+    /// Add a simulated device, replying with `{"bus_id": "..."}`
+    Add { device: DeviceConfig },
+    /// Remove an available device
+    Remove { bus_id: String },
+    /// Close the session which imported a device, giving it back
+    Detach { bus_id: String },
+}
+
+#[cfg(feature = "admin")]
+impl UsbIpServer {
+    /// Run an admin command, returning its JSON reply, `{"error": "..."}` when it fails
+    pub async fn handle_admin_request(
+        &self,
+        request: AdminRequest,
+        registry: &HandlerRegistry,
+    ) -> serde_json::Value {
+        let res = match request {
+            AdminRequest::List => {
+                let devices = self.list_devices().await;
+                Ok(serde_json::json!({ "devices": devices }))
+            }
+            AdminRequest::Add { device } => {
+                let index = self.available_devices.read().await.len()
+                    + self.used_devices.read().await.len();
+                match device.build(index as u32, registry) {
+                    Ok(device) => self
+                        .add_device(device)
+                        .await
+                        .map(|bus_id| serde_json::json!({ "bus_id": bus_id })),
+                    // an invalid description or a handler failing to be created
+                    Err(err) => Err(Error::from(err)),
+                }
+            }
+            AdminRequest::Remove { bus_id } => self
+                .remove_device(&bus_id)
+                .await
+                .map(|_| serde_json::json!({})),
+            AdminRequest::Detach { bus_id } => self
+                .detach_device(&bus_id)
+                .await
+                .map(|_| serde_json::json!({})),
+        };
+        res.unwrap_or_else(|err| serde_json::json!({ "error": err.to_string() }))
+    }
+}
+
+/// Serve the admin endpoint of `server` on a Unix socket at `path`
+///
+/// Each line sent to the socket is a JSON [AdminRequest], answered by a line of JSON, for example:
+/// ```text
+/// {"command": "detach", "bus_id": "1-1"}
+/// {}
+/// ```
+/// Simulated devices are added with the handlers of `registry`. A stale socket left at `path` is replaced, but not
+/// one another process still serves.
+/// Only the owner of the process may connect to the socket, which is created with mode 0600.
+#[cfg(all(unix, feature = "admin"))]
+pub async fn serve_admin<P: AsRef<std::path::Path>>(
+    path: P,
+    server: Arc<UsbIpServer>,
+    registry: Arc<HandlerRegistry>,
+) -> Result<()> {
+    use std::os::unix::fs::FileTypeExt;
+    use tokio::io::{AsyncBufReadExt, BufReader};
+
+    let path = path.as_ref();
+    if std::fs::symlink_metadata(path).is_ok_and(|meta| meta.file_type().is_socket()) {
+        if std::os::unix::net::UnixStream::connect(path).is_ok() {
+            return Err(std::io::Error::new(
+                ErrorKind::AddrInUse,
+                format!("{} is served by another process", path.display()),
+            ));
+        }
+        std::fs::remove_file(path)?;
+    }
+    let listener = bind_private(path)?;
+    loop {
+        let (socket, _) = listener.accept().await?;
+        let server = server.clone();
+        let registry = registry.clone();
+        tokio::spawn(async move {
+            let (reader, mut writer) = socket.into_split();
+            let mut lines = BufReader::new(reader).lines();
+            while let Ok(Some(line)) = lines.next_line().await {
+                if line.trim().is_empty() {
+                    continue;
+                }
+                let reply = match serde_json::from_str(&line) {
+                    Ok(request) => server.handle_admin_request(request, &registry).await,
+                    Err(err) => serde_json::json!({ "error": err.to_string() }),
+                };
+                let mut reply = reply.to_string();
+                reply.push('\n');
+                if writer.write_all(reply.as_bytes()).await.is_err() {
+                    break;
+                }
+            }
+        });
+    }
+}
+
+/// Bind a Unix socket at `path` with mode 0600
+///
+/// The socket is bound in a directory only accessible to the owner, then linked at `path` once restricted, so that
+/// no other user can connect to it meanwhile whatever the umask.
+#[cfg(all(unix, feature = "admin"))]
+fn bind_private(path: &std::path::Path) -> Result<tokio::net::UnixListener> {
+    use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+
+    let name = path
+        .file_name()
+        .ok_or_else(|| std::io::Error::new(ErrorKind::InvalidInput, "no socket file name"))?;
+    let mut dir_name = std::ffi::OsString::from(".");
+    dir_name.push(name);
+    dir_name.push(format!(".{}", std::process::id()));
+    let dir = path.with_file_name(dir_name);
+    std::fs::DirBuilder::new().mode(0o700).create(&dir)?;
+    let private_path = dir.join("socket");
+    let res = tokio::net::UnixListener::bind(&private_path).and_then(|listener| {
+        // the endpoint detaches and unplugs devices of any client
+        std::fs::set_permissions(&private_path, std::fs::Permissions::from_mode(0o600))?;
+        // unlike a rename, fails if anything exists at the path
+        std::fs::hard_link(&private_path, path)?;
+        Ok(listener)
+    });
+    std::fs::remove_dir_all(&dir).ok();
+    res
+}
+
+#[cfg(all(test, unix, feature = "admin"))]
+mod tests {
+    use super::*;
+    use crate::util::tests::*;
+    use tokio::io::{AsyncBufReadExt, BufReader, Lines};
+    use tokio::net::unix::{OwnedReadHalf, OwnedWriteHalf};
+    use tokio::net::UnixStream;
+
+    async fn request(
+        writer: &mut OwnedWriteHalf,
+        lines: &mut Lines<BufReader<OwnedReadHalf>>,
+        line: &str,
+    ) -> serde_json::Value {
+        writer.write_all(line.as_bytes()).await.unwrap();
+        writer.write_all(b"\n").await.unwrap();
+        let reply = lines.next_line().await.unwrap().unwrap();
+        serde_json::from_str(&reply).unwrap()
+    }
+
+    #[tokio::test]
+    async fn admin_endpoint() {
+        setup_test_logger();
+        let path = std::env::temp_dir().join(format!("usbip-admin-{}.sock", std::process::id()));
+        let server = Arc::new(UsbIpServer::default());
+        tokio::spawn(serve_admin(
+            path.clone(),
+            server.clone(),
+            Arc::new(HandlerRegistry::default()),
+        ));
+        let socket = loop {
+            if let Ok(socket) = UnixStream::connect(&path).await {
+                break socket;
+            }
+            tokio::task::yield_now().await;
+        };
+        let (reader, mut writer) = socket.into_split();
+        let mut lines = BufReader::new(reader).lines();
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        // the socket is not stale while served
+        let err = serve_admin(
+            path.clone(),
+            server.clone(),
+            Arc::new(HandlerRegistry::default()),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::AddrInUse);
+
+        let reply = request(
+            &mut writer,
+            &mut lines,
+            r#"{"command": "add", "device": {"vendor_id": 4660, "product_id": 22136, "configurations": [{"interfaces": [{"handler": {"kind": "cdc_acm"}}]}]}}"#,
+        )
+        .await;
+        assert_eq!(reply["bus_id"], "0-1");
+        let reply = request(&mut writer, &mut lines, r#"{"command": "list"}"#).await;
+        assert_eq!(reply["devices"][0]["bus_id"], "0-1");
+        assert_eq!(reply["devices"][0]["vendor_id"], 0x1234);
+        assert_eq!(reply["devices"][0]["session"], serde_json::Value::Null);
+
+        let detach = r#"{"command": "detach", "bus_id": "0-1"}"#;
+        let reply = request(&mut writer, &mut lines, detach).await;
+        assert_eq!(reply["error"], "Device 0-1 is not in use");
+        let remove = r#"{"command": "remove", "bus_id": "0-1"}"#;
+        let reply = request(&mut writer, &mut lines, remove).await;
+        assert_eq!(reply, serde_json::json!({}));
+        assert!(server.list_devices().await.is_empty());
+        let reply = request(&mut writer, &mut lines, r#"{"command": "reboot"}"#).await;
+        assert!(reply["error"].is_string());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,756 @@
+//! Backends giving access to USB devices of the host
+use super::*;
+use std::time::Duration;
+
+/// Enumerate USB devices of the host
+///
+/// Implemented for libusb contexts of [rusb], and by [MockUsbHost] to test passthrough without hardware.
+pub trait UsbHostBackend {
+    type Device: UsbHostDevice;
+
+    /// List the devices currently plugged in
+    fn devices(&self) -> rusb::Result<Vec<Self::Device>>;
+}
+
+/// A USB device of the host, not opened yet
+pub trait UsbHostDevice: Send + 'static {
+    type Handle: UsbHostHandle;
+
+    fn bus_number(&self) -> u8;
+    fn address(&self) -> u8;
+    /// Bus id to export the device under, named after its port chain like the Linux kernel does by default
+    fn bus_id(&self) -> String
+    where
+        Self: Sized,
+    {
+        host_bus_id(self)
+    }
+    /// Ports from the root hub to the device, empty for root hubs
+    fn port_numbers(&self) -> rusb::Result<Vec<u8>>;
+    fn speed(&self) -> UsbSpeed;
+    /// Raw device descriptor
+    fn device_descriptor(&self) -> rusb::Result<Vec<u8>>;
+    /// Raw descriptor of the active configuration, followed by its interface and endpoint descriptors
+    fn active_config_descriptor(&self, timeout: Duration) -> rusb::Result<Vec<u8>>;
+    fn open(&self) -> rusb::Result<Self::Handle>;
+}
+
+/// An open USB device of the host, driven by [UsbHostDeviceHandler] and [UsbHostInterfaceHandler]
+///
+/// Requests fail with [rusb::Error::Timeout] unless they complete within their `timeout`, a zero timeout waits
+/// until they complete.
+pub trait UsbHostHandle: Send + 'static {
+    /// Raw descriptor of the active configuration, see [UsbHostDevice::active_config_descriptor]
+    fn active_config_descriptor(&self, timeout: Duration) -> rusb::Result<Vec<u8>>;
+    /// Raw BOS descriptor with its device capabilities
+    fn read_bos_descriptor(&self, timeout: Duration) -> rusb::Result<Vec<u8>>;
+    /// LANGIDs of the string descriptors
+    fn read_languages(&self, timeout: Duration) -> rusb::Result<Vec<u16>>;
+    fn read_string_descriptor(
+        &self,
+        language: u16,
+        index: u8,
+        timeout: Duration,
+    ) -> rusb::Result<String>;
+
+    fn read_control(
+        &self,
+        request_type: u8,
+        request: u8,
+        value: u16,
+        index: u16,
+        buf: &mut [u8],
+        timeout: Duration,
+    ) -> rusb::Result<usize>;
+    fn write_control(
+        &self,
+        request_type: u8,
+        request: u8,
+        value: u16,
+        index: u16,
+        buf: &[u8],
+        timeout: Duration,
+    ) -> rusb::Result<usize>;
+    fn read_bulk(&self, endpoint: u8, buf: &mut [u8], timeout: Duration) -> rusb::Result<usize>;
+    fn write_bulk(&self, endpoint: u8, buf: &[u8], timeout: Duration) -> rusb::Result<usize>;
+    fn read_interrupt(
+        &self,
+        endpoint: u8,
+        buf: &mut [u8],
+        timeout: Duration,
+    ) -> rusb::Result<usize>;
+    fn write_interrupt(&self, endpoint: u8, buf: &[u8], timeout: Duration) -> rusb::Result<usize>;
+
+    fn kernel_driver_active(&self, interface: u8) -> rusb::Result<bool>;
+    fn detach_kernel_driver(&self, interface: u8) -> rusb::Result<()>;
+    fn attach_kernel_driver(&self, interface: u8) -> rusb::Result<()>;
+    fn claim_interface(&self, interface: u8) -> rusb::Result<()>;
+    fn release_interface(&self, interface: u8) -> rusb::Result<()>;
+    fn set_alternate_setting(&self, interface: u8, alternate_setting: u8) -> rusb::Result<()>;
+    fn set_active_configuration(&self, configuration: u8) -> rusb::Result<()>;
+    fn unconfigure(&self) -> rusb::Result<()>;
+    fn clear_halt(&self, endpoint: u8) -> rusb::Result<()>;
+    fn reset(&self) -> rusb::Result<()>;
+
+    /// Called when a client imports the device, before its interfaces are claimed
+    fn open_session(&mut self) -> rusb::Result<()> {
+        Ok(())
+    }
+
+    /// Called when the client detaches the device, or once its descriptors have been read when exporting it
+    fn close_session(&mut self) {}
+
+    /// Keep completing transfers submitted by [UsbHostHandle::submit_transfer] while the result is alive
+    ///
+    /// Held by [UsbHostDeviceHandler] while a client has imported the device.
+    fn handle_events(&self) -> Option<Arc<dyn Any + Send + Sync>> {
+        None
+    }
+
+    /// Submit a transfer without waiting for its completion, see [UsbInterfaceHandler::submit_urb]
+    ///
+    /// When `None` is returned, the transfer is performed with the blocking methods instead.
+    fn submit_transfer(
+        _handle: &Arc<Mutex<Self>>,
+        _ep: UsbEndpoint,
+        _transfer_buffer_length: u32,
+        _setup: SetupPacket,
+        _req: &[u8],
+        _timeout: Duration,
+    ) -> Option<PendingTransfer>
+    where
+        Self: Sized,
+    {
+        None
+    }
+}
+
+impl<T: UsbContext + 'static> UsbHostBackend for T {
+    type Device = Device<T>;
+
+    fn devices(&self) -> rusb::Result<Vec<Self::Device>> {
+        Ok(UsbContext::devices(self)?.iter().collect())
+    }
+}
+
+impl<T: UsbContext + 'static> UsbHostDevice for Device<T> {
+    type Handle = DeviceHandle<T>;
+
+    fn bus_number(&self) -> u8 {
+        Device::bus_number(self)
+    }
+
+    fn address(&self) -> u8 {
+        Device::address(self)
+    }
+
+    fn port_numbers(&self) -> rusb::Result<Vec<u8>> {
+        Device::port_numbers(self)
+    }
+
+    fn speed(&self) -> UsbSpeed {
+        host_speed(Device::speed(self))
+    }
+
+    fn device_descriptor(&self) -> rusb::Result<Vec<u8>> {
+        let desc = Device::device_descriptor(self)?;
+        let usb_version = crate::device::Version::from(desc.usb_version()).to_bcd();
+        let device_version = crate::device::Version::from(desc.device_version()).to_bcd();
+        let mut result = vec![
+            0x12,                         // bLength
+            DescriptorType::Device as u8, // bDescriptorType
+        ];
+        result.extend_from_slice(&usb_version.to_le_bytes()); // bcdUSB
+        result.extend_from_slice(&[
+            desc.class_code(),      // bDeviceClass
+            desc.sub_class_code(),  // bDeviceSubClass
+            desc.protocol_code(),   // bDeviceProtocol
+            desc.max_packet_size(), // bMaxPacketSize0
+        ]);
+        result.extend_from_slice(&desc.vendor_id().to_le_bytes()); // idVendor
+        result.extend_from_slice(&desc.product_id().to_le_bytes()); // idProduct
+        result.extend_from_slice(&device_version.to_le_bytes()); // bcdDevice
+        result.extend_from_slice(&[
+            desc.manufacturer_string_index().unwrap_or(0), // iManufacturer
+            desc.product_string_index().unwrap_or(0),      // iProduct
+            desc.serial_number_string_index().unwrap_or(0), // iSerial
+            desc.num_configurations(),                     // bNumConfigurations
+        ]);
+        Ok(result)
+    }
+
+    fn active_config_descriptor(&self, timeout: Duration) -> rusb::Result<Vec<u8>> {
+        // libusb only keeps the parsed descriptors, read the raw ones from the device
+        UsbHostHandle::active_config_descriptor(&Device::open(self)?, timeout)
+    }
+
+    fn open(&self) -> rusb::Result<Self::Handle> {
+        Device::open(self)
+    }
+}
+
+impl<T: UsbContext + 'static> UsbHostHandle for DeviceHandle<T> {
+    fn active_config_descriptor(&self, timeout: Duration) -> rusb::Result<Vec<u8>> {
+        let device = self.device();
+        let num_configurations = device.device_descriptor()?.num_configurations().max(1);
+        let configuration = device
+            .active_config_descriptor()
+            .map(|config| config.number());
+
+        // the active configuration is known by its value, fall back to the first one when unconfigured
+        let index = (0..num_configurations)
+            .find(|&i| {
+                device
+                    .config_descriptor(i)
+                    .is_ok_and(|config| Ok(config.number()) == configuration)
+            })
+            .unwrap_or(0);
+        let header = read_descriptor(self, DescriptorType::Configuration, index, 0, 9, timeout)?;
+        if header.len() < 9 {
+            return Err(rusb::Error::BadDescriptor);
+        }
+        let total_length = u16::from_le_bytes([header[2], header[3]]);
+        read_descriptor(
+            self,
+            DescriptorType::Configuration,
+            index,
+            0,
+            total_length,
+            timeout,
+        )
+    }
+
+    fn read_bos_descriptor(&self, timeout: Duration) -> rusb::Result<Vec<u8>> {
+        request_bos_descriptor(self, timeout)
+    }
+
+    fn read_languages(&self, timeout: Duration) -> rusb::Result<Vec<u16>> {
+        request_languages(self, timeout)
+    }
+
+    fn read_string_descriptor(
+        &self,
+        language: u16,
+        index: u8,
+        timeout: Duration,
+    ) -> rusb::Result<String> {
+        request_string_descriptor(self, language, index, timeout)
+    }
+
+    fn read_control(
+        &self,
+        request_type: u8,
+        request: u8,
+        value: u16,
+        index: u16,
+        buf: &mut [u8],
+        timeout: Duration,
+    ) -> rusb::Result<usize> {
+        DeviceHandle::read_control(self, request_type, request, value, index, buf, timeout)
+    }
+
+    fn write_control(
+        &self,
+        request_type: u8,
+        request: u8,
+        value: u16,
+        index: u16,
+        buf: &[u8],
+        timeout: Duration,
+    ) -> rusb::Result<usize> {
+        DeviceHandle::write_control(self, request_type, request, value, index, buf, timeout)
+    }
+
+    fn read_bulk(&self, endpoint: u8, buf: &mut [u8], timeout: Duration) -> rusb::Result<usize> {
+        DeviceHandle::read_bulk(self, endpoint, buf, timeout)
+    }
+
+    fn write_bulk(&self, endpoint: u8, buf: &[u8], timeout: Duration) -> rusb::Result<usize> {
+        DeviceHandle::write_bulk(self, endpoint, buf, timeout)
+    }
+
+    fn read_interrupt(
+        &self,
+        endpoint: u8,
+        buf: &mut [u8],
+        timeout: Duration,
+    ) -> rusb::Result<usize> {
+        DeviceHandle::read_interrupt(self, endpoint, buf, timeout)
+    }
+
+    fn write_interrupt(&self, endpoint: u8, buf: &[u8], timeout: Duration) -> rusb::Result<usize> {
+        DeviceHandle::write_interrupt(self, endpoint, buf, timeout)
+    }
+
+    fn kernel_driver_active(&self, interface: u8) -> rusb::Result<bool> {
+        DeviceHandle::kernel_driver_active(self, interface)
+    }
+
+    fn detach_kernel_driver(&self, interface: u8) -> rusb::Result<()> {
+        DeviceHandle::detach_kernel_driver(self, interface)
+    }
+
+    fn attach_kernel_driver(&self, interface: u8) -> rusb::Result<()> {
+        DeviceHandle::attach_kernel_driver(self, interface)
+    }
+
+    fn claim_interface(&self, interface: u8) -> rusb::Result<()> {
+        DeviceHandle::claim_interface(self, interface)
+    }
+
+    fn release_interface(&self, interface: u8) -> rusb::Result<()> {
+        DeviceHandle::release_interface(self, interface)
+    }
+
+    fn set_alternate_setting(&self, interface: u8, alternate_setting: u8) -> rusb::Result<()> {
+        DeviceHandle::set_alternate_setting(self, interface, alternate_setting)
+    }
+
+    fn set_active_configuration(&self, configuration: u8) -> rusb::Result<()> {
+        DeviceHandle::set_active_configuration(self, configuration)
+    }
+
+    fn unconfigure(&self) -> rusb::Result<()> {
+        DeviceHandle::unconfigure(self)
+    }
+
+    fn clear_halt(&self, endpoint: u8) -> rusb::Result<()> {
+        DeviceHandle::clear_halt(self, endpoint)
+    }
+
+    fn reset(&self) -> rusb::Result<()> {
+        DeviceHandle::reset(self)
+    }
+
+    fn handle_events(&self) -> Option<Arc<dyn Any + Send + Sync>> {
+        Some(EventThread::acquire(self.context()))
+    }
+
+    fn submit_transfer(
+        handle: &Arc<Mutex<Self>>,
+        ep: UsbEndpoint,
+        transfer_buffer_length: u32,
+        setup: SetupPacket,
+        req: &[u8],
+        timeout: Duration,
+    ) -> Option<PendingTransfer> {
+        submit_transfer(handle, ep, transfer_buffer_length, setup, req, timeout)
+    }
+}
+
+/// Read a descriptor with a GET_DESCRIPTOR request, truncated to its bLength or `length`
+pub(crate) fn read_descriptor<H: UsbHostHandle + ?Sized>(
+    handle: &H,
+    descriptor_type: DescriptorType,
+    descriptor_index: u8,
+    language: u16,
+    length: u16,
+    timeout: Duration,
+) -> rusb::Result<Vec<u8>> {
+    let mut buf = vec![0; length as usize];
+    let len = handle.read_control(
+        0x80,
+        StandardRequest::GetDescriptor as u8,
+        (descriptor_type as u16) << 8 | descriptor_index as u16,
+        language,
+        &mut buf,
+        timeout,
+    )?;
+    buf.truncate(len);
+    Ok(buf)
+}
+
+/// Read the BOS descriptor with GET_DESCRIPTOR requests, see [UsbHostHandle::read_bos_descriptor]
+pub(crate) fn request_bos_descriptor<H: UsbHostHandle + ?Sized>(
+    handle: &H,
+    timeout: Duration,
+) -> rusb::Result<Vec<u8>> {
+    // the header tells the total length
+    let header = read_descriptor(handle, DescriptorType::BOS, 0, 0, 5, timeout)?;
+    if header.len() < 5 {
+        return Err(rusb::Error::BadDescriptor);
+    }
+    let total_length = u16::from_le_bytes([header[2], header[3]]);
+    read_descriptor(handle, DescriptorType::BOS, 0, 0, total_length, timeout)
+}
+
+/// Read the LANGIDs of the string descriptors, see [UsbHostHandle::read_languages]
+pub(crate) fn request_languages<H: UsbHostHandle + ?Sized>(
+    handle: &H,
+    timeout: Duration,
+) -> rusb::Result<Vec<u16>> {
+    let desc = read_descriptor(handle, DescriptorType::String, 0, 0, 255, timeout)?;
+    Ok(desc
+        .get(2..)
+        .unwrap_or_default()
+        .chunks_exact(2)
+        .map(|language| u16::from_le_bytes([language[0], language[1]]))
+        .collect())
+}
+
+/// Read a string descriptor, see [UsbHostHandle::read_string_descriptor]
+pub(crate) fn request_string_descriptor<H: UsbHostHandle + ?Sized>(
+    handle: &H,
+    language: u16,
+    index: u8,
+    timeout: Duration,
+) -> rusb::Result<String> {
+    let desc = read_descriptor(
+        handle,
+        DescriptorType::String,
+        index,
+        language,
+        255,
+        timeout,
+    )?;
+    let utf16: Vec<u16> = desc
+        .get(2..)
+        .unwrap_or_default()
+        .chunks_exact(2)
+        .map(|c| u16::from_le_bytes([c[0], c[1]]))
+        .collect();
+    Ok(String::from_utf16_lossy(&utf16))
+}
+
+/// An in-memory [UsbHostBackend], to test passthrough without USB hardware
+#[derive(Clone, Default)]
+pub struct MockUsbHost {
+    pub devices: Vec<MockUsbHostDevice>,
+}
+
+impl MockUsbHost {
+    pub fn new(devices: Vec<MockUsbHostDevice>) -> Self {
+        Self { devices }
+    }
+}
+
+impl UsbHostBackend for MockUsbHost {
+    type Device = MockUsbHostDevice;
+
+    fn devices(&self) -> rusb::Result<Vec<Self::Device>> {
+        Ok(self.devices.clone())
+    }
+}
+
+/// A device of a [MockUsbHost]
+///
+/// Clones and handles share the same [MockUsbHostState], which tests use to script and inspect the device.
+#[derive(Clone)]
+pub struct MockUsbHostDevice {
+    pub bus_number: u8,
+    pub address: u8,
+    pub port_numbers: Vec<u8>,
+    pub speed: UsbSpeed,
+    pub device_descriptor: Vec<u8>,
+    pub config_descriptor: Vec<u8>,
+    pub state: Arc<Mutex<MockUsbHostState>>,
+}
+
+impl MockUsbHostDevice {
+    /// Create a high speed device plugged into port `address` of the root hub of bus 1
+    pub fn new(address: u8, device_descriptor: Vec<u8>, config_descriptor: Vec<u8>) -> Self {
+        Self {
+            bus_number: 1,
+            address,
+            port_numbers: vec![address],
+            speed: UsbSpeed::High,
+            device_descriptor,
+            config_descriptor,
+            state: Default::default(),
+        }
+    }
+}
+
+/// State of a [MockUsbHostDevice]
+#[derive(Default)]
+pub struct MockUsbHostState {
+    /// String descriptors by LANGID and index
+    pub strings: HashMap<(u16, u8), String>,
+    /// BOS descriptor, stalled when missing
+    pub bos_descriptor: Option<Vec<u8>>,
+    /// Results of transfers by endpoint address, `0x80` and `0x00` for control transfers
+    ///
+    /// IN transfers without a queued result time out, OUT transfers succeed.
+    pub responses: HashMap<u8, VecDeque<rusb::Result<Vec<u8>>>>,
+    /// Setup packets of control transfers
+    pub control_requests: Vec<SetupPacket>,
+    /// Data of OUT transfers with their endpoint address
+    pub written: Vec<(u8, Vec<u8>)>,
+    /// Interfaces bound to a host driver
+    pub kernel_drivers: Vec<u8>,
+    /// Interfaces claimed by another process
+    pub busy_interfaces: Vec<u8>,
+    pub claimed_interfaces: Vec<u8>,
+    pub alternate_settings: HashMap<u8, u8>,
+    pub configuration: Option<u8>,
+    /// Endpoints whose transfers are submitted asynchronously and never complete, like a hung device
+    pub unresponsive_endpoints: Vec<u8>,
+    pub cleared_halts: Vec<u8>,
+    pub resets: usize,
+    /// Error returned when opening the device
+    pub open_error: Option<rusb::Error>,
+}
+
+impl MockUsbHostState {
+    fn transfer(&mut self, endpoint: u8, buf: &mut [u8]) -> rusb::Result<usize> {
+        match self
+            .responses
+            .get_mut(&endpoint)
+            .and_then(VecDeque::pop_front)
+        {
+            Some(Ok(data)) => {
+                let len = data.len().min(buf.len());
+                buf[..len].copy_from_slice(&data[..len]);
+                Ok(len)
+            }
+            Some(Err(err)) => Err(err),
+            None => Err(rusb::Error::Timeout),
+        }
+    }
+
+    fn write(&mut self, endpoint: u8, buf: &[u8]) -> rusb::Result<usize> {
+        self.written.push((endpoint, buf.to_vec()));
+        match self
+            .responses
+            .get_mut(&endpoint)
+            .and_then(VecDeque::pop_front)
+        {
+            Some(Err(err)) => Err(err),
+            _ => Ok(buf.len()),
+        }
+    }
+}
+
+impl UsbHostDevice for MockUsbHostDevice {
+    type Handle = MockUsbHostHandle;
+
+    fn bus_number(&self) -> u8 {
+        self.bus_number
+    }
+
+    fn address(&self) -> u8 {
+        self.address
+    }
+
+    fn port_numbers(&self) -> rusb::Result<Vec<u8>> {
+        Ok(self.port_numbers.clone())
+    }
+
+    fn speed(&self) -> UsbSpeed {
+        self.speed
+    }
+
+    fn device_descriptor(&self) -> rusb::Result<Vec<u8>> {
+        Ok(self.device_descriptor.clone())
+    }
+
+    fn active_config_descriptor(&self, _timeout: Duration) -> rusb::Result<Vec<u8>> {
+        Ok(self.config_descriptor.clone())
+    }
+
+    fn open(&self) -> rusb::Result<Self::Handle> {
+        match self.state.lock().unwrap().open_error {
+            Some(err) => Err(err),
+            None => Ok(MockUsbHostHandle {
+                device: self.clone(),
+            }),
+        }
+    }
+}
+
+/// An open [MockUsbHostDevice]
+pub struct MockUsbHostHandle {
+    device: MockUsbHostDevice,
+}
+
+impl MockUsbHostHandle {
+    fn state(&self) -> std::sync::MutexGuard<'_, MockUsbHostState> {
+        self.device.state.lock().unwrap()
+    }
+}
+
+impl UsbHostHandle for MockUsbHostHandle {
+    fn active_config_descriptor(&self, _timeout: Duration) -> rusb::Result<Vec<u8>> {
+        Ok(self.device.config_descriptor.clone())
+    }
+
+    fn read_bos_descriptor(&self, _timeout: Duration) -> rusb::Result<Vec<u8>> {
+        self.state().bos_descriptor.clone().ok_or(rusb::Error::Pipe)
+    }
+
+    fn read_languages(&self, _timeout: Duration) -> rusb::Result<Vec<u16>> {
+        let mut languages: Vec<u16> = self.state().strings.keys().map(|&(l, _)| l).collect();
+        languages.sort_unstable();
+        languages.dedup();
+        Ok(languages)
+    }
+
+    fn read_string_descriptor(
+        &self,
+        language: u16,
+        index: u8,
+        _timeout: Duration,
+    ) -> rusb::Result<String> {
+        // a device stalls requests for missing strings
+        self.state()
+            .strings
+            .get(&(language, index))
+            .cloned()
+            .ok_or(rusb::Error::Pipe)
+    }
+
+    fn read_control(
+        &self,
+        request_type: u8,
+        request: u8,
+        value: u16,
+        index: u16,
+        buf: &mut [u8],
+        _timeout: Duration,
+    ) -> rusb::Result<usize> {
+        let mut state = self.state();
+        state.control_requests.push(SetupPacket {
+            request_type,
+            request,
+            value,
+            index,
+            length: buf.len() as u16,
+        });
+        state.transfer(0x80, buf)
+    }
+
+    fn write_control(
+        &self,
+        request_type: u8,
+        request: u8,
+        value: u16,
+        index: u16,
+        buf: &[u8],
+        _timeout: Duration,
+    ) -> rusb::Result<usize> {
+        let mut state = self.state();
+        state.control_requests.push(SetupPacket {
+            request_type,
+            request,
+            value,
+            index,
+            length: buf.len() as u16,
+        });
+        state.write(0x00, buf)
+    }
+
+    fn read_bulk(&self, endpoint: u8, buf: &mut [u8], _timeout: Duration) -> rusb::Result<usize> {
+        self.state().transfer(endpoint, buf)
+    }
+
+    fn write_bulk(&self, endpoint: u8, buf: &[u8], _timeout: Duration) -> rusb::Result<usize> {
+        self.state().write(endpoint, buf)
+    }
+
+    fn read_interrupt(
+        &self,
+        endpoint: u8,
+        buf: &mut [u8],
+        _timeout: Duration,
+    ) -> rusb::Result<usize> {
+        self.state().transfer(endpoint, buf)
+    }
+
+    fn write_interrupt(&self, endpoint: u8, buf: &[u8], _timeout: Duration) -> rusb::Result<usize> {
+        self.state().write(endpoint, buf)
+    }
+
+    fn kernel_driver_active(&self, interface: u8) -> rusb::Result<bool> {
+        Ok(self.state().kernel_drivers.contains(&interface))
+    }
+
+    fn detach_kernel_driver(&self, interface: u8) -> rusb::Result<()> {
+        let mut state = self.state();
+        if !state.kernel_drivers.contains(&interface) {
+            return Err(rusb::Error::NotFound);
+        }
+        state.kernel_drivers.retain(|&i| i != interface);
+        Ok(())
+    }
+
+    fn attach_kernel_driver(&self, interface: u8) -> rusb::Result<()> {
+        let mut state = self.state();
+        if state.kernel_drivers.contains(&interface) {
+            return Err(rusb::Error::Busy);
+        }
+        state.kernel_drivers.push(interface);
+        Ok(())
+    }
+
+    fn claim_interface(&self, interface: u8) -> rusb::Result<()> {
+        let mut state = self.state();
+        if state.busy_interfaces.contains(&interface) || state.kernel_drivers.contains(&interface) {
+            return Err(rusb::Error::Busy);
+        }
+        if !state.claimed_interfaces.contains(&interface) {
+            state.claimed_interfaces.push(interface);
+        }
+        Ok(())
+    }
+
+    fn release_interface(&self, interface: u8) -> rusb::Result<()> {
+        let mut state = self.state();
+        if !state.claimed_interfaces.contains(&interface) {
+            return Err(rusb::Error::NotFound);
+        }
+        state.claimed_interfaces.retain(|&i| i != interface);
+        Ok(())
+    }
+
+    fn set_alternate_setting(&self, interface: u8, alternate_setting: u8) -> rusb::Result<()> {
+        let mut state = self.state();
+        if !state.claimed_interfaces.contains(&interface) {
+            return Err(rusb::Error::NotFound);
+        }
+        state
+            .alternate_settings
+            .insert(interface, alternate_setting);
+        Ok(())
+    }
+
+    fn set_active_configuration(&self, configuration: u8) -> rusb::Result<()> {
+        self.state().configuration = Some(configuration);
+        Ok(())
+    }
+
+    fn unconfigure(&self) -> rusb::Result<()> {
+        self.state().configuration = Some(0);
+        Ok(())
+    }
+
+    fn clear_halt(&self, endpoint: u8) -> rusb::Result<()> {
+        self.state().cleared_halts.push(endpoint);
+        Ok(())
+    }
+
+    fn reset(&self) -> rusb::Result<()> {
+        self.state().resets += 1;
+        Ok(())
+    }
+
+    fn submit_transfer(
+        handle: &Arc<Mutex<Self>>,
+        ep: UsbEndpoint,
+        _transfer_buffer_length: u32,
+        _setup: SetupPacket,
+        _req: &[u8],
+        timeout: Duration,
+    ) -> Option<PendingTransfer> {
+        let handle = handle.lock().unwrap();
+        if !handle.state().unresponsive_endpoints.contains(&ep.address) {
+            return None;
+        }
+        // never sent, the transfer completes once cancelled or timed out
+        let (sender, receiver) = oneshot::channel();
+        Some(
+            PendingTransfer::new(receiver)
+                .with_cancel(move || std::mem::drop(sender))
+                .with_timeout(timeout),
+        )
+    }
+}
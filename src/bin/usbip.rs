@@ -0,0 +1,324 @@
+//! A USB/IP daemon serving devices of the host, simulated devices and devices of other USB/IP servers
+use clap::{Args, Parser, Subcommand};
+use log::*;
+use rusb::{Device, GlobalContext, UsbContext};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::sync::Arc;
+use std::time::Duration;
+use usbip::{
+    DevicesConfig, HandlerRegistry, UsbHostDevice, UsbHostOptions, UsbIpBackend, UsbIpServer,
+    UsbIpServerBuilder,
+};
+
+#[derive(Parser)]
+#[command(
+    name = "usbip",
+    version,
+    about = "Share USB devices over the network with USB/IP"
+)]
+struct Cli {
+    /// Log level, overridden by the RUST_LOG environment variable
+    #[arg(long, global = true, default_value = "info")]
+    log_level: String,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+// parsed once, its size does not matter
+#[allow(clippy::large_enum_variant)]
+enum Command {
+    /// Export devices until interrupted
+    Serve(ServeArgs),
+    /// List the devices of this host which can be exported
+    List,
+    /// List the devices exported by a USB/IP server
+    ListRemote {
+        /// Address of the server, e.g. 192.168.1.2:3240
+        addr: SocketAddr,
+    },
+}
+
+#[derive(Args)]
+struct ServeArgs {
+    /// Address to listen on
+    #[arg(long, default_value = "0.0.0.0:3240")]
+    listen: SocketAddr,
+    /// Export all devices of the host, unless narrowed by --device, --bus-id or --serial
+    #[arg(long)]
+    host: bool,
+    /// Export host devices with this vendor and product id, in hex, e.g. 1050:0407
+    #[arg(long = "device", value_name = "VID:PID", value_parser = parse_vid_pid)]
+    devices: Vec<(u16, u16)>,
+    /// Export the host device with this bus id, e.g. 1-1.4
+    #[arg(long = "bus-id", value_name = "BUS_ID")]
+    bus_ids: Vec<String>,
+    /// Export host devices with this serial number
+    #[arg(long = "serial", value_name = "SERIAL")]
+    serials: Vec<String>,
+    /// Reset host devices when a client detaches them
+    #[arg(long)]
+    reset_on_detach: bool,
+    /// Timeout of transfers passed to host devices, in seconds, none by default
+    #[arg(long, value_name = "SECS", value_parser = parse_seconds)]
+    transfer_timeout: Option<Duration>,
+    /// Export the simulated devices of a TOML or JSON file
+    #[arg(long = "config", value_name = "FILE")]
+    configs: Vec<PathBuf>,
+    /// Re-export the devices of another USB/IP server
+    #[arg(long = "upstream", value_name = "ADDR")]
+    upstreams: Vec<SocketAddr>,
+    /// Serve the admin endpoint on this Unix socket, to list sessions and add, remove or detach devices
+    #[cfg(unix)]
+    #[arg(long, value_name = "PATH")]
+    admin_socket: Option<PathBuf>,
+    /// Refuse connections beyond this many at once
+    #[arg(long, value_name = "COUNT")]
+    max_connections: Option<usize>,
+    /// Fail URBs submitted while this many are pending in a session
+    #[arg(long, value_name = "COUNT")]
+    max_in_flight_urbs: Option<usize>,
+    /// Close connections submitting a URB with a larger transfer buffer, in bytes
+    #[arg(long, value_name = "BYTES")]
+    max_transfer_size: Option<u32>,
+    /// Close connections which have not imported a device after this long, in seconds
+    #[arg(long, value_name = "SECS", value_parser = parse_seconds)]
+    handshake_timeout: Option<Duration>,
+    /// Close connections sending no command for this long, in seconds
+    #[arg(long, value_name = "SECS", value_parser = parse_seconds)]
+    idle_timeout: Option<Duration>,
+    /// Send responses right away, disabling Nagle's algorithm
+    #[arg(long)]
+    tcp_nodelay: bool,
+    /// Probe connections silent for this long, in seconds, closing them if the client is gone
+    #[arg(long, value_name = "SECS", value_parser = parse_seconds)]
+    tcp_keepalive: Option<Duration>,
+}
+
+impl ServeArgs {
+    /// A server builder with the limits and timeouts of the arguments
+    fn server_builder(&self) -> UsbIpServerBuilder {
+        let mut builder = UsbIpServer::builder()
+            .with_host_options(self.host_options())
+            .with_tcp_nodelay(self.tcp_nodelay);
+        if let Some(max) = self.max_connections {
+            builder = builder.with_max_connections(max);
+        }
+        if let Some(max) = self.max_in_flight_urbs {
+            builder = builder.with_max_in_flight_urbs(max);
+        }
+        if let Some(max) = self.max_transfer_size {
+            builder = builder.with_max_transfer_size(max);
+        }
+        if let Some(timeout) = self.handshake_timeout {
+            builder = builder.with_handshake_timeout(timeout);
+        }
+        if let Some(timeout) = self.idle_timeout {
+            builder = builder.with_idle_timeout(timeout);
+        }
+        if let Some(time) = self.tcp_keepalive {
+            builder = builder.with_tcp_keepalive(time);
+        }
+        builder
+    }
+
+    fn host_options(&self) -> UsbHostOptions {
+        let mut options = UsbHostOptions {
+            reset_on_detach: self.reset_on_detach,
+            ..Default::default()
+        };
+        if let Some(timeout) = self.transfer_timeout {
+            options.transfer_timeout = timeout;
+        }
+        options
+    }
+
+    fn exports_host(&self) -> bool {
+        self.host
+            || !self.devices.is_empty()
+            || !self.bus_ids.is_empty()
+            || !self.serials.is_empty()
+    }
+
+    /// Host devices matching any of the filters, or all of them without filters
+    fn host_filter(&self) -> impl FnMut(&Device<GlobalContext>) -> bool + Send + 'static {
+        let devices = self.devices.clone();
+        let bus_ids = self.bus_ids.clone();
+        let serials = self.serials.clone();
+        move |dev| {
+            if devices.is_empty() && bus_ids.is_empty() && serials.is_empty() {
+                return true;
+            }
+            if bus_ids.contains(&dev.bus_id()) {
+                return true;
+            }
+            let Ok(desc) = dev.device_descriptor() else {
+                return false;
+            };
+            if devices.contains(&(desc.vendor_id(), desc.product_id())) {
+                return true;
+            }
+            // reading the serial number needs to open the device
+            !serials.is_empty()
+                && dev
+                    .open()
+                    .and_then(|handle| handle.read_serial_number_string_ascii(&desc))
+                    .is_ok_and(|serial| serials.contains(&serial))
+        }
+    }
+}
+
+fn parse_vid_pid(s: &str) -> Result<(u16, u16), String> {
+    let (vid, pid) = s
+        .split_once(':')
+        .ok_or_else(|| format!("expected VID:PID, got {}", s))?;
+    let parse = |id: &str| u16::from_str_radix(id, 16).map_err(|err| format!("{}: {}", id, err));
+    Ok((parse(vid)?, parse(pid)?))
+}
+
+fn parse_seconds(s: &str) -> Result<Duration, String> {
+    s.parse::<f64>()
+        .map_err(|err| err.to_string())
+        .and_then(|secs| Duration::try_from_secs_f64(secs).map_err(|err| err.to_string()))
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let cli = Cli::parse();
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or(&cli.log_level))
+        .init();
+
+    let res = match cli.command {
+        Command::Serve(args) => serve(args).await,
+        Command::List => list(),
+        Command::ListRemote { addr } => list_remote(addr),
+    };
+    match res {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            error!("{}", err);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+async fn serve(args: ServeArgs) -> std::io::Result<()> {
+    let mut simulated = vec![];
+    let registry = Arc::new(HandlerRegistry::default());
+    for path in &args.configs {
+        simulated.extend(DevicesConfig::load(path)?.build(&registry)?);
+    }
+
+    // export host devices as they are plugged in, falling back to the ones present now
+    let mut server = Arc::new(args.server_builder().build()?);
+    let mut _hotplug = None;
+    if args.exports_host() {
+        match server.watch_host_devices(args.host_filter(), |_, _| true, args.host_options()) {
+            Ok(hotplug) => _hotplug = Some(hotplug),
+            Err(err) => {
+                warn!(
+                    "Hotplug unavailable ({}), exporting devices present now",
+                    err
+                );
+                server = Arc::new(
+                    args.server_builder()
+                        .with_host_devices(GlobalContext::default(), args.host_filter())
+                        .build()?,
+                );
+            }
+        }
+    }
+    for device in simulated {
+        let bus_id = server.add_device(device).await?;
+        info!("Exporting simulated device {}", bus_id);
+    }
+    for upstream in &args.upstreams {
+        let count = server
+            .add_upstream_devices(&UsbIpBackend::new(*upstream))
+            .await?;
+        info!("Re-exporting {} devices of {}", count, upstream);
+    }
+
+    #[cfg(unix)]
+    if let Some(path) = args.admin_socket {
+        info!("Admin endpoint on {}", path.display());
+        let server = server.clone();
+        tokio::spawn(async move {
+            if let Err(err) = usbip::serve_admin(&path, server, registry).await {
+                error!("Admin endpoint failed: {}", err);
+            }
+        });
+    }
+
+    info!("Listening on {}", args.listen);
+    usbip::server_with_shutdown(args.listen, server, shutdown_signal()).await?;
+    info!("Server stopped");
+    Ok(())
+}
+
+/// Completes on Ctrl-C, or SIGTERM on Unix
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => {}
+                    _ = terminate.recv() => {}
+                }
+                return;
+            }
+            Err(err) => warn!("Failed to listen for SIGTERM: {}", err),
+        }
+    }
+    if let Err(err) = tokio::signal::ctrl_c().await {
+        warn!("Failed to listen for Ctrl-C: {}", err);
+        std::future::pending::<()>().await;
+    }
+}
+
+fn list() -> std::io::Result<()> {
+    let devices = rusb::Context::new()
+        .and_then(|context| context.devices())
+        .map_err(|err| std::io::Error::other(format!("failed to list host devices: {}", err)))?;
+    for dev in devices.iter() {
+        let Ok(desc) = dev.device_descriptor() else {
+            continue;
+        };
+        let product = dev
+            .open()
+            .and_then(|handle| handle.read_product_string_ascii(&desc))
+            .unwrap_or_default();
+        println!(
+            "{:<12} {:04x}:{:04x} {}",
+            dev.bus_id(),
+            desc.vendor_id(),
+            desc.product_id(),
+            product
+        );
+    }
+    Ok(())
+}
+
+fn list_remote(addr: SocketAddr) -> std::io::Result<()> {
+    for dev in UsbIpBackend::new(addr).list()? {
+        println!(
+            "{:<12} {:04x}:{:04x} {}",
+            dev.bus_id,
+            dev.vendor_id,
+            dev.product_id,
+            dev.interfaces
+                .iter()
+                .map(|intf| format!(
+                    "{:02x}/{:02x}/{:02x}",
+                    intf.interface_class, intf.interface_subclass, intf.interface_protocol
+                ))
+                .collect::<Vec<_>>()
+                .join(" ")
+        );
+    }
+    Ok(())
+}
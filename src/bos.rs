@@ -0,0 +1,331 @@
+//! Binary device Object Store (BOS) and device capabilities
+use super::*;
+
+// reference:
+// USB 3.2: Section 9.6.2 Binary Device Object Store (BOS)
+// USB 2.0 LPM ECN: Table 9-12. USB 2.0 Extension Descriptor
+
+/// A list of defined device capability types
+/// from USB 3.2 standard Table 9-14. Device Capability Type Codes
+#[derive(Copy, Clone, Debug, FromPrimitive)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum DeviceCapabilityType {
+    WirelessUsb = 0x01,
+    Usb20Extension = 0x02,
+    SuperSpeed = 0x03,
+    ContainerId = 0x04,
+    Platform = 0x05,
+    SuperSpeedPlus = 0x0A,
+}
+
+/// A sublink speed attribute of a [BosCapability::SuperSpeedPlus] capability
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct SublinkSpeedAttribute {
+    /// Sublink Speed Attribute ID (SSID)
+    pub id: u8,
+    /// Lane Speed Exponent (LSE): 0 = b/s, 1 = Kb/s, 2 = Mb/s, 3 = Gb/s
+    pub exponent: u8,
+    /// Sublink Type (ST): bit 0 set for asymmetric, bit 1 set for transmit
+    pub sublink_type: u8,
+    /// Link Protocol (LP): 0 = SuperSpeed, 1 = SuperSpeedPlus
+    pub protocol: u8,
+    /// Lane Speed Mantissa (LSM)
+    pub mantissa: u16,
+}
+
+impl SublinkSpeedAttribute {
+    fn to_u32(self) -> u32 {
+        (self.id as u32 & 0xF)
+            | (self.exponent as u32 & 0x3) << 4
+            | (self.sublink_type as u32 & 0x3) << 6
+            | (self.protocol as u32 & 0x3) << 14
+            | (self.mantissa as u32) << 16
+    }
+}
+
+/// A device capability descriptor reported in the BOS descriptor
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum BosCapability {
+    /// USB 2.0 Extension, advertising Link Power Management
+    Usb20Extension {
+        /// Link Power Management supported
+        lpm: bool,
+        /// Recommended Baseline BESL value, if any
+        baseline_besl: Option<u8>,
+        /// Recommended Deep BESL value, if any
+        deep_besl: Option<u8>,
+    },
+    /// SuperSpeed USB device capability
+    SuperSpeed {
+        /// Latency Tolerance Messages supported
+        ltm: bool,
+        /// wSpeedsSupported: bit 0 low, bit 1 full, bit 2 high, bit 3 5 Gbps
+        speeds_supported: u16,
+        /// bFunctionalitySupport: lowest speed at which all functionality is available
+        functionality_support: u8,
+        /// bU1DevExitLat in microseconds
+        u1_exit_latency: u8,
+        /// wU2DevExitLat in microseconds
+        u2_exit_latency: u16,
+    },
+    /// SuperSpeedPlus USB device capability
+    SuperSpeedPlus {
+        /// Sublink speed ID of the minimum functional speed
+        min_functional_speed_id: u8,
+        /// Minimum receive lane count
+        min_rx_lanes: u8,
+        /// Minimum transmit lane count
+        min_tx_lanes: u8,
+        /// Supported sublink speeds
+        sublink_speeds: Vec<SublinkSpeedAttribute>,
+    },
+    /// Container ID, a UUID unique to this device instance
+    ContainerId {
+        /// UUID in the byte order it appears in the descriptor
+        uuid: [u8; 16],
+    },
+    /// Platform specific capability, e.g. WebUSB or Microsoft OS 2.0
+    Platform {
+        /// PlatformCapabilityUUID in the byte order it appears in the descriptor
+        uuid: [u8; 16],
+        /// CapabilityData, truncated to [MAX_PLATFORM_DATA_LEN] bytes in the descriptor
+        data: Vec<u8>,
+    },
+}
+
+/// Longest CapabilityData of a [BosCapability::Platform] fitting in its descriptor, whose bLength is a byte
+pub const MAX_PLATFORM_DATA_LEN: usize = 0xFF - 20;
+
+impl BosCapability {
+    /// Platform capability with `data`, `None` if it does not fit in the descriptor
+    pub fn platform(uuid: [u8; 16], data: Vec<u8>) -> Option<Self> {
+        (data.len() <= MAX_PLATFORM_DATA_LEN).then_some(Self::Platform { uuid, data })
+    }
+
+    /// USB 2.0 Extension with LPM support
+    pub fn usb20_lpm() -> Self {
+        Self::Usb20Extension {
+            lpm: true,
+            baseline_besl: None,
+            deep_besl: None,
+        }
+    }
+
+    /// SuperSpeed capability of a device supporting full, high and SuperSpeed
+    pub fn superspeed() -> Self {
+        Self::SuperSpeed {
+            ltm: false,
+            speeds_supported: 0b1110,
+            functionality_support: 1, // full speed
+            u1_exit_latency: 0x0A,
+            u2_exit_latency: 0x07FF,
+        }
+    }
+
+    /// SuperSpeedPlus capability of a Gen 2x1 (10 Gbps) device
+    pub fn superspeed_plus_gen2() -> Self {
+        let attribute = |sublink_type| SublinkSpeedAttribute {
+            id: 1,
+            exponent: 3, // Gb/s
+            sublink_type,
+            protocol: 1, // SuperSpeedPlus
+            mantissa: 10,
+        };
+        Self::SuperSpeedPlus {
+            min_functional_speed_id: 1,
+            min_rx_lanes: 1,
+            min_tx_lanes: 1,
+            sublink_speeds: vec![attribute(0b00), attribute(0b10)],
+        }
+    }
+
+    /// Get the bDevCapabilityType of this capability
+    pub fn capability_type(&self) -> DeviceCapabilityType {
+        match self {
+            Self::Usb20Extension { .. } => DeviceCapabilityType::Usb20Extension,
+            Self::SuperSpeed { .. } => DeviceCapabilityType::SuperSpeed,
+            Self::SuperSpeedPlus { .. } => DeviceCapabilityType::SuperSpeedPlus,
+            Self::ContainerId { .. } => DeviceCapabilityType::ContainerId,
+            Self::Platform { .. } => DeviceCapabilityType::Platform,
+        }
+    }
+
+    /// Converts the capability into a device capability descriptor
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut desc = vec![
+            0x00,                                   // bLength: to be filled below
+            DescriptorType::DeviceCapability as u8, // bDescriptorType: Device Capability
+            self.capability_type() as u8,           // bDevCapabilityType
+        ];
+        match self {
+            Self::Usb20Extension {
+                lpm,
+                baseline_besl,
+                deep_besl,
+            } => {
+                let mut attributes = 0u32;
+                if *lpm {
+                    // LPM and BESL & Alternate HIRD definitions supported
+                    attributes |= 0b110;
+                }
+                if let Some(besl) = baseline_besl {
+                    attributes |= 1 << 3 | (*besl as u32 & 0xF) << 8;
+                }
+                if let Some(besl) = deep_besl {
+                    attributes |= 1 << 4 | (*besl as u32 & 0xF) << 12;
+                }
+                desc.extend_from_slice(&attributes.to_le_bytes()); // bmAttributes
+            }
+            Self::SuperSpeed {
+                ltm,
+                speeds_supported,
+                functionality_support,
+                u1_exit_latency,
+                u2_exit_latency,
+            } => {
+                desc.push(if *ltm { 0x02 } else { 0x00 }); // bmAttributes
+                desc.extend_from_slice(&speeds_supported.to_le_bytes()); // wSpeedsSupported
+                desc.push(*functionality_support); // bFunctionalitySupport
+                desc.push(*u1_exit_latency); // bU1DevExitLat
+                desc.extend_from_slice(&u2_exit_latency.to_le_bytes()); // wU2DevExitLat
+            }
+            Self::SuperSpeedPlus {
+                min_functional_speed_id,
+                min_rx_lanes,
+                min_tx_lanes,
+                sublink_speeds,
+            } => {
+                debug_assert!(!sublink_speeds.is_empty() && sublink_speeds.len() <= 32);
+                let mut ids: Vec<u8> = sublink_speeds.iter().map(|s| s.id).collect();
+                ids.sort_unstable();
+                ids.dedup();
+                // SSAC and SSIC are zero based
+                let attributes =
+                    (sublink_speeds.len() as u32 - 1) & 0x1F | ((ids.len() as u32 - 1) & 0xF) << 5;
+                let functionality = (*min_functional_speed_id as u16 & 0xF)
+                    | (*min_rx_lanes as u16 & 0xF) << 8
+                    | (*min_tx_lanes as u16 & 0xF) << 12;
+
+                desc.push(0x00); // bReserved
+                desc.extend_from_slice(&attributes.to_le_bytes()); // bmAttributes
+                desc.extend_from_slice(&functionality.to_le_bytes()); // wFunctionalitySupport
+                desc.extend_from_slice(&[0x00, 0x00]); // wReserved
+                for speed in sublink_speeds {
+                    desc.extend_from_slice(&speed.to_u32().to_le_bytes()); // bmSublinkSpeedAttr
+                }
+            }
+            Self::ContainerId { uuid } => {
+                desc.push(0x00); // bReserved
+                desc.extend_from_slice(uuid); // ContainerID
+            }
+            Self::Platform { uuid, data } => {
+                desc.push(0x00); // bReserved
+                desc.extend_from_slice(uuid); // PlatformCapabilityUUID
+                                              // CapabilityData, capped rather than wrapping bLength
+                desc.extend_from_slice(&data[..data.len().min(MAX_PLATFORM_DATA_LEN)]);
+            }
+        }
+        debug_assert!(desc.len() <= 0xFF);
+        desc[0] = desc.len() as u8;
+        desc
+    }
+}
+
+/// Build a BOS descriptor containing `capabilities`
+pub fn bos_descriptor(capabilities: &[BosCapability]) -> Vec<u8> {
+    let mut desc = vec![
+        0x05,                      // bLength
+        DescriptorType::BOS as u8, // bDescriptorType: BOS
+        0x00,
+        0x00,                     // wTotalLength: to be filled below
+        capabilities.len() as u8, // bNumDeviceCaps
+    ];
+    for capability in capabilities {
+        desc.extend(capability.to_bytes());
+    }
+    let len = desc.len() as u16;
+    desc[2] = len as u8;
+    desc[3] = (len >> 8) as u8;
+    desc
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::util::tests::*;
+
+    use super::*;
+
+    #[test]
+    fn capability_lengths() {
+        setup_test_logger();
+        assert_eq!(BosCapability::usb20_lpm().to_bytes().len(), 7);
+        assert_eq!(BosCapability::superspeed().to_bytes().len(), 10);
+        assert_eq!(BosCapability::superspeed_plus_gen2().to_bytes().len(), 20);
+        assert_eq!(
+            BosCapability::ContainerId { uuid: [0; 16] }
+                .to_bytes()
+                .len(),
+            20
+        );
+        assert_eq!(
+            BosCapability::Platform {
+                uuid: [0; 16],
+                data: vec![1, 2, 3, 4]
+            }
+            .to_bytes()
+            .len(),
+            24
+        );
+    }
+
+    #[test]
+    fn oversized_platform_data() {
+        setup_test_logger();
+        let data = vec![0; MAX_PLATFORM_DATA_LEN + 1];
+        assert!(BosCapability::platform([0; 16], data.clone()).is_none());
+        let desc = BosCapability::Platform {
+            uuid: [0; 16],
+            data,
+        }
+        .to_bytes();
+        assert_eq!(desc.len(), 0xFF);
+        assert_eq!(desc[0], 0xFF);
+    }
+
+    #[test]
+    fn usb20_extension() {
+        setup_test_logger();
+        let cap = BosCapability::Usb20Extension {
+            lpm: true,
+            baseline_besl: Some(4),
+            deep_besl: None,
+        };
+        assert_eq!(cap.to_bytes(), [0x07, 0x10, 0x02, 0x0E, 0x04, 0x00, 0x00]);
+    }
+
+    #[test]
+    fn superspeed_plus() {
+        setup_test_logger();
+        let desc = BosCapability::superspeed_plus_gen2().to_bytes();
+        // one SSID, two sublink speed attributes
+        assert_eq!(&desc[4..8], &[0x01, 0x00, 0x00, 0x00]);
+        assert_eq!(&desc[8..10], &[0x01, 0x11]);
+        assert_eq!(&desc[12..16], &[0x31, 0x40, 0x0A, 0x00]);
+        assert_eq!(&desc[16..20], &[0xB1, 0x40, 0x0A, 0x00]);
+    }
+
+    #[test]
+    fn desc_verify() {
+        setup_test_logger();
+        let desc = bos_descriptor(&[
+            BosCapability::usb20_lpm(),
+            BosCapability::superspeed(),
+            BosCapability::ContainerId { uuid: [0xAA; 16] },
+        ]);
+        verify_descriptor(&desc);
+        assert_eq!(desc[2] as usize | (desc[3] as usize) << 8, desc.len());
+        assert_eq!(desc[4], 3);
+    }
+}
@@ -0,0 +1,176 @@
+//! Configure the limits and timeouts of a server
+use super::*;
+use std::time::Duration;
+
+/// Limits and timeouts enforced by [handler], see [UsbIpServerBuilder]
+#[derive(Clone, Debug)]
+pub(crate) struct ServerSettings {
+    pub(crate) max_connections: Option<usize>,
+    pub(crate) max_in_flight_urbs: Option<usize>,
+    pub(crate) max_transfer_size: u32,
+    pub(crate) handshake_timeout: Option<Duration>,
+    pub(crate) idle_timeout: Option<Duration>,
+    pub(crate) tcp_nodelay: bool,
+    pub(crate) tcp_keepalive: Option<Duration>,
+}
+
+impl Default for ServerSettings {
+    fn default() -> Self {
+        Self {
+            max_connections: None,
+            max_in_flight_urbs: None,
+            max_transfer_size: usbip_protocol::USBIP_MAX_TRANSFER_BUFFER_LENGTH,
+            handshake_timeout: None,
+            idle_timeout: None,
+            tcp_nodelay: false,
+            tcp_keepalive: None,
+        }
+    }
+}
+
+impl ServerSettings {
+    /// Apply the socket options to an accepted connection
+    pub(crate) fn configure_socket(&self, socket: &tokio::net::TcpStream) -> Result<()> {
+        socket.set_nodelay(self.tcp_nodelay)?;
+        if let Some(time) = self.tcp_keepalive {
+            let keepalive = socket2::TcpKeepalive::new().with_time(time);
+            socket2::SockRef::from(socket).set_tcp_keepalive(&keepalive)?;
+        }
+        Ok(())
+    }
+}
+
+/// Opens the devices of a backend selected by the builder, shared according to the options
+type HostDevices = dyn FnOnce(&UsbHostOptions) -> Vec<UsbDevice>;
+
+/// Build a [UsbIpServer] with limits and timeouts, see [UsbIpServer::builder]
+///
+/// Servers created otherwise have no limits besides the largest transfer accepted,
+/// [usbip_protocol::USBIP_MAX_TRANSFER_BUFFER_LENGTH].
+#[derive(Default)]
+pub struct UsbIpServerBuilder {
+    devices: Vec<UsbDevice>,
+    host_devices: Vec<Box<HostDevices>>,
+    host_options: UsbHostOptions,
+    settings: ServerSettings,
+}
+
+impl UsbIpServer {
+    /// Configure a [UsbIpServer] step by step
+    pub fn builder() -> UsbIpServerBuilder {
+        UsbIpServerBuilder::default()
+    }
+}
+
+impl UsbIpServerBuilder {
+    /// Export a simulated device, see [UsbIpServer::new_simulated]
+    pub fn with_device(mut self, device: UsbDevice) -> Self {
+        self.devices.push(device);
+        self
+    }
+
+    /// Export simulated devices, see [UsbIpServer::new_simulated]
+    pub fn with_devices(mut self, devices: Vec<UsbDevice>) -> Self {
+        self.devices.extend(devices);
+        self
+    }
+
+    /// Export the devices of `backend` selected by `filter`, see [UsbIpServer::new_from_backend]
+    ///
+    /// Pass [GlobalContext] as backend to export the devices of the host.
+    pub fn with_host_devices<B, F>(self, backend: B, filter: F) -> Self
+    where
+        B: UsbHostBackend + 'static,
+        F: FnMut(&B::Device) -> bool + 'static,
+    {
+        self.with_host_interfaces(backend, filter, |_, _| true)
+    }
+
+    /// Export the devices of `backend` selected by `filter`, and only their interfaces chosen by `select`,
+    /// see [UsbIpServer::new_from_backend_with_interfaces]
+    pub fn with_host_interfaces<B, F, G>(mut self, backend: B, filter: F, select: G) -> Self
+    where
+        B: UsbHostBackend + 'static,
+        F: FnMut(&B::Device) -> bool + 'static,
+        G: FnMut(&B::Device, &UsbHostInterfaceInfo) -> bool + 'static,
+    {
+        self.host_devices.push(Box::new(move |options| {
+            UsbIpServer::new_from_backend_with_interfaces(&backend, filter, select, options.clone())
+                .available_devices
+                .into_inner()
+        }));
+        self
+    }
+
+    /// Share devices of the host according to `options`
+    pub fn with_host_options(mut self, options: UsbHostOptions) -> Self {
+        self.host_options = options;
+        self
+    }
+
+    /// Set the timeout of transfers passed to devices of the host, see [UsbHostOptions::transfer_timeout]
+    pub fn with_transfer_timeout(mut self, transfer_timeout: Duration) -> Self {
+        self.host_options.transfer_timeout = transfer_timeout;
+        self
+    }
+
+    /// Refuse connections beyond this many at once
+    pub fn with_max_connections(mut self, max_connections: usize) -> Self {
+        self.settings.max_connections = Some(max_connections);
+        self
+    }
+
+    /// Fail URBs submitted while this many are pending in the session, with `-EBUSY`
+    pub fn with_max_in_flight_urbs(mut self, max_in_flight_urbs: usize) -> Self {
+        self.settings.max_in_flight_urbs = Some(max_in_flight_urbs);
+        self
+    }
+
+    /// Close connections submitting a URB with a larger transfer buffer
+    pub fn with_max_transfer_size(mut self, max_transfer_size: u32) -> Self {
+        self.settings.max_transfer_size = max_transfer_size;
+        self
+    }
+
+    /// Close connections which have not imported a device after this long
+    ///
+    /// Clients listing the devices close their connection right away.
+    pub fn with_handshake_timeout(mut self, handshake_timeout: Duration) -> Self {
+        self.settings.handshake_timeout = Some(handshake_timeout);
+        self
+    }
+
+    /// Close connections sending no command for this long, while none of their URBs is pending
+    pub fn with_idle_timeout(mut self, idle_timeout: Duration) -> Self {
+        self.settings.idle_timeout = Some(idle_timeout);
+        self
+    }
+
+    /// Disable Nagle's algorithm on connections accepted by [server], sending small responses right away
+    pub fn with_tcp_nodelay(mut self, tcp_nodelay: bool) -> Self {
+        self.settings.tcp_nodelay = tcp_nodelay;
+        self
+    }
+
+    /// Probe connections accepted by [server] after they are silent for this long, closing them if the client
+    /// is gone
+    pub fn with_tcp_keepalive(mut self, time: Duration) -> Self {
+        self.settings.tcp_keepalive = Some(time);
+        self
+    }
+
+    /// Create the server, opening the devices of the host if selected
+    ///
+    /// Fails with [Error::DuplicateBusId] if devices are exported under the same bus id.
+    pub fn build(self) -> std::result::Result<UsbIpServer, Error> {
+        let mut devices = vec![];
+        for host_devices in self.host_devices {
+            devices.extend(host_devices(&self.host_options));
+        }
+        devices.extend(self.devices);
+        Ok(UsbIpServer {
+            settings: self.settings,
+            ..UsbIpServer::try_new_simulated(devices)?
+        })
+    }
+}
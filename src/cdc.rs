@@ -29,6 +29,7 @@ impl UsbCdcAcmHandler {
                 attributes: EndpointAttributes::Interrupt as u8, // Interrupt
                 max_packet_size: 0x08,                           // 8 bytes
                 interval: 10,
+                ..Default::default()
             },
             // bulk in
             UsbEndpoint {
@@ -36,6 +37,7 @@ impl UsbCdcAcmHandler {
                 attributes: EndpointAttributes::Bulk as u8, // Bulk
                 max_packet_size: 512,                       // 512 bytes
                 interval: 0,
+                ..Default::default()
             },
             // bulk out
             UsbEndpoint {
@@ -43,6 +45,7 @@ impl UsbCdcAcmHandler {
                 attributes: EndpointAttributes::Bulk as u8, // Bulk
                 max_packet_size: 512,                       // 512 bytes
                 interval: 0,
+                ..Default::default()
             },
         ]
     }
@@ -0,0 +1,843 @@
+//! Re-export devices of another USB/IP server, as a client of it
+use super::*;
+use crate::usbip_protocol::*;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::mpsc::RecvTimeoutError;
+use std::time::Duration;
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::TcpStream;
+
+/// transfer_flags of URBs reading from the device, as set by the Linux kernel
+const URB_DIR_IN: u32 = 0x0200;
+
+/// Devices exported by another USB/IP server, to re-export them with [UsbIpServer::new_from_backend]
+///
+/// This turns the server into a gateway to the upstream server, or aggregates several of them.
+/// Listing the devices only queries the device list of the upstream server, opening one imports it from there,
+/// then its URBs are forwarded over that connection until the client detaches it.
+#[derive(Clone, Debug)]
+pub struct UsbIpBackend {
+    addr: SocketAddr,
+    timeout: Duration,
+}
+
+impl UsbIpBackend {
+    pub fn new(addr: SocketAddr) -> Self {
+        Self {
+            addr,
+            timeout: Duration::from_secs(5),
+        }
+    }
+
+    /// Give up connecting to, listing or importing from the upstream server after `timeout`, 5 seconds by default
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// List the devices exported by the upstream server, as described by its OP_REP_DEVLIST
+    ///
+    /// Their handlers are placeholders, unlike the devices of [UsbHostBackend::devices] which can be opened.
+    pub fn list(&self) -> Result<Vec<UsbDevice>> {
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let addr = self.addr;
+        spawn_client(async move {
+            let devices = match request(addr, UsbIpCommand::OpReqDevlist { status: 0 }).await {
+                Ok((
+                    _,
+                    UsbIpResponse::OpRepDevlist {
+                        status: ST_OK,
+                        devices,
+                        ..
+                    },
+                )) => Ok(devices),
+                Ok((_, UsbIpResponse::OpRepDevlist { status, .. })) => Err(op_status_error(status)),
+                Ok(_) => Err(std::io::Error::new(
+                    ErrorKind::InvalidData,
+                    "unexpected reply to OP_REQ_DEVLIST",
+                )),
+                Err(err) => Err(err),
+            };
+            sender.send(devices).ok();
+        });
+        match receiver.recv_timeout(self.timeout) {
+            Ok(res) => res,
+            Err(_) => Err(std::io::Error::new(
+                ErrorKind::TimedOut,
+                format!("timed out listing devices of {}", self.addr),
+            )),
+        }
+    }
+}
+
+impl UsbHostBackend for UsbIpBackend {
+    type Device = UsbIpRemoteDevice;
+
+    fn devices(&self) -> rusb::Result<Vec<Self::Device>> {
+        let listed = match self.list() {
+            Ok(devices) => devices,
+            Err(err) if err.kind() == ErrorKind::TimedOut => {
+                warn!("Timed out listing devices of {}", self.addr);
+                return Err(rusb::Error::Timeout);
+            }
+            Err(err) => {
+                warn!("Failed to list devices of {}: {}", self.addr, err);
+                return Err(rusb::Error::Io);
+            }
+        };
+
+        Ok(listed
+            .iter()
+            .map(|listed| UsbIpRemoteDevice::new(self, listed))
+            .collect())
+    }
+}
+
+/// A device exported by the upstream server of a [UsbIpBackend]
+///
+/// Described by the device list until it is opened, which imports it and reads its device descriptor.
+#[derive(Clone, Debug)]
+pub struct UsbIpRemoteDevice {
+    backend: UsbIpBackend,
+    bus_id: String,
+    bus_number: u8,
+    address: u8,
+    speed: UsbSpeed,
+    /// Built from the device list, replaced by the one read from the device once opened
+    device_descriptor: Arc<Mutex<Vec<u8>>>,
+}
+
+impl UsbIpRemoteDevice {
+    fn new(backend: &UsbIpBackend, listed: &UsbDevice) -> Self {
+        let speed = FromPrimitive::from_u32(listed.speed).unwrap_or(UsbSpeed::Unknown);
+        // the device list has no bcdUSB, bMaxPacketSize0 or string indices
+        let (usb_version, ep0_max_packet_size) = match speed {
+            UsbSpeed::Super => (0x0300u16, 9),
+            UsbSpeed::SuperPlus => (0x0310, 9),
+            UsbSpeed::Low => (0x0110, 8),
+            UsbSpeed::Full => (0x0110, 64),
+            _ => (0x0200, 64),
+        };
+        let device_bcd = listed.device_bcd.to_bcd();
+        let device_descriptor = vec![
+            0x12,                         // bLength
+            DescriptorType::Device as u8, // bDescriptorType
+            usb_version as u8,            // bcdUSB
+            (usb_version >> 8) as u8,
+            listed.device_class,    // bDeviceClass
+            listed.device_subclass, // bDeviceSubClass
+            listed.device_protocol, // bDeviceProtocol
+            ep0_max_packet_size,    // bMaxPacketSize0
+            listed.vendor_id as u8, // idVendor
+            (listed.vendor_id >> 8) as u8,
+            listed.product_id as u8, // idProduct
+            (listed.product_id >> 8) as u8,
+            device_bcd as u8, // bcdDevice
+            (device_bcd >> 8) as u8,
+            0, // iManufacturer
+            0, // iProduct
+            0, // iSerial
+            listed.num_configurations,
+        ];
+        Self {
+            backend: backend.clone(),
+            bus_id: listed.bus_id.clone(),
+            bus_number: listed.bus_num as u8,
+            address: listed.dev_num as u8,
+            speed,
+            device_descriptor: Arc::new(Mutex::new(device_descriptor)),
+        }
+    }
+
+    /// Bus id of the device on the upstream server
+    pub fn upstream_bus_id(&self) -> &str {
+        &self.bus_id
+    }
+}
+
+impl UsbHostDevice for UsbIpRemoteDevice {
+    type Handle = UsbIpRemoteHandle;
+
+    fn bus_number(&self) -> u8 {
+        self.bus_number
+    }
+
+    fn address(&self) -> u8 {
+        self.address
+    }
+
+    /// Exported under the bus id of the upstream server
+    fn bus_id(&self) -> String {
+        self.bus_id.clone()
+    }
+
+    fn port_numbers(&self) -> rusb::Result<Vec<u8>> {
+        // e.g. 1-1.4, named after the port chain by Linux servers
+        let (_, ports) = self.bus_id.split_once('-').ok_or(rusb::Error::NotFound)?;
+        ports
+            .split('.')
+            .map(|port| port.parse().map_err(|_| rusb::Error::NotFound))
+            .collect()
+    }
+
+    fn speed(&self) -> UsbSpeed {
+        self.speed
+    }
+
+    /// As listed until the device has been opened
+    fn device_descriptor(&self) -> rusb::Result<Vec<u8>> {
+        Ok(self.device_descriptor.lock().unwrap().clone())
+    }
+
+    /// Imports the device for as long as it is read
+    fn active_config_descriptor(&self, timeout: Duration) -> rusb::Result<Vec<u8>> {
+        UsbHostHandle::active_config_descriptor(&self.open()?, timeout)
+    }
+
+    fn open(&self) -> rusb::Result<Self::Handle> {
+        let handle = UsbIpRemoteHandle::import(&self.backend, &self.bus_id)?;
+        let desc = read_descriptor(
+            &handle,
+            DescriptorType::Device,
+            0,
+            0,
+            18,
+            self.backend.timeout,
+        )?;
+        *self.device_descriptor.lock().unwrap() = desc;
+        Ok(handle)
+    }
+}
+
+/// A device imported from the upstream server of a [UsbIpBackend]
+///
+/// Requests handled by the host for local devices, e.g. SET_CONFIGURATION or a port reset, are sent upstream as
+/// the Linux kernel client does, so that the upstream server performs them.
+pub struct UsbIpRemoteHandle {
+    backend: UsbIpBackend,
+    bus_id: String,
+    connection: Option<Arc<UsbIpConnection>>,
+}
+
+impl std::fmt::Debug for UsbIpRemoteHandle {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("UsbIpRemoteHandle")
+            .field("backend", &self.backend)
+            .field("bus_id", &self.bus_id)
+            .finish_non_exhaustive()
+    }
+}
+
+impl UsbIpRemoteHandle {
+    fn import(backend: &UsbIpBackend, bus_id: &str) -> rusb::Result<Self> {
+        let mut handle = Self {
+            backend: backend.clone(),
+            bus_id: bus_id.to_string(),
+            connection: None,
+        };
+        handle.open_session()?;
+        Ok(handle)
+    }
+
+    fn connection(&self) -> rusb::Result<&Arc<UsbIpConnection>> {
+        self.connection.as_ref().ok_or(rusb::Error::NoDevice)
+    }
+
+    /// Forward a URB and wait for its completion, unlinking it after `timeout` unless it is zero
+    fn transfer(
+        &self,
+        endpoint: u8,
+        interval: u8,
+        setup: [u8; 8],
+        transfer_buffer_length: u32,
+        data: &[u8],
+        timeout: Duration,
+    ) -> rusb::Result<Vec<u8>> {
+        let connection = self.connection()?;
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let seqnum = connection.submit(
+            endpoint,
+            interval,
+            setup,
+            transfer_buffer_length,
+            data,
+            Box::new(move |res| {
+                sender.send(res).ok();
+            }),
+        )?;
+        let res = if timeout.is_zero() {
+            receiver.recv().map_err(|_| RecvTimeoutError::Disconnected)
+        } else {
+            receiver.recv_timeout(timeout)
+        };
+        match res {
+            Ok(res) => res,
+            Err(RecvTimeoutError::Timeout) => {
+                connection.unlink(seqnum);
+                Err(rusb::Error::Timeout)
+            }
+            Err(RecvTimeoutError::Disconnected) => Err(rusb::Error::NoDevice),
+        }
+    }
+
+    /// Send a request without data to the default control pipe, waiting at most the timeout of the backend
+    fn request(&self, request_type: u8, request: u8, value: u16, index: u16) -> rusb::Result<()> {
+        let timeout = self.backend.timeout;
+        self.write_control(request_type, request, value, index, &[], timeout)
+            .map(|_| ())
+    }
+}
+
+/// Copy the data read by a transfer into the buffer of the caller
+fn copy_into(data: Vec<u8>, buf: &mut [u8]) -> rusb::Result<usize> {
+    if data.len() > buf.len() {
+        return Err(rusb::Error::Overflow);
+    }
+    buf[..data.len()].copy_from_slice(&data);
+    Ok(data.len())
+}
+
+impl UsbHostHandle for UsbIpRemoteHandle {
+    fn active_config_descriptor(&self, timeout: Duration) -> rusb::Result<Vec<u8>> {
+        let device = read_descriptor(self, DescriptorType::Device, 0, 0, 18, timeout)?;
+        let num_configurations = device.get(17).copied().unwrap_or(1).max(1);
+        let configuration = self.connection()?.configuration.load(Ordering::SeqCst);
+
+        // the active configuration is known by its value, fall back to the first one when unconfigured
+        let mut index = 0;
+        for i in 0..num_configurations {
+            let header = read_descriptor(self, DescriptorType::Configuration, i, 0, 9, timeout)?;
+            if header.len() >= 9 && header[5] == configuration {
+                index = i;
+                break;
+            }
+        }
+        let header = read_descriptor(self, DescriptorType::Configuration, index, 0, 9, timeout)?;
+        if header.len() < 9 {
+            return Err(rusb::Error::BadDescriptor);
+        }
+        let total_length = u16::from_le_bytes([header[2], header[3]]);
+        read_descriptor(
+            self,
+            DescriptorType::Configuration,
+            index,
+            0,
+            total_length,
+            timeout,
+        )
+    }
+
+    fn read_bos_descriptor(&self, timeout: Duration) -> rusb::Result<Vec<u8>> {
+        request_bos_descriptor(self, timeout)
+    }
+
+    fn read_languages(&self, timeout: Duration) -> rusb::Result<Vec<u16>> {
+        request_languages(self, timeout)
+    }
+
+    fn read_string_descriptor(
+        &self,
+        language: u16,
+        index: u8,
+        timeout: Duration,
+    ) -> rusb::Result<String> {
+        request_string_descriptor(self, language, index, timeout)
+    }
+
+    fn read_control(
+        &self,
+        request_type: u8,
+        request: u8,
+        value: u16,
+        index: u16,
+        buf: &mut [u8],
+        timeout: Duration,
+    ) -> rusb::Result<usize> {
+        let setup = SetupPacket {
+            request_type: request_type | 0x80,
+            request,
+            value,
+            index,
+            length: buf.len() as u16,
+        };
+        let data = self.transfer(0, 0, setup.to_bytes(), buf.len() as u32, &[], timeout)?;
+        copy_into(data, buf)
+    }
+
+    fn write_control(
+        &self,
+        request_type: u8,
+        request: u8,
+        value: u16,
+        index: u16,
+        buf: &[u8],
+        timeout: Duration,
+    ) -> rusb::Result<usize> {
+        let setup = SetupPacket {
+            request_type: request_type & !0x80,
+            request,
+            value,
+            index,
+            length: buf.len() as u16,
+        };
+        self.transfer(0, 0, setup.to_bytes(), buf.len() as u32, buf, timeout)?;
+        Ok(buf.len())
+    }
+
+    fn read_bulk(&self, endpoint: u8, buf: &mut [u8], timeout: Duration) -> rusb::Result<usize> {
+        let data = self.transfer(endpoint, 0, [0; 8], buf.len() as u32, &[], timeout)?;
+        copy_into(data, buf)
+    }
+
+    fn write_bulk(&self, endpoint: u8, buf: &[u8], timeout: Duration) -> rusb::Result<usize> {
+        self.transfer(endpoint, 0, [0; 8], buf.len() as u32, buf, timeout)?;
+        Ok(buf.len())
+    }
+
+    fn read_interrupt(
+        &self,
+        endpoint: u8,
+        buf: &mut [u8],
+        timeout: Duration,
+    ) -> rusb::Result<usize> {
+        let data = self.transfer(endpoint, 1, [0; 8], buf.len() as u32, &[], timeout)?;
+        copy_into(data, buf)
+    }
+
+    fn write_interrupt(&self, endpoint: u8, buf: &[u8], timeout: Duration) -> rusb::Result<usize> {
+        self.transfer(endpoint, 1, [0; 8], buf.len() as u32, buf, timeout)?;
+        Ok(buf.len())
+    }
+
+    // drivers and interfaces are managed by the upstream server
+    fn kernel_driver_active(&self, _interface: u8) -> rusb::Result<bool> {
+        Ok(false)
+    }
+
+    fn detach_kernel_driver(&self, _interface: u8) -> rusb::Result<()> {
+        Ok(())
+    }
+
+    fn attach_kernel_driver(&self, _interface: u8) -> rusb::Result<()> {
+        Ok(())
+    }
+
+    fn claim_interface(&self, _interface: u8) -> rusb::Result<()> {
+        Ok(())
+    }
+
+    fn release_interface(&self, _interface: u8) -> rusb::Result<()> {
+        Ok(())
+    }
+
+    fn set_alternate_setting(&self, interface: u8, alternate_setting: u8) -> rusb::Result<()> {
+        self.request(
+            0x01,
+            StandardRequest::SetInterface as u8,
+            alternate_setting as u16,
+            interface as u16,
+        )
+    }
+
+    fn set_active_configuration(&self, configuration: u8) -> rusb::Result<()> {
+        self.request(
+            0x00,
+            StandardRequest::SetConfiguration as u8,
+            configuration as u16,
+            0,
+        )?;
+        self.connection()?
+            .configuration
+            .store(configuration, Ordering::SeqCst);
+        Ok(())
+    }
+
+    fn unconfigure(&self) -> rusb::Result<()> {
+        self.set_active_configuration(0)
+    }
+
+    fn clear_halt(&self, endpoint: u8) -> rusb::Result<()> {
+        // ENDPOINT_HALT
+        self.request(
+            0x02,
+            StandardRequest::ClearFeature as u8,
+            0,
+            endpoint as u16,
+        )
+    }
+
+    fn reset(&self) -> rusb::Result<()> {
+        // SET_FEATURE(PORT_RESET) to the port of the device, recognized by Linux servers
+        self.request(0x23, StandardRequest::SetFeature as u8, 4, 0)
+    }
+
+    fn open_session(&mut self) -> rusb::Result<()> {
+        if self.connection.is_none() {
+            let connection = UsbIpConnection::import(&self.backend, &self.bus_id)?;
+            self.connection = Some(Arc::new(connection));
+        }
+        Ok(())
+    }
+
+    fn close_session(&mut self) {
+        // the upstream server releases the device when the connection is closed
+        self.connection = None;
+    }
+
+    fn submit_transfer(
+        handle: &Arc<Mutex<Self>>,
+        ep: UsbEndpoint,
+        transfer_buffer_length: u32,
+        setup: SetupPacket,
+        req: &[u8],
+        timeout: Duration,
+    ) -> Option<PendingTransfer> {
+        let transfer_type = ep.attributes & 0x3;
+        if transfer_type == EndpointAttributes::Isochronous as u8 {
+            return None;
+        }
+        let connection = match handle.lock().unwrap().connection() {
+            Ok(connection) => connection.clone(),
+            Err(err) => return Some(PendingTransfer::new(failed_transfer(transfer_error(err)))),
+        };
+        let endpoint = if transfer_type == EndpointAttributes::Control as u8 {
+            0
+        } else {
+            ep.address
+        };
+
+        let (sender, receiver) = oneshot::channel();
+        match connection.submit(
+            endpoint,
+            ep.interval,
+            setup.to_bytes(),
+            transfer_buffer_length,
+            req,
+            Box::new(move |res| {
+                sender.send(res.map_err(transfer_error)).ok();
+            }),
+        ) {
+            Ok(seqnum) => Some(
+                PendingTransfer::new(receiver)
+                    .with_cancel(move || connection.unlink(seqnum))
+                    .with_timeout(timeout),
+            ),
+            Err(err) => Some(PendingTransfer::new(failed_transfer(transfer_error(err)))),
+        }
+    }
+}
+
+/// Called with the result of a forwarded URB
+type Completion = Box<dyn FnOnce(rusb::Result<Vec<u8>>) + Send>;
+
+/// URBs forwarded to the upstream server and not completed yet
+#[derive(Default)]
+struct RemoteUrbs {
+    next_seqnum: u32,
+    /// Whether the URB reads from the device, and its completion unless it has been unlinked, by seqnum
+    submitted: HashMap<u32, (bool, Option<Completion>)>,
+    /// Seqnum of the unlinked URB, by seqnum of the CMD_UNLINK
+    unlinking: HashMap<u32, u32>,
+    closed: bool,
+}
+
+impl RemoteUrbs {
+    fn seqnum(&mut self) -> u32 {
+        // seqnum 0 is not used by the Linux kernel client
+        self.next_seqnum = self.next_seqnum.wrapping_add(1).max(1);
+        self.next_seqnum
+    }
+}
+
+/// A connection to the upstream server which has imported a device, served by a thread of its own
+struct UsbIpConnection {
+    commands: mpsc::UnboundedSender<UsbIpCommand>,
+    urbs: Arc<Mutex<RemoteUrbs>>,
+    devid: u32,
+    /// bConfigurationValue of the active configuration, as reported on import and set since
+    configuration: AtomicU8,
+}
+
+impl UsbIpConnection {
+    fn import(backend: &UsbIpBackend, bus_id: &str) -> rusb::Result<Self> {
+        let mut busid = [0; 32];
+        let len = bus_id.len().min(busid.len());
+        busid[..len].copy_from_slice(&bus_id.as_bytes()[..len]);
+
+        let (commands, receiver) = mpsc::unbounded_channel();
+        let urbs = Arc::new(Mutex::new(RemoteUrbs::default()));
+        let (ready, imported) = std::sync::mpsc::channel();
+        let addr = backend.addr;
+        let connection_urbs = urbs.clone();
+        spawn_client(async move {
+            let command = UsbIpCommand::OpReqImport { status: 0, busid };
+            let socket = match request(addr, command).await {
+                Ok((socket, UsbIpResponse::OpRepImport { status: 0, device })) => {
+                    // identifies the device in the URBs
+                    let imported = device.map_or((0, 0), |d| {
+                        (d.bus_num << 16 | d.dev_num, d.configuration_value)
+                    });
+                    ready.send(Ok(imported)).ok();
+                    socket
+                }
+                Ok((_, UsbIpResponse::OpRepImport { status, .. })) => {
+                    ready.send(Err(op_status_error(status))).ok();
+                    return;
+                }
+                Ok(_) => {
+                    ready
+                        .send(Err(std::io::Error::new(
+                            ErrorKind::InvalidData,
+                            "unexpected reply to OP_REQ_IMPORT",
+                        )))
+                        .ok();
+                    return;
+                }
+                Err(err) => {
+                    ready.send(Err(err)).ok();
+                    return;
+                }
+            };
+            serve(socket, receiver, connection_urbs).await;
+        });
+
+        let (devid, configuration) = match imported.recv_timeout(backend.timeout) {
+            Ok(Ok(imported)) => imported,
+            Ok(Err(err)) => {
+                warn!("Failed to import {} from {}: {}", bus_id, addr, err);
+                return Err(match err.kind() {
+                    ErrorKind::TimedOut => rusb::Error::Timeout,
+                    ErrorKind::InvalidData => rusb::Error::Io,
+                    ErrorKind::ResourceBusy => rusb::Error::Busy,
+                    ErrorKind::PermissionDenied => rusb::Error::Access,
+                    ErrorKind::NotFound => rusb::Error::NotFound,
+                    _ => rusb::Error::NoDevice,
+                });
+            }
+            Err(_) => {
+                warn!("Timed out importing {} from {}", bus_id, addr);
+                return Err(rusb::Error::Timeout);
+            }
+        };
+        debug!("Imported {} from {}", bus_id, addr);
+        Ok(Self {
+            commands,
+            urbs,
+            devid,
+            configuration: AtomicU8::new(configuration),
+        })
+    }
+
+    /// Forward a URB, calling `completion` with its result unless it is unlinked
+    ///
+    /// Control transfers go to endpoint 0, their direction is taken from `setup`.
+    fn submit(
+        &self,
+        endpoint: u8,
+        interval: u8,
+        setup: [u8; 8],
+        transfer_buffer_length: u32,
+        data: &[u8],
+        completion: Completion,
+    ) -> rusb::Result<u32> {
+        let direction_in = if endpoint & 0x7F == 0 {
+            setup[0] & 0x80 != 0
+        } else {
+            endpoint & 0x80 != 0
+        };
+
+        let mut urbs = self.urbs.lock().unwrap();
+        if urbs.closed {
+            return Err(rusb::Error::NoDevice);
+        }
+        let seqnum = urbs.seqnum();
+        urbs.submitted
+            .insert(seqnum, (direction_in, Some(completion)));
+        let command = UsbIpCommand::UsbIpCmdSubmit {
+            header: UsbIpHeaderBasic {
+                command: USBIP_CMD_SUBMIT.into(),
+                seqnum,
+                devid: self.devid,
+                direction: direction_in as u32,
+                ep: (endpoint & 0x7F) as u32,
+            },
+            transfer_flags: if direction_in { URB_DIR_IN } else { 0 },
+            transfer_buffer_length: if direction_in {
+                transfer_buffer_length
+            } else {
+                data.len() as u32
+            },
+            start_frame: 0,
+            number_of_packets: 0,
+            interval: interval as u32,
+            setup,
+            data: if direction_in { vec![] } else { data.to_vec() },
+            iso_packet_descriptor: vec![],
+        };
+        if self.commands.send(command).is_err() {
+            urbs.submitted.remove(&seqnum);
+            return Err(rusb::Error::NoDevice);
+        }
+        Ok(seqnum)
+    }
+
+    /// Unlink a URB, its completion is not called anymore
+    fn unlink(&self, seqnum: u32) {
+        let mut urbs = self.urbs.lock().unwrap();
+        match urbs.submitted.get_mut(&seqnum) {
+            Some((_, completion)) => *completion = None,
+            // completed already
+            None => return,
+        }
+        let unlink_seqnum = urbs.seqnum();
+        urbs.unlinking.insert(unlink_seqnum, seqnum);
+        let command = UsbIpCommand::UsbIpCmdUnlink {
+            header: UsbIpHeaderBasic {
+                command: USBIP_CMD_UNLINK.into(),
+                seqnum: unlink_seqnum,
+                devid: self.devid,
+                direction: 0,
+                ep: 0,
+            },
+            unlink_seqnum: seqnum,
+        };
+        self.commands.send(command).ok();
+    }
+}
+
+/// Run a client task in a thread of its own, so that blocking callers do not starve it
+fn spawn_client<F: Future<Output = ()> + Send + 'static>(task: F) {
+    let res = std::thread::Builder::new()
+        .name("usbip-client".to_string())
+        .spawn(move || {
+            match tokio::runtime::Builder::new_current_thread()
+                .enable_io()
+                .build()
+            {
+                Ok(runtime) => runtime.block_on(task),
+                Err(err) => warn!("Failed to start USB/IP client runtime: {}", err),
+            }
+        });
+    if let Err(err) = res {
+        warn!("Failed to start USB/IP client thread: {}", err);
+    }
+}
+
+/// An error for an OP_REP_DEVLIST or OP_REP_IMPORT failed with `status`
+fn op_status_error(status: u32) -> std::io::Error {
+    let kind = match status {
+        ST_NA => ErrorKind::PermissionDenied,
+        ST_DEV_BUSY => ErrorKind::ResourceBusy,
+        ST_NODEV => ErrorKind::NotFound,
+        _ => ErrorKind::Other,
+    };
+    std::io::Error::new(
+        kind,
+        format!("{} (status {})", op_status_message(status), status),
+    )
+}
+
+/// Connect to a server and send an operation, returning the connection along with the reply
+async fn request(addr: SocketAddr, command: UsbIpCommand) -> Result<(TcpStream, UsbIpResponse)> {
+    let mut socket = TcpStream::connect(addr).await?;
+    socket.write_all(&command.to_bytes()).await?;
+    let response = UsbIpResponse::read_from_socket(&mut socket, |_| false).await?;
+    Ok((socket, response))
+}
+
+/// Forward the URBs of an imported device until the connection is dropped or closed by the server
+async fn serve(
+    socket: TcpStream,
+    commands: mpsc::UnboundedReceiver<UsbIpCommand>,
+    urbs: Arc<Mutex<RemoteUrbs>>,
+) {
+    let (reader, writer) = socket.into_split();
+    let res = tokio::select! {
+        res = write_commands(writer, commands) => res,
+        res = read_responses(reader, urbs.clone()) => res,
+    };
+    if let Err(err) = res {
+        warn!("Connection to upstream server failed: {}", err);
+    }
+
+    // nothing is going to complete the remaining URBs
+    let mut urbs = urbs.lock().unwrap();
+    urbs.closed = true;
+    for (_, (_, completion)) in urbs.submitted.drain() {
+        if let Some(completion) = completion {
+            completion(Err(rusb::Error::NoDevice));
+        }
+    }
+}
+
+async fn write_commands(
+    mut writer: OwnedWriteHalf,
+    mut commands: mpsc::UnboundedReceiver<UsbIpCommand>,
+) -> Result<()> {
+    while let Some(command) = commands.recv().await {
+        writer.write_all(&command.to_bytes()).await?;
+    }
+    Ok(())
+}
+
+async fn read_responses(mut reader: OwnedReadHalf, urbs: Arc<Mutex<RemoteUrbs>>) -> Result<()> {
+    loop {
+        let response = UsbIpResponse::read_from_socket(&mut reader, |header| {
+            // servers like Linux do not fill in the direction of replies
+            header.direction == 1
+                || urbs
+                    .lock()
+                    .unwrap()
+                    .submitted
+                    .get(&header.seqnum)
+                    .is_some_and(|(direction_in, _)| *direction_in)
+        })
+        .await?;
+
+        let mut pending = urbs.lock().unwrap();
+        match response {
+            UsbIpResponse::UsbIpRetSubmit {
+                header,
+                status,
+                transfer_buffer,
+                ..
+            } => match pending.submitted.remove(&header.seqnum) {
+                Some((_, Some(completion))) => completion(if status == 0 {
+                    Ok(transfer_buffer)
+                } else {
+                    Err(urb_error(status as i32))
+                }),
+                Some((_, None)) => trace!("URB {} completed after unlink", header.seqnum),
+                None => warn!("Reply to unknown URB {}", header.seqnum),
+            },
+            UsbIpResponse::UsbIpRetUnlink { header, status } => {
+                if let Some(seqnum) = pending.unlinking.remove(&header.seqnum) {
+                    // unlinked before completion, no USBIP_RET_SUBMIT follows
+                    if status != 0 {
+                        pending.submitted.remove(&seqnum);
+                    }
+                }
+            }
+            _ => warn!("Unexpected reply to an imported device"),
+        }
+    }
+}
+
+/// Convert the status of a URB, the inverse of how it is reported to clients
+fn urb_error(status: i32) -> rusb::Error {
+    match -status {
+        EPIPE => rusb::Error::Pipe,
+        ETIMEDOUT => rusb::Error::Timeout,
+        ENODEV => rusb::Error::NoDevice,
+        EOVERFLOW => rusb::Error::Overflow,
+        ECONNRESET => rusb::Error::Interrupted,
+        EIO => rusb::Error::Io,
+        EBUSY => rusb::Error::Busy,
+        ENOENT => rusb::Error::NotFound,
+        EINVAL => rusb::Error::InvalidParam,
+        EACCES => rusb::Error::Access,
+        ENOMEM => rusb::Error::NoMem,
+        EOPNOTSUPP => rusb::Error::NotSupported,
+        _ => rusb::Error::Other,
+    }
+}
@@ -0,0 +1,711 @@
+//! Devices described in TOML or JSON configuration files
+use super::*;
+use std::path::Path;
+
+/// Parameters of an interface handler, passed as is to its [HandlerKind]
+pub type HandlerParams = serde_json::Map<String, serde_json::Value>;
+
+/// Creates interface handlers from their parameters
+pub type HandlerFactory =
+    dyn Fn(&HandlerParams) -> Result<Box<dyn UsbInterfaceHandler + Send>> + Send + Sync;
+
+fn invalid(msg: String) -> std::io::Error {
+    std::io::Error::new(ErrorKind::InvalidData, msg)
+}
+
+/// Devices of a configuration file
+///
+/// In TOML, each device is a `[[devices]]` table:
+/// ```toml
+/// [[devices]]
+/// vendor_id = 0x1234
+/// product_id = 0x5678
+/// product = "Keyboard"
+///
+/// [[devices.configurations]]
+/// [[devices.configurations.interfaces]]
+/// name = "Keyboard"
+/// handler = { kind = "hid_keyboard", text = "hello" }
+/// ```
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct DevicesConfig {
+    #[serde(default)]
+    pub devices: Vec<DeviceConfig>,
+}
+
+impl DevicesConfig {
+    pub fn from_toml(s: &str) -> Result<Self> {
+        toml::from_str(s).map_err(|err| invalid(err.to_string()))
+    }
+
+    pub fn from_json(s: &str) -> Result<Self> {
+        serde_json::from_str(s).map_err(|err| invalid(err.to_string()))
+    }
+
+    /// Load a `.json` file, or a TOML file otherwise
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let content = std::fs::read_to_string(path)?;
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("json") => Self::from_json(&content),
+            _ => Self::from_toml(&content),
+        }
+        .map_err(|err| invalid(format!("{}: {}", path.display(), err)))
+    }
+
+    /// Create the devices, with their interface handlers from `registry`
+    ///
+    /// Fails if several devices choose the same bus id.
+    pub fn build(&self, registry: &HandlerRegistry) -> Result<Vec<UsbDevice>> {
+        let mut bus_ids = HashSet::new();
+        for bus_id in self
+            .devices
+            .iter()
+            .filter_map(|device| device.bus_id.as_ref())
+        {
+            if !bus_ids.insert(bus_id) {
+                return Err(invalid(format!("duplicate bus id: {}", bus_id)));
+            }
+        }
+        self.devices
+            .iter()
+            .enumerate()
+            .map(|(index, device)| device.build(index as u32, registry))
+            .collect()
+    }
+}
+
+/// A simulated device
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct DeviceConfig {
+    /// Bus id to export the device under, allocated by the server by default
+    pub bus_id: Option<String>,
+    pub vendor_id: u16,
+    pub product_id: u16,
+    /// bcdDevice
+    #[serde(default)]
+    pub device_bcd: u16,
+    #[serde(default)]
+    pub device_class: u8,
+    #[serde(default)]
+    pub device_subclass: u8,
+    #[serde(default)]
+    pub device_protocol: u8,
+    /// High speed by default
+    pub speed: Option<UsbSpeed>,
+    pub manufacturer: Option<String>,
+    pub product: Option<String>,
+    pub serial: Option<String>,
+    #[serde(default)]
+    pub self_powered: bool,
+    #[serde(default)]
+    pub remote_wakeup: bool,
+    /// Maximum power consumption from the bus in mA
+    pub max_power: Option<u16>,
+    /// At most one configuration, as a [UsbDevice] has a single one, with a default one if none is given
+    #[serde(default)]
+    pub configurations: Vec<ConfigurationConfig>,
+}
+
+impl DeviceConfig {
+    /// Create the device, with its interface handlers from `registry`
+    ///
+    /// Fails if there are several configurations, if interfaces share an endpoint address, or if bulk endpoints of a
+    /// SuperSpeed device do not have 1024 bytes packets, with [ErrorKind::InvalidData]. Handlers failing to be created are reported as [Error::Handler].
+    pub fn build(&self, index: u32, registry: &HandlerRegistry) -> Result<UsbDevice> {
+        let default_configuration = ConfigurationConfig::default();
+        let configuration = match self.configurations.as_slice() {
+            [] => &default_configuration,
+            [configuration] => configuration,
+            _ => {
+                return Err(invalid(
+                    "only a single configuration is supported".to_string(),
+                ))
+            }
+        };
+
+        let mut device = UsbDevice::new(index)
+            .with_speed(self.speed.unwrap_or(UsbSpeed::High))
+            .with_self_powered(self.self_powered)
+            .with_remote_wakeup(self.remote_wakeup);
+        if let Some(bus_id) = &self.bus_id {
+            device = device.with_bus_id(bus_id);
+        }
+        device.vendor_id = self.vendor_id;
+        device.product_id = self.product_id;
+        device.device_bcd = crate::device::Version::from_bcd(self.device_bcd);
+        device.device_class = self.device_class;
+        device.device_subclass = self.device_subclass;
+        device.device_protocol = self.device_protocol;
+        if let Some(max_power) = self.max_power {
+            device = device.with_max_power(max_power);
+        }
+        if let Some(manufacturer) = &self.manufacturer {
+            device.set_manufacturer_name(manufacturer);
+        }
+        if let Some(product) = &self.product {
+            device.set_product_name(product);
+        }
+        if let Some(serial) = &self.serial {
+            device.set_serial_number(serial);
+        }
+
+        if let Some(value) = configuration.value {
+            device.configuration_value = value;
+        }
+        if let Some(name) = &configuration.name {
+            device.set_configuration_name(name);
+        }
+        // interface using each endpoint address
+        let mut addresses = HashMap::new();
+        for (number, interface) in configuration.interfaces.iter().enumerate() {
+            let kind = registry.kind(&interface.handler.kind)?;
+            let handler = (kind.factory)(&interface.handler.params).map_err(|err| {
+                let err = std::io::Error::new(
+                    err.kind(),
+                    format!("interface {} of device {}: {}", number, index, err),
+                );
+                std::io::Error::from(Error::Handler(err))
+            })?;
+            let endpoints = match &interface.endpoints {
+                Some(endpoints) => endpoints.iter().map(EndpointConfig::to_endpoint).collect(),
+                None => kind.endpoints.clone(),
+            };
+            for ep in &endpoints {
+                if let Some(other) = addresses.insert(ep.address, number) {
+                    return Err(invalid(format!(
+                        "endpoint {:#04x} of device {} used by interfaces {} and {}",
+                        ep.address, index, other, number
+                    )));
+                }
+                if device.is_superspeed()
+                    && ep.attributes & 0x3 == EndpointAttributes::Bulk as u8
+                    && ep.max_packet_size != 1024
+                {
+                    return Err(invalid(format!(
+                        "bulk endpoint {:#04x} of device {} has {} bytes packets, SuperSpeed requires 1024",
+                        ep.address, index, ep.max_packet_size
+                    )));
+                }
+            }
+            device = device.with_interface(
+                interface.class.unwrap_or(kind.class),
+                interface.subclass.unwrap_or(kind.subclass),
+                interface.protocol.unwrap_or(kind.protocol),
+                interface.name.as_deref().unwrap_or_default(),
+                endpoints,
+                Arc::new(Mutex::new(handler)),
+            );
+        }
+        Ok(device)
+    }
+}
+
+/// The configuration of a device
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct ConfigurationConfig {
+    /// bConfigurationValue, 1 by default
+    pub value: Option<u8>,
+    pub name: Option<String>,
+    #[serde(default)]
+    pub interfaces: Vec<InterfaceConfig>,
+}
+
+/// An interface, numbered by its position in the configuration
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct InterfaceConfig {
+    /// bInterfaceClass, the one of the handler kind by default
+    pub class: Option<u8>,
+    /// bInterfaceSubClass, the one of the handler kind by default
+    pub subclass: Option<u8>,
+    /// bInterfaceProtocol, the one of the handler kind by default
+    pub protocol: Option<u8>,
+    pub name: Option<String>,
+    /// The endpoints of the handler kind by default
+    pub endpoints: Option<Vec<EndpointConfig>>,
+    pub handler: HandlerConfig,
+}
+
+/// An endpoint of an interface
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct EndpointConfig {
+    /// bEndpointAddress, with bit 7 set for IN endpoints
+    pub address: u8,
+    pub transfer_type: EndpointAttributes,
+    pub max_packet_size: u16,
+    /// bInterval
+    #[serde(default)]
+    pub interval: u8,
+}
+
+impl EndpointConfig {
+    fn to_endpoint(&self) -> UsbEndpoint {
+        UsbEndpoint {
+            address: self.address,
+            attributes: self.transfer_type as u8,
+            max_packet_size: self.max_packet_size,
+            interval: self.interval,
+            ..Default::default()
+        }
+    }
+}
+
+/// The handler of an interface: a kind known to the [HandlerRegistry], along with its parameters
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct HandlerConfig {
+    pub kind: String,
+    #[serde(flatten)]
+    pub params: HandlerParams,
+}
+
+/// A kind of interface handler, referenced by name in configuration files
+pub struct HandlerKind {
+    /// bInterfaceClass of interfaces not setting their own
+    pub class: u8,
+    /// bInterfaceSubClass of interfaces not setting their own
+    pub subclass: u8,
+    /// bInterfaceProtocol of interfaces not setting their own
+    pub protocol: u8,
+    /// Endpoints of interfaces not listing their own
+    pub endpoints: Vec<UsbEndpoint>,
+    factory: Box<HandlerFactory>,
+}
+
+impl HandlerKind {
+    pub fn new<F>(
+        class: u8,
+        subclass: u8,
+        protocol: u8,
+        endpoints: Vec<UsbEndpoint>,
+        factory: F,
+    ) -> Self
+    where
+        F: Fn(&HandlerParams) -> Result<Box<dyn UsbInterfaceHandler + Send>>
+            + Send
+            + Sync
+            + 'static,
+    {
+        Self {
+            class,
+            subclass,
+            protocol,
+            endpoints,
+            factory: Box::new(factory),
+        }
+    }
+}
+
+/// Kinds of interface handlers available to configuration files, by name
+///
+/// The default registry knows the handlers of this crate:
+/// - `hid_keyboard`: a [hid::UsbHidKeyboardHandler], typing the optional `text` parameter
+/// - `cdc_acm`: a [cdc::UsbCdcAcmHandler], sending the optional `text` parameter to the host
+/// - `replay`: a vendor specific interface with a bulk IN and a bulk OUT endpoint, answering IN transfers with the
+///   payloads of the `file` parameter in turn, one per line in hex, e.g. `01 02 0a`
+pub struct HandlerRegistry {
+    kinds: HashMap<String, HandlerKind>,
+}
+
+impl HandlerRegistry {
+    /// A registry without any kind, not even the ones of this crate
+    pub fn empty() -> Self {
+        Self {
+            kinds: HashMap::new(),
+        }
+    }
+
+    /// Add a kind of handler, replacing any kind of the same name
+    pub fn with_kind(mut self, name: &str, kind: HandlerKind) -> Self {
+        self.kinds.insert(name.to_string(), kind);
+        self
+    }
+
+    pub fn kind(&self, name: &str) -> Result<&HandlerKind> {
+        self.kinds
+            .get(name)
+            .ok_or_else(|| invalid(format!("unknown handler kind: {}", name)))
+    }
+}
+
+impl Default for HandlerRegistry {
+    fn default() -> Self {
+        Self::empty()
+            .with_kind(
+                "hid_keyboard",
+                HandlerKind::new(
+                    ClassCode::HID as u8,
+                    0x00,
+                    0x00,
+                    vec![UsbEndpoint {
+                        address: 0x81,         // IN
+                        attributes: 0x03,      // Interrupt
+                        max_packet_size: 0x08, // 8 bytes
+                        interval: 10,
+                        ..Default::default()
+                    }],
+                    |params| {
+                        let mut handler = hid::UsbHidKeyboardHandler::new_keyboard();
+                        for c in text_param(params)?.bytes() {
+                            handler
+                                .pending_key_events
+                                .push_back(hid::UsbHidKeyboardReport::from_ascii(c));
+                        }
+                        Ok(Box::new(handler))
+                    },
+                ),
+            )
+            .with_kind(
+                "cdc_acm",
+                HandlerKind::new(
+                    ClassCode::CDC as u8,
+                    cdc::CDC_ACM_SUBCLASS,
+                    0x00,
+                    cdc::UsbCdcAcmHandler::endpoints(),
+                    |params| {
+                        let mut handler = cdc::UsbCdcAcmHandler::new();
+                        handler.tx_buffer = text_param(params)?.into_bytes();
+                        Ok(Box::new(handler))
+                    },
+                ),
+            )
+            .with_kind(
+                "replay",
+                HandlerKind::new(
+                    ClassCode::VendorSpecific as u8,
+                    0x00,
+                    0x00,
+                    vec![
+                        UsbEndpoint {
+                            address: 0x81,          // IN
+                            attributes: 0x02,       // Bulk
+                            max_packet_size: 0x200, // 512 bytes
+                            interval: 0,
+                            ..Default::default()
+                        },
+                        UsbEndpoint {
+                            address: 0x01,          // OUT
+                            attributes: 0x02,       // Bulk
+                            max_packet_size: 0x200, // 512 bytes
+                            interval: 0,
+                            ..Default::default()
+                        },
+                    ],
+                    |params| {
+                        let path = match params.get("file") {
+                            Some(serde_json::Value::String(path)) => path,
+                            Some(_) => {
+                                return Err(invalid("parameter file must be a string".to_string()))
+                            }
+                            None => return Err(invalid("parameter file is missing".to_string())),
+                        };
+                        let content = std::fs::read_to_string(path)
+                            .map_err(|err| invalid(format!("{}: {}", path, err)))?;
+                        Ok(Box::new(ReplayHandler::parse(&content)?))
+                    },
+                ),
+            )
+    }
+}
+
+/// Answers IN transfers with recorded payloads in turn, then with empty ones, see [HandlerRegistry]
+struct ReplayHandler {
+    payloads: VecDeque<Vec<u8>>,
+}
+
+impl ReplayHandler {
+    /// Read one payload in hex per line, skipping empty lines and `#` comments
+    fn parse(content: &str) -> Result<Self> {
+        let mut payloads = VecDeque::new();
+        for (number, line) in content.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default();
+            let digits: String = line.split_whitespace().collect();
+            if digits.is_empty() {
+                continue;
+            }
+            let payload = (0..digits.len())
+                .step_by(2)
+                .map(|i| {
+                    digits
+                        .get(i..i + 2)
+                        .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+                })
+                .collect::<Option<Vec<u8>>>()
+                .ok_or_else(|| invalid(format!("line {}: invalid hex payload", number + 1)))?;
+            payloads.push_back(payload);
+        }
+        Ok(Self { payloads })
+    }
+}
+
+impl UsbInterfaceHandler for ReplayHandler {
+    fn get_class_specific_descriptor(&self) -> Vec<u8> {
+        vec![]
+    }
+
+    fn handle_urb(
+        &mut self,
+        _interface: &UsbInterface,
+        ep: UsbEndpoint,
+        transfer_buffer_length: u32,
+        _setup: SetupPacket,
+        _req: &[u8],
+    ) -> Result<Vec<u8>> {
+        // nothing recorded for control transfers
+        if ep.attributes == EndpointAttributes::Control as u8 || ep.direction() == Direction::Out {
+            return Ok(vec![]);
+        }
+        let mut payload = self.payloads.pop_front().unwrap_or_default();
+        payload.truncate(transfer_buffer_length as usize);
+        Ok(payload)
+    }
+
+    fn as_any(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+/// The optional `text` parameter of the handlers of this crate
+fn text_param(params: &HandlerParams) -> Result<String> {
+    match params.get("text") {
+        None => Ok(String::new()),
+        Some(serde_json::Value::String(text)) => Ok(text.clone()),
+        Some(_) => Err(invalid("parameter text must be a string".to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::tests::*;
+
+    const DEVICES: &str = r#"
+        [[devices]]
+        vendor_id = 0x1234
+        product_id = 0x5678
+        device_bcd = 0x0102
+        manufacturer = "QA"
+        product = "Keyboard"
+        speed = "Full"
+        remote_wakeup = true
+
+        [[devices.configurations]]
+        name = "Typing"
+
+        [[devices.configurations.interfaces]]
+        name = "Keys"
+        handler = { kind = "hid_keyboard", text = "hi" }
+
+        [[devices]]
+        bus_id = "1-2"
+        vendor_id = 0x1234
+        product_id = 0x5679
+
+        [[devices.configurations]]
+        [[devices.configurations.interfaces]]
+        endpoints = [
+            { address = 0x82, transfer_type = "Bulk", max_packet_size = 64 },
+            { address = 0x02, transfer_type = "Bulk", max_packet_size = 64 },
+        ]
+        handler = { kind = "cdc_acm", text = "ok" }
+    "#;
+
+    #[test]
+    fn devices_from_toml() {
+        setup_test_logger();
+        let config = DevicesConfig::from_toml(DEVICES).unwrap();
+        let devices = config.build(&HandlerRegistry::default()).unwrap();
+        assert_eq!(devices.len(), 2);
+
+        let keyboard = &devices[0];
+        // allocated once exported
+        assert_eq!(keyboard.bus_id, "");
+        assert_eq!(keyboard.vendor_id, 0x1234);
+        assert_eq!(keyboard.device_bcd.to_bcd(), 0x0102);
+        assert_eq!(keyboard.usb_speed(), UsbSpeed::Full);
+        assert!(keyboard.remote_wakeup);
+        assert_eq!(
+            keyboard.get_string(keyboard.product_string_index(), LANGUAGE_ID_EN_US),
+            Some("Keyboard")
+        );
+        assert_eq!(
+            keyboard.get_string(keyboard.configuration_string_index(), LANGUAGE_ID_EN_US),
+            Some("Typing")
+        );
+        let intf = &keyboard.interfaces[0];
+        assert_eq!(intf.interface_class, ClassCode::HID as u8);
+        assert_eq!(intf.endpoints[0].address, 0x81);
+        let mut handler = intf.handler.lock().unwrap();
+        let hid = handler
+            .as_any()
+            .downcast_mut::<hid::UsbHidKeyboardHandler>()
+            .unwrap();
+        assert_eq!(hid.pending_key_events.len(), 2);
+
+        let serial = &devices[1];
+        assert_eq!(serial.bus_id, "1-2");
+        let intf = &serial.interfaces[0];
+        assert_eq!(intf.interface_subclass, cdc::CDC_ACM_SUBCLASS);
+        assert_eq!(
+            intf.endpoints
+                .iter()
+                .map(|ep| (ep.address, ep.attributes))
+                .collect::<Vec<_>>(),
+            [(0x82, 0x02), (0x02, 0x02)]
+        );
+        let mut handler = intf.handler.lock().unwrap();
+        let cdc = handler
+            .as_any()
+            .downcast_mut::<cdc::UsbCdcAcmHandler>()
+            .unwrap();
+        assert_eq!(cdc.tx_buffer, b"ok");
+    }
+
+    #[test]
+    fn devices_from_json() {
+        setup_test_logger();
+        let toml = DevicesConfig::from_toml(DEVICES).unwrap();
+        let json = serde_json::to_string(&toml).unwrap();
+        let config = DevicesConfig::from_json(&json).unwrap();
+        assert_eq!(config.devices.len(), 2);
+        assert_eq!(
+            config.devices[1].configurations[0].interfaces[0]
+                .handler
+                .params["text"],
+            "ok"
+        );
+        assert!(config.build(&HandlerRegistry::default()).is_ok());
+
+        let mut config = config;
+        config.devices[0].bus_id = Some("1-2".to_string());
+        match config.build(&HandlerRegistry::default()) {
+            Err(err) => assert_eq!(err.to_string(), "duplicate bus id: 1-2"),
+            Ok(_) => panic!("duplicate bus id accepted"),
+        }
+    }
+
+    #[test]
+    fn custom_and_unknown_kinds() {
+        setup_test_logger();
+        let config = DevicesConfig::from_json(
+            r#"{"devices": [{"vendor_id": 1, "product_id": 2, "configurations": [
+                {"interfaces": [{"handler": {"kind": "echo"}}]}
+            ]}]}"#,
+        )
+        .unwrap();
+        match config.build(&HandlerRegistry::default()) {
+            Err(err) => assert_eq!(err.to_string(), "unknown handler kind: echo"),
+            Ok(_) => panic!("unknown kind accepted"),
+        }
+
+        let registry = HandlerRegistry::empty().with_kind(
+            "echo",
+            HandlerKind::new(0xFF, 0x01, 0x02, vec![], |_| {
+                Ok(Box::new(cdc::UsbCdcAcmHandler::new()))
+            }),
+        );
+        let devices = config.build(&registry).unwrap();
+        assert_eq!(devices[0].interfaces[0].interface_class, 0xFF);
+        assert_eq!(devices[0].interfaces[0].interface_protocol, 0x02);
+
+        let registry = HandlerRegistry::empty().with_kind(
+            "echo",
+            HandlerKind::new(0xFF, 0x01, 0x02, vec![], |_| {
+                Err(std::io::Error::other("no echo"))
+            }),
+        );
+        match config.build(&registry).map_err(Error::from) {
+            Err(Error::Handler(err)) => {
+                assert_eq!(err.to_string(), "interface 0 of device 0: no echo")
+            }
+            _ => panic!("failed handler accepted"),
+        }
+    }
+
+    #[test]
+    fn conflicting_devices_rejected() {
+        setup_test_logger();
+        let config = DevicesConfig::from_json(
+            r#"{"devices": [{"vendor_id": 1, "product_id": 2, "configurations": [
+                {"interfaces": [
+                    {"handler": {"kind": "hid_keyboard"}},
+                    {"handler": {"kind": "hid_keyboard"}}
+                ]}
+            ]}]}"#,
+        )
+        .unwrap();
+        match config.build(&HandlerRegistry::default()) {
+            Err(err) => assert_eq!(
+                err.to_string(),
+                "endpoint 0x81 of device 0 used by interfaces 0 and 1"
+            ),
+            Ok(_) => panic!("shared endpoint accepted"),
+        }
+
+        let mut config = config;
+        config.devices[0].configurations[0].interfaces.pop();
+        config.devices[0]
+            .configurations
+            .push(ConfigurationConfig::default());
+        assert!(config.build(&HandlerRegistry::default()).is_err());
+    }
+
+    #[test]
+    fn superspeed_bulk_packets_checked() {
+        setup_test_logger();
+        let json = |max_packet_size| {
+            format!(
+                r#"{{"devices": [{{"vendor_id": 1, "product_id": 2, "speed": "Super", "configurations": [
+                    {{"interfaces": [{{"handler": {{"kind": "cdc_acm"}}, "endpoints": [
+                        {{"address": 129, "transfer_type": "Bulk", "max_packet_size": {}}}
+                    ]}}]}}
+                ]}}]}}"#,
+                max_packet_size
+            )
+        };
+        let config = DevicesConfig::from_json(&json(512)).unwrap();
+        match config.build(&HandlerRegistry::default()) {
+            Err(err) => assert_eq!(
+                err.to_string(),
+                "bulk endpoint 0x81 of device 0 has 512 bytes packets, SuperSpeed requires 1024"
+            ),
+            Ok(_) => panic!("SuperSpeed bulk endpoint of 512 bytes accepted"),
+        }
+        let config = DevicesConfig::from_json(&json(1024)).unwrap();
+        let devices = config.build(&HandlerRegistry::default()).unwrap();
+        assert_eq!(devices[0].interfaces[0].endpoints[0].max_packet_size, 1024);
+    }
+
+    #[test]
+    fn payloads_replayed() {
+        setup_test_logger();
+        let path = std::env::temp_dir().join(format!("usbip-replay-{}.txt", std::process::id()));
+        std::fs::write(&path, "# recorded\n01 02 0a\n\nff00 # status\n").unwrap();
+        let config = DevicesConfig::from_json(&format!(
+            r#"{{"devices": [{{"vendor_id": 1, "product_id": 2, "configurations": [
+                {{"interfaces": [{{"handler": {{"kind": "replay", "file": {:?}}}}}]}}
+            ]}}]}}"#,
+            path.to_string_lossy()
+        ))
+        .unwrap();
+        let devices = config.build(&HandlerRegistry::default()).unwrap();
+        std::fs::remove_file(&path).ok();
+
+        let intf = &devices[0].interfaces[0];
+        let ep = intf.endpoints[0];
+        let setup = SetupPacket::parse(&[0; 8]);
+        let mut handler = intf.handler.lock().unwrap();
+        assert_eq!(
+            handler.handle_urb(intf, ep, 512, setup, &[]).unwrap(),
+            [0x01, 0x02, 0x0a]
+        );
+        // truncated to the transfer buffer
+        assert_eq!(handler.handle_urb(intf, ep, 1, setup, &[]).unwrap(), [0xff]);
+        assert!(handler
+            .handle_urb(intf, ep, 512, setup, &[])
+            .unwrap()
+            .is_empty());
+
+        assert!(ReplayHandler::parse("01 2").is_err());
+        assert!(ReplayHandler::parse("zz").is_err());
+    }
+}
@@ -1,7 +1,7 @@
 use super::*;
 
 /// A list of known USB speeds
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, FromPrimitive)]
 #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum UsbSpeed {
     Unknown = 0x0,
@@ -56,9 +56,15 @@ pub enum EndpointAttributes {
 /// Already exists in rusb crate
 pub use rusb::Direction;
 
+/// LANGID of English (United States), the default language of string descriptors
+pub const LANGUAGE_ID_EN_US: u16 = 0x0409;
+
 /// Emulated max packet size of EP0
 pub const EP0_MAX_PACKET_SIZE: u16 = 64;
 
+/// Max packet size of EP0 for SuperSpeed devices
+pub const EP0_MAX_PACKET_SIZE_SUPERSPEED: u16 = 512;
+
 /// A list of defined USB standard requests
 /// from USB 2.0 standard Table 9.4. Standard Request Codes
 #[derive(Copy, Clone, Debug, FromPrimitive)]
@@ -94,4 +100,23 @@ pub enum DescriptorType {
     Debug = 0xA,
     InterfaceAssociation = 0xB,
     BOS = 0xF,
+    DeviceCapability = 0x10,
+    SuperSpeedEndpointCompanion = 0x30,
 }
+
+/// A list of defined USB standard feature selectors
+/// from USB 2.0 standard Table 9.6. Standard Feature Selectors
+#[derive(Copy, Clone, Debug, FromPrimitive)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum FeatureSelector {
+    EndpointHalt = 0,
+    DeviceRemoteWakeup = 1,
+    TestMode = 2,
+}
+
+/// bmRequestType of class requests to a hub port, sent by USB/IP clients to the virtual port of a device
+pub const REQUEST_TYPE_PORT: u8 = 0x23;
+
+/// Hub port feature selector to reset the port
+/// from USB 2.0 standard Table 11-17. Hub Class Feature Selectors
+pub const PORT_FEATURE_RESET: u16 = 4;
@@ -1,5 +1,6 @@
 use super::*;
 use rusb::Version as rusbVersion;
+use std::sync::atomic::{AtomicBool, Ordering};
 
 #[derive(Clone, Default)]
 #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
@@ -9,6 +10,29 @@ pub struct Version {
     pub patch: u8,
 }
 
+impl Version {
+    pub fn new(major: u8, minor: u8, patch: u8) -> Self {
+        Self {
+            major,
+            minor,
+            patch,
+        }
+    }
+
+    /// Encode as a binary-coded decimal, e.g. `0x0210` for 2.1.0
+    pub fn to_bcd(&self) -> u16 {
+        ((self.major / 10 % 10) as u16) << 12
+            | ((self.major % 10) as u16) << 8
+            | ((self.minor % 10) as u16) << 4
+            | (self.patch % 10) as u16
+    }
+
+    /// Decode from a binary-coded decimal, e.g. `0x0210` for 2.1.0
+    pub fn from_bcd(bcd: u16) -> Self {
+        rusbVersion::from_bcd(bcd).into()
+    }
+}
+
 impl From<rusbVersion> for Version {
     fn from(value: rusbVersion) -> Self {
         Self {
@@ -34,6 +58,10 @@ pub struct UsbDevice {
     pub bus_num: u32,
     pub dev_num: u32,
     pub speed: u32,
+    /// Speeds the device can operate at, dual-speed devices support both full and high speed
+    ///
+    /// Left empty for devices of the host, which answer as single-speed devices.
+    pub supported_speeds: Vec<UsbSpeed>,
     pub vendor_id: u16,
     pub product_id: u16,
     pub device_bcd: Version,
@@ -49,10 +77,36 @@ pub struct UsbDevice {
 
     pub usb_version: Version,
 
+    /// Whether the device is self-powered, reported in bmAttributes and GET_STATUS
+    pub self_powered: bool,
+    /// Whether the device supports remote wakeup
+    pub remote_wakeup: bool,
+    /// Maximum power consumption from the bus in mA
+    pub max_power: u16,
+
+    /// Device capabilities reported in the BOS descriptor
+    pub bos_capabilities: Vec<BosCapability>,
+    /// Microsoft OS 2.0 descriptors, retrieved by a vendor request
+    pub ms_os_20: Option<msos::MsOs20DescriptorSet>,
+    /// WebUSB descriptors, retrieved by a vendor request
+    pub webusb: Option<webusb::WebUsb>,
+
+    /// Configuration descriptor reported as is instead of the one generated from `interfaces`,
+    /// e.g. mirrored from a host device with its alternate settings
+    pub raw_configuration: Option<Vec<u8>>,
+    /// BOS descriptor reported as is instead of the one generated from `bos_capabilities`
+    pub raw_bos: Option<Vec<u8>>,
+
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub(crate) remote_wakeup_state: RemoteWakeup,
+
     pub(crate) ep0_in: UsbEndpoint,
     pub(crate) ep0_out: UsbEndpoint,
     // strings
+    /// LANGID of `string_pool`, used when a translation is missing
+    pub(crate) default_language: u16,
     pub(crate) string_pool: HashMap<u8, String>,
+    pub(crate) string_translations: HashMap<u16, HashMap<u8, String>>,
     pub(crate) string_configuration: u8,
     pub(crate) string_manufacturer: u8,
     pub(crate) string_product: u8,
@@ -60,27 +114,32 @@ pub struct UsbDevice {
 }
 
 impl UsbDevice {
+    /// Create a simulated device, whose bus id gets allocated by the server it is added to
     pub fn new(index: u32) -> Self {
         let mut res = Self {
-            path: "/sys/bus/0/0/0".to_string(),
-            bus_id: "0-0-0".to_string(),
             dev_num: index,
             speed: UsbSpeed::High as u32,
+            supported_speeds: vec![UsbSpeed::Full, UsbSpeed::High],
+            usb_version: Version::new(2, 0, 0),
+            default_language: LANGUAGE_ID_EN_US,
             ep0_in: UsbEndpoint {
                 address: 0x80,
                 attributes: EndpointAttributes::Control as u8,
                 max_packet_size: EP0_MAX_PACKET_SIZE,
                 interval: 0,
+                ..Default::default()
             },
             ep0_out: UsbEndpoint {
                 address: 0x00,
                 attributes: EndpointAttributes::Control as u8,
                 max_packet_size: EP0_MAX_PACKET_SIZE,
                 interval: 0,
+                ..Default::default()
             },
             // configured by default
             configuration_value: 1,
             num_configurations: 1,
+            max_power: 100,
             ..Self::default()
         };
         res.string_configuration = res.new_string("Default Configuration");
@@ -114,6 +173,81 @@ impl UsbDevice {
             .insert(self.string_manufacturer, name.to_string())
     }
 
+    /// Set the language of the strings set without a language, en-US by default
+    pub fn with_default_language(mut self, language: u16) -> Self {
+        self.default_language = language;
+        self
+    }
+
+    /// Add a language to the supported LANGIDs, without translating any string yet
+    pub fn with_language(mut self, language: u16) -> Self {
+        if language != self.default_language {
+            self.string_translations.entry(language).or_default();
+        }
+        self
+    }
+
+    /// Set the translation of the string at `index` in `language`
+    ///
+    /// Returns the old value, if present.
+    pub fn set_string_translation(
+        &mut self,
+        language: u16,
+        index: u8,
+        value: &str,
+    ) -> Option<String> {
+        if language == self.default_language {
+            self.string_pool.insert(index, value.to_string())
+        } else {
+            self.string_translations
+                .entry(language)
+                .or_default()
+                .insert(index, value.to_string())
+        }
+    }
+
+    /// Supported LANGIDs, the default language first
+    pub fn languages(&self) -> Vec<u16> {
+        let mut languages: Vec<u16> = self
+            .string_translations
+            .keys()
+            .copied()
+            .filter(|&l| l != self.default_language)
+            .collect();
+        languages.sort_unstable();
+        languages.insert(0, self.default_language);
+        languages
+    }
+
+    /// Look up the string at `index` in `language`, falling back to the default language
+    pub fn get_string(&self, index: u8, language: u16) -> Option<&str> {
+        self.string_translations
+            .get(&language)
+            .and_then(|strings| strings.get(&index))
+            .or_else(|| self.string_pool.get(&index))
+            .map(|s| s.as_str())
+    }
+
+    /// Index of the manufacturer string
+    pub fn manufacturer_string_index(&self) -> u8 {
+        self.string_manufacturer
+    }
+
+    /// Index of the product string
+    pub fn product_string_index(&self) -> u8 {
+        self.string_product
+    }
+
+    /// Index of the serial number string
+    pub fn serial_number_string_index(&self) -> u8 {
+        self.string_serial
+    }
+
+    /// Index of the configuration string
+    pub fn configuration_string_index(&self) -> u8 {
+        self.string_configuration
+    }
+
     pub fn with_interface(
         mut self,
         interface_class: u8,
@@ -137,6 +271,157 @@ impl UsbDevice {
         self
     }
 
+    /// Export the device under `bus_id`, e.g. `1-2`, instead of the first free one of the server
+    pub fn with_bus_id(mut self, bus_id: &str) -> Self {
+        self.bus_id = bus_id.to_string();
+        self
+    }
+
+    /// Set the operating speed of the device
+    ///
+    /// SuperSpeed devices get a USB 3.x bcdUSB, a 512 bytes EP0 and endpoint companion descriptors. Their bulk
+    /// endpoints must have a `max_packet_size` of 1024 bytes.
+    pub fn with_speed(mut self, speed: UsbSpeed) -> Self {
+        self.speed = speed as u32;
+        match speed {
+            UsbSpeed::Super | UsbSpeed::SuperPlus => {
+                self.usb_version = if speed == UsbSpeed::Super {
+                    Version::new(3, 0, 0)
+                } else {
+                    Version::new(3, 2, 0)
+                };
+                self.ep0_in.max_packet_size = EP0_MAX_PACKET_SIZE_SUPERSPEED;
+                self.ep0_out.max_packet_size = EP0_MAX_PACKET_SIZE_SUPERSPEED;
+            }
+            _ => {
+                if self.usb_version.major >= 3 {
+                    self.usb_version = Version::new(2, 0, 0);
+                }
+                self.ep0_in.max_packet_size = EP0_MAX_PACKET_SIZE;
+                self.ep0_out.max_packet_size = EP0_MAX_PACKET_SIZE;
+            }
+        }
+        self
+    }
+
+    /// Get the operating speed of the device
+    pub fn usb_speed(&self) -> UsbSpeed {
+        FromPrimitive::from_u32(self.speed).unwrap_or(UsbSpeed::Unknown)
+    }
+
+    /// Whether the device operates at SuperSpeed or faster
+    pub fn is_superspeed(&self) -> bool {
+        matches!(self.usb_speed(), UsbSpeed::Super | UsbSpeed::SuperPlus)
+    }
+
+    /// Set the speeds the device can operate at
+    pub fn with_supported_speeds(mut self, speeds: &[UsbSpeed]) -> Self {
+        self.supported_speeds = speeds.to_vec();
+        self
+    }
+
+    /// Whether the device can operate at both full and high speed, and currently does at one of them
+    pub fn is_dual_speed(&self) -> bool {
+        matches!(self.usb_speed(), UsbSpeed::Full | UsbSpeed::High)
+            && self.supported_speeds.contains(&UsbSpeed::Full)
+            && self.supported_speeds.contains(&UsbSpeed::High)
+    }
+
+    /// Set the maximum power consumption from the bus in mA
+    pub fn with_max_power(mut self, max_power: u16) -> Self {
+        self.max_power = max_power;
+        self
+    }
+
+    /// Report the device as self-powered
+    pub fn with_self_powered(mut self, self_powered: bool) -> Self {
+        self.self_powered = self_powered;
+        self
+    }
+
+    /// Advertise remote wakeup support
+    pub fn with_remote_wakeup(mut self, remote_wakeup: bool) -> Self {
+        self.remote_wakeup = remote_wakeup;
+        self
+    }
+
+    /// Add a device capability to the BOS descriptor
+    pub fn with_bos_capability(mut self, capability: BosCapability) -> Self {
+        self.bos_capabilities.push(capability);
+        self.require_bos();
+        self
+    }
+
+    /// Advertise a Microsoft OS 2.0 descriptor set, e.g. to bind WinUSB automatically
+    pub fn with_ms_os_20_descriptors(mut self, descriptors: msos::MsOs20DescriptorSet) -> Self {
+        self.ms_os_20 = Some(descriptors);
+        self.require_bos();
+        self
+    }
+
+    /// Advertise WebUSB support and landing page
+    pub fn with_webusb(mut self, webusb: webusb::WebUsb) -> Self {
+        self.webusb = Some(webusb);
+        self.require_bos();
+        self
+    }
+
+    /// Hosts only request the BOS descriptor from devices with bcdUSB 2.01 or higher
+    fn require_bos(&mut self) {
+        if self.usb_version.to_bcd() < 0x0201 {
+            self.usb_version = Version::new(2, 1, 0);
+        }
+    }
+
+    /// All device capabilities, including platform capabilities of vendor descriptors
+    pub(crate) fn all_bos_capabilities(&self) -> Vec<BosCapability> {
+        let mut capabilities = self.bos_capabilities.clone();
+        if self.is_superspeed() {
+            // USB 3.x devices are required to report these capabilities
+            let has = |t: DeviceCapabilityType| {
+                capabilities
+                    .iter()
+                    .any(|c| c.capability_type() as u8 == t as u8)
+            };
+            let mut required = vec![];
+            if !has(DeviceCapabilityType::Usb20Extension) {
+                required.push(BosCapability::usb20_lpm());
+            }
+            if !has(DeviceCapabilityType::SuperSpeed) {
+                required.push(BosCapability::superspeed());
+            }
+            if self.usb_speed() == UsbSpeed::SuperPlus && !has(DeviceCapabilityType::SuperSpeedPlus)
+            {
+                required.push(BosCapability::superspeed_plus_gen2());
+            }
+            capabilities.splice(0..0, required);
+        }
+        if let Some(webusb) = &self.webusb {
+            capabilities.push(webusb.capability());
+        }
+        if let Some(ms_os_20) = &self.ms_os_20 {
+            capabilities.push(ms_os_20.capability());
+        }
+        capabilities
+    }
+
+    /// Answer vendor requests for WebUSB and MS OS descriptors
+    fn handle_vendor_descriptor_request(&self, setup_packet: &SetupPacket) -> Option<Vec<u8>> {
+        self.webusb
+            .as_ref()
+            .and_then(|w| w.handle_request(setup_packet))
+            .or_else(|| {
+                self.ms_os_20
+                    .as_ref()
+                    .and_then(|m| m.handle_request(setup_packet))
+            })
+    }
+
+    /// Get a handle to signal remote wakeup from handlers
+    pub fn remote_wakeup_handle(&self) -> RemoteWakeup {
+        self.remote_wakeup_state.clone()
+    }
+
     pub fn with_device_handler(
         mut self,
         handler: Arc<Mutex<Box<dyn UsbDeviceHandler + Send>>>,
@@ -147,14 +432,107 @@ impl UsbDevice {
 
     pub(crate) fn new_string(&mut self, s: &str) -> u8 {
         for i in 1.. {
-            if self.string_pool.get(&i).is_none() {
-                self.string_pool.insert(i, s.to_string());
+            if let std::collections::hash_map::Entry::Vacant(e) = self.string_pool.entry(i) {
+                e.insert(s.to_string());
                 return i;
             }
         }
         panic!("string poll exhausted")
     }
 
+    /// bmAttributes of the configuration descriptor
+    pub(crate) fn configuration_attributes(&self) -> u8 {
+        let mut attributes = 0x80; // reserved, set to one
+        if self.self_powered {
+            attributes |= 0x40;
+        }
+        if self.remote_wakeup {
+            attributes |= 0x20;
+        }
+        attributes
+    }
+
+    /// bMaxPower of the configuration descriptor, in 8mA units for SuperSpeed and 2mA units otherwise
+    pub(crate) fn configuration_max_power(&self) -> u8 {
+        let unit = if self.is_superspeed() { 8 } else { 2 };
+        self.max_power.div_ceil(unit).min(0xFF) as u8
+    }
+
+    /// bMaxPacketSize0 of the device descriptor, an exponent for SuperSpeed
+    pub(crate) fn ep0_max_packet_size(&self) -> u8 {
+        if self.is_superspeed() {
+            self.ep0_in.max_packet_size.max(1).trailing_zeros() as u8
+        } else {
+            self.ep0_in.max_packet_size as u8
+        }
+    }
+
+    /// Generate the configuration descriptor with all interfaces and endpoints,
+    /// or the one at the other speed for [DescriptorType::OtherSpeedConfiguration]
+    pub(crate) fn configuration_descriptor(&self, descriptor_type: DescriptorType) -> Vec<u8> {
+        let other_speed = matches!(descriptor_type, DescriptorType::OtherSpeedConfiguration);
+        if let Some(raw) = self.raw_configuration.as_ref().filter(|_| !other_speed) {
+            return raw.clone();
+        }
+        let mut desc = vec![
+            0x09,                  // bLength
+            descriptor_type as u8, // bDescriptorType: Configuration or Other Speed Configuration
+            0x00,
+            0x00,                            // wTotalLength: to be filled below
+            self.interfaces.len() as u8,     // bNumInterfaces
+            self.configuration_value,        // bConfigurationValue
+            self.string_configuration,       // iConfiguration
+            self.configuration_attributes(), // bmAttributes
+            self.configuration_max_power(),  // bMaxPower
+        ];
+        for (i, intf) in self.interfaces.iter().enumerate() {
+            let mut intf_desc = vec![
+                0x09,                            // bLength
+                DescriptorType::Interface as u8, // bDescriptorType: Interface
+                i as u8,                         // bInterfaceNum
+                0x00,                            // bAlternateSettings
+                intf.endpoints.len() as u8,      // bNumEndpoints
+                intf.interface_class,            // bInterfaceClass
+                intf.interface_subclass,         // bInterfaceSubClass
+                intf.interface_protocol,         // bInterfaceProtocol
+                intf.string_interface,           //iInterface
+            ];
+            // class specific endpoint
+            let mut specific = intf.class_specific_descriptor.clone();
+            intf_desc.append(&mut specific);
+            // endpoint descriptors
+            for endpoint in &intf.endpoints {
+                if other_speed {
+                    intf_desc.extend(endpoint.other_speed_descriptor(self.usb_speed()));
+                } else {
+                    intf_desc.extend(endpoint.descriptor(self.is_superspeed()));
+                }
+            }
+            desc.append(&mut intf_desc);
+        }
+        // length
+        let len = desc.len() as u16;
+        desc[2] = len as u8;
+        desc[3] = (len >> 8) as u8;
+        desc
+    }
+
+    /// Called when a client imports the device
+    pub(crate) fn attach(&self) -> std::result::Result<(), Error> {
+        match self.device_handler.as_ref() {
+            Some(lock) => lock.lock().unwrap().attach().map_err(Error::Handler),
+            None => Ok(()),
+        }
+    }
+
+    /// Called when the client releases the device
+    pub(crate) fn detach(&self) -> std::result::Result<(), Error> {
+        match self.device_handler.as_ref() {
+            Some(lock) => lock.lock().unwrap().detach().map_err(Error::Handler),
+            None => Ok(()),
+        }
+    }
+
     pub(crate) fn find_ep(&self, ep: u8) -> Option<(UsbEndpoint, Option<&UsbInterface>)> {
         if ep == self.ep0_in.address {
             Some((self.ep0_in, None))
@@ -190,8 +568,7 @@ impl UsbDevice {
         result.extend_from_slice(&self.speed.to_be_bytes());
         result.extend_from_slice(&self.vendor_id.to_be_bytes());
         result.extend_from_slice(&self.product_id.to_be_bytes());
-        result.push(self.device_bcd.major);
-        result.push(self.device_bcd.minor);
+        result.extend_from_slice(&self.device_bcd.to_bcd().to_be_bytes());
         result.push(self.device_class);
         result.push(self.device_subclass);
         result.push(self.device_protocol);
@@ -229,10 +606,26 @@ impl UsbDevice {
         use EndpointAttributes::*;
         use StandardRequest::*;
 
-        match (FromPrimitive::from_u8(ep.attributes), ep.direction()) {
+        // any traffic from the host means it has resumed
+        if self.remote_wakeup_state.take_pending() {
+            debug!("Host resumed after remote wakeup");
+        }
+
+        // low bits are the transfer type, the others the synchronization and usage type
+        match (FromPrimitive::from_u8(ep.attributes & 0x3), ep.direction()) {
             (Some(Control), In) => {
                 // control in
                 debug!("Control IN setup={:x?}", setup_packet);
+                if setup_packet.request_type == 0b11000000 {
+                    // vendor request to device
+                    if let Some(mut desc) = self.handle_vendor_descriptor_request(&setup_packet) {
+                        // requested len too short: wLength < real length
+                        if setup_packet.length < desc.len() as u16 {
+                            desc.resize(setup_packet.length as usize, 0);
+                        }
+                        return Ok(desc);
+                    }
+                }
                 match (
                     setup_packet.request_type,
                     FromPrimitive::from_u8(setup_packet.request),
@@ -245,18 +638,18 @@ impl UsbDevice {
                                 let mut desc = vec![
                                     0x12,         // bLength
                                     Device as u8, // bDescriptorType: Device
-                                    self.usb_version.minor,
-                                    self.usb_version.major, // bcdUSB: USB 2.0
-                                    self.device_class,      // bDeviceClass
-                                    self.device_subclass,   // bDeviceSubClass
-                                    self.device_protocol,   // bDeviceProtocol
-                                    self.ep0_in.max_packet_size as u8, // bMaxPacketSize0
-                                    self.vendor_id as u8,   // idVendor
+                                    self.usb_version.to_bcd() as u8,
+                                    (self.usb_version.to_bcd() >> 8) as u8, // bcdUSB
+                                    self.device_class,                      // bDeviceClass
+                                    self.device_subclass,                   // bDeviceSubClass
+                                    self.device_protocol,                   // bDeviceProtocol
+                                    self.ep0_max_packet_size(),             // bMaxPacketSize0
+                                    self.vendor_id as u8,                   // idVendor
                                     (self.vendor_id >> 8) as u8,
                                     self.product_id as u8, // idProduct
                                     (self.product_id >> 8) as u8,
-                                    self.device_bcd.minor, // bcdDevice
-                                    self.device_bcd.major,
+                                    self.device_bcd.to_bcd() as u8, // bcdDevice
+                                    (self.device_bcd.to_bcd() >> 8) as u8,
                                     self.string_manufacturer, // iManufacturer
                                     self.string_product,      // iProduct
                                     self.string_serial,       // iSerial
@@ -271,12 +664,10 @@ impl UsbDevice {
                             }
                             Some(BOS) => {
                                 debug!("Get BOS descriptor");
-                                let mut desc = vec![
-                                    0x05,      // bLength
-                                    BOS as u8, // bDescriptorType: BOS
-                                    0x05, 0x00, // wTotalLength
-                                    0x00, // bNumCapabilities
-                                ];
+                                let mut desc = match &self.raw_bos {
+                                    Some(raw) => raw.clone(),
+                                    None => bos_descriptor(&self.all_bos_capabilities()),
+                                };
 
                                 // requested len too short: wLength < real length
                                 if setup_packet.length < desc.len() as u16 {
@@ -286,51 +677,7 @@ impl UsbDevice {
                             }
                             Some(Configuration) => {
                                 debug!("Get configuration descriptor");
-                                let mut desc = vec![
-                                    0x09,                // bLength
-                                    Configuration as u8, // bDescriptorType: Configuration
-                                    0x00,
-                                    0x00, // wTotalLength: to be filled below
-                                    self.interfaces.len() as u8, // bNumInterfaces
-                                    self.configuration_value, // bConfigurationValue
-                                    self.string_configuration, // iConfiguration
-                                    0x80, // bmAttributes Bus Powered
-                                    0x32, // bMaxPower 100mA
-                                ];
-                                for (i, intf) in self.interfaces.iter().enumerate() {
-                                    let mut intf_desc = vec![
-                                        0x09,                       // bLength
-                                        Interface as u8,            // bDescriptorType: Interface
-                                        i as u8,                    // bInterfaceNum
-                                        0x00,                       // bAlternateSettings
-                                        intf.endpoints.len() as u8, // bNumEndpoints
-                                        intf.interface_class,       // bInterfaceClass
-                                        intf.interface_subclass,    // bInterfaceSubClass
-                                        intf.interface_protocol,    // bInterfaceProtocol
-                                        intf.string_interface,      //iInterface
-                                    ];
-                                    // class specific endpoint
-                                    let mut specific = intf.class_specific_descriptor.clone();
-                                    intf_desc.append(&mut specific);
-                                    // endpoint descriptors
-                                    for endpoint in &intf.endpoints {
-                                        let mut ep_desc = vec![
-                                            0x07,                // bLength
-                                            Endpoint as u8,      // bDescriptorType: Endpoint
-                                            endpoint.address,    // bEndpointAddress
-                                            endpoint.attributes, // bmAttributes
-                                            endpoint.max_packet_size as u8,
-                                            (endpoint.max_packet_size >> 8) as u8, // wMaxPacketSize
-                                            endpoint.interval,                     // bInterval
-                                        ];
-                                        intf_desc.append(&mut ep_desc);
-                                    }
-                                    desc.append(&mut intf_desc);
-                                }
-                                // length
-                                let len = desc.len() as u16;
-                                desc[2] = len as u8;
-                                desc[3] = (len >> 8) as u8;
+                                let mut desc = self.configuration_descriptor(Configuration);
 
                                 // requested len too short: wLength < real length
                                 if setup_packet.length < desc.len() as u16 {
@@ -343,20 +690,35 @@ impl UsbDevice {
                                 let index = setup_packet.value as u8;
                                 if index == 0 {
                                     // language ids
+                                    let languages = self.languages();
                                     let mut desc = vec![
-                                        4,                            // bLength
-                                        DescriptorType::String as u8, // bDescriptorType
-                                        0x09,
-                                        0x04, // bLANGID, en-US
+                                        (2 + languages.len() * 2) as u8, // bLength
+                                        DescriptorType::String as u8,    // bDescriptorType
                                     ];
+                                    for language in languages {
+                                        desc.extend_from_slice(&language.to_le_bytes());
+                                        // wLANGID
+                                    }
                                     // requested len too short: wLength < real length
                                     if setup_packet.length < desc.len() as u16 {
                                         desc.resize(setup_packet.length as usize, 0);
                                     }
                                     Ok(desc)
-                                } else {
-                                    let s = &self.string_pool[&index];
-                                    let bytes: Vec<u16> = s.encode_utf16().collect();
+                                } else if let Some(ms_os_20) = self
+                                    .ms_os_20
+                                    .as_ref()
+                                    .filter(|m| m.legacy_ms_os_10)
+                                    .filter(|_| index == msos::MS_OS_10_STRING_INDEX)
+                                {
+                                    let mut desc = ms_os_20.ms_os_10_string_descriptor();
+                                    // requested len too short: wLength < real length
+                                    if setup_packet.length < desc.len() as u16 {
+                                        desc.resize(setup_packet.length as usize, 0);
+                                    }
+                                    Ok(desc)
+                                } else if let Some(s) = self.get_string(index, setup_packet.index) {
+                                    // at most 126 UTF-16 code units fit in bLength
+                                    let bytes: Vec<u16> = s.encode_utf16().take(126).collect();
                                     let mut desc = vec![
                                         (2 + bytes.len() * 2) as u8,  // bLength
                                         DescriptorType::String as u8, // bDescriptorType
@@ -371,21 +733,29 @@ impl UsbDevice {
                                         desc.resize(setup_packet.length as usize, 0);
                                     }
                                     Ok(desc)
+                                } else {
+                                    warn!("String descriptor {} not found", index);
+                                    Err(stall())
                                 }
                             }
                             Some(DeviceQualifier) => {
                                 debug!("Get device qualifier descriptor");
+                                if !self.is_dual_speed() {
+                                    debug!("Device does not support dual speed");
+                                    return Err(stall());
+                                }
+                                let ep0_params = self.ep0_in.other_speed_params(self.usb_speed());
                                 let mut desc = vec![
                                     0x0A,                  // bLength
                                     DeviceQualifier as u8, // bDescriptorType: Device Qualifier
-                                    self.usb_version.minor,
-                                    self.usb_version.major,
-                                    self.device_class,    // bDeviceClass
-                                    self.device_subclass, // bDeviceSUbClass
-                                    self.device_protocol, // bDeviceProtocol
-                                    self.ep0_in.max_packet_size as u8, // bMaxPacketSize0
-                                    self.num_configurations, // bNumConfigurations
-                                    0x00,                 // reserved
+                                    self.usb_version.to_bcd() as u8,
+                                    (self.usb_version.to_bcd() >> 8) as u8, // bcdUSB
+                                    self.device_class,                      // bDeviceClass
+                                    self.device_subclass,                   // bDeviceSUbClass
+                                    self.device_protocol,                   // bDeviceProtocol
+                                    ep0_params.max_packet_size as u8,       // bMaxPacketSize0
+                                    self.num_configurations,                // bNumConfigurations
+                                    0x00,                                   // reserved
                                 ];
 
                                 // requested len too short: wLength < real length
@@ -394,26 +764,58 @@ impl UsbDevice {
                                 }
                                 Ok(desc)
                             }
+                            Some(OtherSpeedConfiguration) => {
+                                debug!("Get other speed configuration descriptor");
+                                if !self.is_dual_speed() {
+                                    debug!("Device does not support dual speed");
+                                    return Err(stall());
+                                }
+                                let mut desc =
+                                    self.configuration_descriptor(OtherSpeedConfiguration);
+
+                                // requested len too short: wLength < real length
+                                if setup_packet.length < desc.len() as u16 {
+                                    desc.resize(setup_packet.length as usize, 0);
+                                }
+                                Ok(desc)
+                            }
                             _ => {
                                 warn!("unknown desc type: {:x?}", setup_packet);
                                 Ok(vec![])
                             }
                         }
                     }
+                    (0b10000000, Some(GetStatus)) => {
+                        debug!("Get device status");
+                        let mut status = 0u8;
+                        if self.self_powered {
+                            status |= 0x01; // Self Powered
+                        }
+                        if self.remote_wakeup_state.is_enabled() {
+                            status |= 0x02; // Remote Wakeup
+                        }
+                        let mut desc = vec![status, 0x00];
+
+                        // requested len too short: wLength < real length
+                        if setup_packet.length < desc.len() as u16 {
+                            desc.resize(setup_packet.length as usize, 0);
+                        }
+                        Ok(desc)
+                    }
                     _ if setup_packet.request_type & 0xF == 1 => {
                         // to interface
                         // see https://www.beyondlogic.org/usbnutshell/usb6.shtml
                         // only low 8 bits are valid
                         let intf = &self.interfaces[setup_packet.index as usize & 0xFF];
-                        let mut handler = intf.handler.lock().unwrap();
-                        handler.handle_urb(intf, ep, transfer_buffer_length, setup_packet, out_data)
+                        intf.handle_urb(ep, transfer_buffer_length, setup_packet, out_data)
+                            .await
                     }
                     _ if setup_packet.request_type & 0xF == 0 && self.device_handler.is_some() => {
                         // to device
                         // see https://www.beyondlogic.org/usbnutshell/usb6.shtml
                         let lock = self.device_handler.as_ref().unwrap();
-                        let mut handler = lock.lock().unwrap();
-                        handler.handle_urb(transfer_buffer_length, setup_packet, out_data)
+                        device_handler_urb(lock, transfer_buffer_length, setup_packet, out_data)
+                            .await
                     }
                     _ => unimplemented!("control in"),
                 }
@@ -426,6 +828,10 @@ impl UsbDevice {
                     FromPrimitive::from_u8(setup_packet.request),
                 ) {
                     (0b00000000, Some(SetConfiguration)) => {
+                        if let Some(lock) = self.device_handler.as_ref() {
+                            let mut handler = lock.lock().unwrap();
+                            handler.set_configuration(setup_packet.value as u8)?;
+                        }
                         let mut desc = vec![
                             self.configuration_value, // bConfigurationValue
                         ];
@@ -436,20 +842,70 @@ impl UsbDevice {
                         }
                         Ok(desc)
                     }
+                    (0b00000000, Some(request @ (SetFeature | ClearFeature)))
+                        if setup_packet.value == FeatureSelector::DeviceRemoteWakeup as u16 =>
+                    {
+                        let enable = matches!(request, SetFeature);
+                        if !self.remote_wakeup {
+                            warn!("Remote wakeup is not supported by this device");
+                            return Err(stall());
+                        }
+                        debug!("Set remote wakeup enabled={}", enable);
+                        self.remote_wakeup_state.set_enabled(enable);
+
+                        // let the real device know as well
+                        if let Some(lock) = self.device_handler.as_ref() {
+                            device_handler_urb(lock, transfer_buffer_length, setup_packet, out_data)
+                                .await
+                        } else {
+                            Ok(vec![])
+                        }
+                    }
+                    (0b00000001, Some(SetInterface)) if self.device_handler.is_some() => {
+                        // like usbip-host, the host API keeps its own state in sync
+                        debug!(
+                            "Set interface {} to alternate setting {}",
+                            setup_packet.index, setup_packet.value
+                        );
+                        let mut handler = self.device_handler.as_ref().unwrap().lock().unwrap();
+                        handler
+                            .set_interface(setup_packet.index as u8, setup_packet.value as u8)?;
+                        Ok(vec![])
+                    }
+                    (0b00000010, Some(ClearFeature))
+                        if setup_packet.value == FeatureSelector::EndpointHalt as u16 =>
+                    {
+                        debug!("Clear halt of endpoint {:02x}", setup_packet.index);
+                        if let Some(lock) = self.device_handler.as_ref() {
+                            let mut handler = lock.lock().unwrap();
+                            handler.clear_halt(setup_packet.index as u8)?;
+                        }
+                        Ok(vec![])
+                    }
+                    (REQUEST_TYPE_PORT, Some(SetFeature))
+                        if setup_packet.value == PORT_FEATURE_RESET =>
+                    {
+                        debug!("Reset device");
+                        if let Some(lock) = self.device_handler.as_ref() {
+                            let mut handler = lock.lock().unwrap();
+                            handler.reset()?;
+                        }
+                        Ok(vec![])
+                    }
                     _ if setup_packet.request_type & 0xF == 1 => {
                         // to interface
                         // see https://www.beyondlogic.org/usbnutshell/usb6.shtml
                         // only low 8 bits are valid
                         let intf = &self.interfaces[setup_packet.index as usize & 0xFF];
-                        let mut handler = intf.handler.lock().unwrap();
-                        handler.handle_urb(intf, ep, transfer_buffer_length, setup_packet, out_data)
+                        intf.handle_urb(ep, transfer_buffer_length, setup_packet, out_data)
+                            .await
                     }
                     _ if setup_packet.request_type & 0xF == 0 && self.device_handler.is_some() => {
                         // to device
                         // see https://www.beyondlogic.org/usbnutshell/usb6.shtml
                         let lock = self.device_handler.as_ref().unwrap();
-                        let mut handler = lock.lock().unwrap();
-                        handler.handle_urb(transfer_buffer_length, setup_packet, out_data)
+                        device_handler_urb(lock, transfer_buffer_length, setup_packet, out_data)
+                            .await
                     }
                     _ => unimplemented!("control out"),
                 }
@@ -457,14 +913,88 @@ impl UsbDevice {
             (Some(_), _) => {
                 // others
                 let intf = intf.unwrap();
-                let mut handler = intf.handler.lock().unwrap();
-                handler.handle_urb(intf, ep, transfer_buffer_length, setup_packet, out_data)
+                intf.handle_urb(ep, transfer_buffer_length, setup_packet, out_data)
+                    .await
             }
             _ => unimplemented!("transfer to {:?}", ep),
         }
     }
 }
 
+/// Pass a URB to the device handler, waiting for the transfer without holding its lock
+async fn device_handler_urb(
+    lock: &Mutex<Box<dyn UsbDeviceHandler + Send>>,
+    transfer_buffer_length: u32,
+    setup: SetupPacket,
+    req: &[u8],
+) -> Result<Vec<u8>> {
+    let pending = {
+        let mut handler = lock.lock().unwrap();
+        match handler.submit_urb(transfer_buffer_length, setup, req) {
+            Some(pending) => pending,
+            None => return handler.handle_urb(transfer_buffer_length, setup, req),
+        }
+    };
+    pending.await
+}
+
+/// Remote wakeup state shared between a [UsbDevice] and its handlers
+///
+/// The host arms remote wakeup with SET_FEATURE(DEVICE_REMOTE_WAKEUP) before suspending the device.
+/// Handlers keep a clone of this handle and call [RemoteWakeup::signal] when an event should wake the host.
+///
+/// USB/IP cannot carry resume signaling: the client never sees the signal itself. What reaches it is the
+/// event, so handlers deliver it by completing their pending interrupt IN URB as well, e.g. with the key
+/// report of a simulated keyboard, which makes the client resume the device if it suspended it.
+#[derive(Clone, Debug, Default)]
+pub struct RemoteWakeup {
+    inner: Arc<RemoteWakeupState>,
+}
+
+#[derive(Debug, Default)]
+struct RemoteWakeupState {
+    enabled: AtomicBool,
+    pending: AtomicBool,
+}
+
+impl RemoteWakeup {
+    /// Whether the host has enabled remote wakeup
+    pub fn is_enabled(&self) -> bool {
+        self.inner.enabled.load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn set_enabled(&self, enabled: bool) {
+        self.inner.enabled.store(enabled, Ordering::SeqCst);
+        if !enabled {
+            self.inner.pending.store(false, Ordering::SeqCst);
+        }
+    }
+
+    /// Record a remote wakeup, until the host sends a request again
+    ///
+    /// Returns `false` if the host has not enabled remote wakeup, in which case the signal is dropped.
+    /// The wakeup is not sent to the client, see [RemoteWakeup].
+    pub fn signal(&self) -> bool {
+        if self.is_enabled() {
+            info!("Signal remote wakeup");
+            self.inner.pending.store(true, Ordering::SeqCst);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Whether a remote wakeup has been signaled and not yet answered by the host
+    pub fn is_pending(&self) -> bool {
+        self.inner.pending.load(Ordering::SeqCst)
+    }
+
+    /// Clear the pending remote wakeup, returning whether one was pending
+    pub(crate) fn take_pending(&self) -> bool {
+        self.inner.pending.swap(false, Ordering::SeqCst)
+    }
+}
+
 /// A handler for URB targeting the device
 pub trait UsbDeviceHandler {
     /// Handle a URB(USB Request Block) targeting at this device
@@ -478,6 +1008,53 @@ pub trait UsbDeviceHandler {
         req: &[u8],
     ) -> Result<Vec<u8>>;
 
+    /// Prepare the device for a client importing it, e.g. claim interfaces of a real device
+    ///
+    /// The import is rejected when this fails.
+    fn attach(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Give the device back after the client released it, e.g. reattach host drivers of a real device
+    fn detach(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Select a configuration, requested by SET_CONFIGURATION
+    ///
+    /// Handlers backed by real hardware should use the host API instead of forwarding the raw request.
+    fn set_configuration(&mut self, _configuration: u8) -> Result<()> {
+        Ok(())
+    }
+
+    /// Select an alternate setting of an interface, requested by SET_INTERFACE
+    fn set_interface(&mut self, _interface: u8, _alternate_setting: u8) -> Result<()> {
+        Ok(())
+    }
+
+    /// Clear the halt condition of an endpoint, requested by CLEAR_FEATURE(ENDPOINT_HALT)
+    fn clear_halt(&mut self, _endpoint: u8) -> Result<()> {
+        Ok(())
+    }
+
+    /// Reset the device, requested by SET_FEATURE(PORT_RESET) to its port
+    fn reset(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Submit a URB(USB Request Block) targeting at this device without waiting for its completion
+    ///
+    /// Handlers backed by real hardware should implement this, so that other URBs are not blocked meanwhile.
+    /// When `None` is returned, [UsbDeviceHandler::handle_urb] is called instead.
+    fn submit_urb(
+        &mut self,
+        _transfer_buffer_length: u32,
+        _setup: SetupPacket,
+        _req: &[u8],
+    ) -> Option<PendingTransfer> {
+        None
+    }
+
     /// Helper to downcast to actual struct
     ///
     /// Please implement it as:
@@ -514,4 +1091,459 @@ mod test {
         assert_eq!(device.string_pool[&3], "test");
         assert_eq!(device.string_pool[&4], "test");
     }
+
+    #[tokio::test]
+    async fn test_configuration_attributes() {
+        setup_test_logger();
+        let device = UsbDevice::new(0)
+            .with_max_power(500)
+            .with_self_powered(true)
+            .with_remote_wakeup(true);
+
+        let desc = device
+            .handle_urb(
+                device.ep0_in,
+                None,
+                0,
+                SetupPacket::parse(&[0x80, 0x06, 0x00, 0x02, 0x00, 0x00, 0x09, 0x00]),
+                &[],
+            )
+            .await
+            .unwrap();
+        assert_eq!(desc[7], 0xE0); // bmAttributes
+        assert_eq!(desc[8], 250); // bMaxPower
+
+        // GET_STATUS
+        let get_status = SetupPacket::parse(&[0x80, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x00]);
+        let status = device
+            .handle_urb(device.ep0_in, None, 2, get_status, &[])
+            .await
+            .unwrap();
+        assert_eq!(status, [0x01, 0x00]);
+
+        // SET_FEATURE(DEVICE_REMOTE_WAKEUP)
+        let handle = device.remote_wakeup_handle();
+        assert!(!handle.signal());
+        device
+            .handle_urb(
+                device.ep0_out,
+                None,
+                0,
+                SetupPacket::parse(&[0x00, 0x03, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00]),
+                &[],
+            )
+            .await
+            .unwrap();
+        let status = device
+            .handle_urb(device.ep0_in, None, 2, get_status, &[])
+            .await
+            .unwrap();
+        assert_eq!(status, [0x03, 0x00]);
+        assert!(handle.signal());
+        assert!(handle.is_pending());
+
+        // stalled by devices without remote wakeup
+        let device = UsbDevice::new(0);
+        let err = device
+            .handle_urb(
+                device.ep0_out,
+                None,
+                0,
+                SetupPacket::parse(&[0x00, 0x03, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00]),
+                &[],
+            )
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::BrokenPipe);
+        assert!(!device.remote_wakeup_handle().is_enabled());
+    }
+
+    #[tokio::test]
+    async fn test_superspeed_descriptors() {
+        setup_test_logger();
+        let device = UsbDevice::new(0)
+            .with_speed(UsbSpeed::Super)
+            .with_interface(
+                crate::ClassCode::CDC as u8,
+                crate::cdc::CDC_ACM_SUBCLASS,
+                0x00,
+                "Test CDC ACM",
+                crate::cdc::UsbCdcAcmHandler::endpoints()
+                    .into_iter()
+                    .map(|mut ep| {
+                        if ep.attributes == EndpointAttributes::Bulk as u8 {
+                            ep.max_packet_size = 1024;
+                        }
+                        ep
+                    })
+                    .collect(),
+                Arc::new(Mutex::new(Box::new(crate::cdc::UsbCdcAcmHandler::new())
+                    as Box<dyn UsbInterfaceHandler + Send>)),
+            );
+
+        let desc = device
+            .handle_urb(
+                device.ep0_in,
+                None,
+                0x12,
+                SetupPacket::parse(&[0x80, 0x06, 0x00, 0x01, 0x00, 0x00, 0x12, 0x00]),
+                &[],
+            )
+            .await
+            .unwrap();
+        assert_eq!(desc[2..4], [0x00, 0x03]); // bcdUSB
+        assert_eq!(desc[7], 9); // bMaxPacketSize0
+
+        let desc = device
+            .handle_urb(
+                device.ep0_in,
+                None,
+                0xFF,
+                SetupPacket::parse(&[0x80, 0x06, 0x00, 0x02, 0x00, 0x00, 0xFF, 0x00]),
+                &[],
+            )
+            .await
+            .unwrap();
+        verify_descriptor(&desc);
+        assert_eq!(desc[8], 13); // bMaxPower in 8mA units
+
+        // every endpoint is followed by its companion
+        let mut offset = 0;
+        let mut endpoints = 0;
+        while offset < desc.len() {
+            if desc[offset + 1] == DescriptorType::Endpoint as u8 {
+                let next = offset + desc[offset] as usize;
+                assert_eq!(
+                    desc[next + 1],
+                    DescriptorType::SuperSpeedEndpointCompanion as u8
+                );
+                if desc[offset + 3] == EndpointAttributes::Bulk as u8 {
+                    assert_eq!(desc[offset + 4..offset + 6], [0x00, 0x04]); // wMaxPacketSize
+                }
+                endpoints += 1;
+            }
+            offset += desc[offset] as usize;
+        }
+        assert_eq!(endpoints, 3);
+
+        // BOS gets the SuperSpeed capabilities automatically
+        let bos = device
+            .handle_urb(
+                device.ep0_in,
+                None,
+                0xFF,
+                SetupPacket::parse(&[0x80, 0x06, 0x00, 0x0F, 0x00, 0x00, 0xFF, 0x00]),
+                &[],
+            )
+            .await
+            .unwrap();
+        verify_descriptor(&bos);
+        assert_eq!(bos[4], 2); // bNumDeviceCaps
+    }
+
+    #[tokio::test]
+    async fn test_other_speed_descriptors() {
+        setup_test_logger();
+        let device = UsbDevice::new(0).with_interface(
+            crate::ClassCode::CDC as u8,
+            crate::cdc::CDC_ACM_SUBCLASS,
+            0x00,
+            "Test CDC ACM",
+            crate::cdc::UsbCdcAcmHandler::endpoints(),
+            Arc::new(Mutex::new(Box::new(crate::cdc::UsbCdcAcmHandler::new())
+                as Box<dyn UsbInterfaceHandler + Send>)),
+        );
+        let get_qualifier = SetupPacket::parse(&[0x80, 0x06, 0x00, 0x06, 0x00, 0x00, 0x0A, 0x00]);
+        let get_other_speed = SetupPacket::parse(&[0x80, 0x06, 0x00, 0x07, 0x00, 0x00, 0xFF, 0x00]);
+
+        let desc = device
+            .handle_urb(device.ep0_in, None, 0x0A, get_qualifier, &[])
+            .await
+            .unwrap();
+        assert_eq!(desc.len(), 0x0A);
+        assert_eq!(desc[1], DescriptorType::DeviceQualifier as u8);
+
+        let desc = device
+            .handle_urb(device.ep0_in, None, 0xFF, get_other_speed, &[])
+            .await
+            .unwrap();
+        verify_descriptor(&desc);
+        assert_eq!(desc[1], DescriptorType::OtherSpeedConfiguration as u8);
+        let mut offset = 0;
+        while offset < desc.len() {
+            if desc[offset + 1] == DescriptorType::Endpoint as u8 {
+                let max_packet_size = u16::from_le_bytes([desc[offset + 4], desc[offset + 5]]);
+                match desc[offset + 3] {
+                    // full speed bulk endpoints are limited to 64 bytes
+                    attributes if attributes == EndpointAttributes::Bulk as u8 => {
+                        assert_eq!(max_packet_size, 64)
+                    }
+                    // 2^(10-1) microframes at high speed are 64 frames at full speed
+                    _ => assert_eq!(desc[offset + 6], 64),
+                }
+            }
+            offset += desc[offset] as usize;
+        }
+
+        // full speed only devices stall
+        let device = device
+            .with_speed(UsbSpeed::Full)
+            .with_supported_speeds(&[UsbSpeed::Full]);
+        for setup_packet in [get_qualifier, get_other_speed] {
+            let err = device
+                .handle_urb(device.ep0_in, None, 0xFF, setup_packet, &[])
+                .await
+                .unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::BrokenPipe);
+        }
+    }
+
+    /// A device handler recording the special requests it gets
+    #[derive(Default)]
+    struct SpecialRequestHandler {
+        requests: Vec<String>,
+    }
+
+    impl UsbDeviceHandler for SpecialRequestHandler {
+        fn handle_urb(
+            &mut self,
+            _transfer_buffer_length: u32,
+            setup: SetupPacket,
+            _req: &[u8],
+        ) -> Result<Vec<u8>> {
+            panic!(
+                "special request forwarded as a raw control transfer: {:?}",
+                setup
+            )
+        }
+
+        fn set_configuration(&mut self, configuration: u8) -> Result<()> {
+            self.requests
+                .push(format!("set_configuration {}", configuration));
+            Ok(())
+        }
+
+        fn set_interface(&mut self, interface: u8, alternate_setting: u8) -> Result<()> {
+            self.requests
+                .push(format!("set_interface {} {}", interface, alternate_setting));
+            Ok(())
+        }
+
+        fn clear_halt(&mut self, endpoint: u8) -> Result<()> {
+            self.requests.push(format!("clear_halt {:02x}", endpoint));
+            Ok(())
+        }
+
+        fn reset(&mut self) -> Result<()> {
+            self.requests.push("reset".to_string());
+            Ok(())
+        }
+
+        fn as_any(&mut self) -> &mut dyn Any {
+            self
+        }
+    }
+
+    #[tokio::test]
+    async fn test_special_requests() {
+        setup_test_logger();
+        let special_requests = [
+            // SET_CONFIGURATION
+            [0x00, 0x09, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00],
+            // SET_INTERFACE
+            [0x01, 0x0B, 0x01, 0x00, 0x02, 0x00, 0x00, 0x00],
+            // CLEAR_FEATURE(ENDPOINT_HALT)
+            [0x02, 0x01, 0x00, 0x00, 0x81, 0x00, 0x00, 0x00],
+            // SET_PORT_FEATURE(PORT_RESET)
+            [0x23, 0x03, 0x04, 0x00, 0x01, 0x00, 0x00, 0x00],
+        ];
+
+        let mut device = UsbDevice::new(0);
+        for setup in special_requests {
+            // skip SET_INTERFACE, there is no interface to pass it to
+            if setup[1] != 0x0B {
+                device
+                    .handle_urb(device.ep0_out, None, 0, SetupPacket::parse(&setup), &[])
+                    .await
+                    .unwrap();
+            }
+        }
+
+        let handler = Arc::new(Mutex::new(
+            Box::<SpecialRequestHandler>::default() as Box<dyn UsbDeviceHandler + Send>
+        ));
+        device.device_handler = Some(handler.clone());
+        for setup in special_requests {
+            device
+                .handle_urb(device.ep0_out, None, 0, SetupPacket::parse(&setup), &[])
+                .await
+                .unwrap();
+        }
+        let mut handler = handler.lock().unwrap();
+        let handler = handler
+            .as_any()
+            .downcast_mut::<SpecialRequestHandler>()
+            .unwrap();
+        assert_eq!(
+            handler.requests,
+            [
+                "set_configuration 1",
+                "set_interface 2 1",
+                "clear_halt 81",
+                "reset"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_bos_descriptor() {
+        setup_test_logger();
+        let device = UsbDevice::new(0)
+            .with_bos_capability(BosCapability::usb20_lpm())
+            .with_bos_capability(BosCapability::superspeed());
+        let get_bos = |length: u16| {
+            SetupPacket::parse(&[
+                0x80,
+                0x06,
+                0x00,
+                0x0F,
+                0x00,
+                0x00,
+                length as u8,
+                (length >> 8) as u8,
+            ])
+        };
+
+        // hosts read the header first to learn wTotalLength
+        let header = device
+            .handle_urb(device.ep0_in, None, 5, get_bos(5), &[])
+            .await
+            .unwrap();
+        assert_eq!(header, [0x05, 0x0F, 0x16, 0x00, 0x02]);
+
+        let desc = device
+            .handle_urb(device.ep0_in, None, 0xFF, get_bos(0xFF), &[])
+            .await
+            .unwrap();
+        assert_eq!(desc.len(), 0x16);
+        verify_descriptor(&desc);
+    }
+
+    #[tokio::test]
+    async fn test_vendor_descriptors() {
+        setup_test_logger();
+        let device = UsbDevice::new(0)
+            .with_webusb(webusb::WebUsb::new(0x01, "https://example.com"))
+            .with_ms_os_20_descriptors(
+                msos::MsOs20DescriptorSet::winusb(0x02).with_legacy_ms_os_10(),
+            );
+        assert_eq!(device.usb_version.to_bcd(), 0x0210);
+
+        let bos = device
+            .handle_urb(
+                device.ep0_in,
+                None,
+                0xFF,
+                SetupPacket::parse(&[0x80, 0x06, 0x00, 0x0F, 0x00, 0x00, 0xFF, 0x00]),
+                &[],
+            )
+            .await
+            .unwrap();
+        assert_eq!(bos[4], 2);
+        assert_eq!(bos.len(), 5 + 24 + 28);
+
+        // WebUSB GET_URL
+        let url = device
+            .handle_urb(
+                device.ep0_in,
+                None,
+                0xFF,
+                SetupPacket::parse(&[0xC0, 0x01, 0x01, 0x00, 0x02, 0x00, 0xFF, 0x00]),
+                &[],
+            )
+            .await
+            .unwrap();
+        assert_eq!(&url[3..], b"example.com");
+
+        // MS OS 2.0 descriptor set
+        let set = device
+            .handle_urb(
+                device.ep0_in,
+                None,
+                0xFF,
+                SetupPacket::parse(&[0xC0, 0x02, 0x00, 0x00, 0x07, 0x00, 0xFF, 0x00]),
+                &[],
+            )
+            .await
+            .unwrap();
+        assert_eq!(set, device.ms_os_20.as_ref().unwrap().to_bytes());
+
+        // MS OS 1.0 string descriptor
+        let string = device
+            .handle_urb(
+                device.ep0_in,
+                None,
+                0xFF,
+                SetupPacket::parse(&[0x80, 0x06, 0xEE, 0x03, 0x00, 0x00, 0xFF, 0x00]),
+                &[],
+            )
+            .await
+            .unwrap();
+        assert_eq!(string[16], 0x02);
+    }
+
+    #[tokio::test]
+    async fn test_localized_strings() {
+        setup_test_logger();
+        const LANGUAGE_ID_DE_DE: u16 = 0x0407;
+        let mut device = UsbDevice::new(0).with_language(0x0411);
+        device.set_string_translation(LANGUAGE_ID_DE_DE, device.product_string_index(), "Produkt");
+        assert_eq!(device.languages(), [LANGUAGE_ID_EN_US, 0x0407, 0x0411]);
+
+        let get_string = |index: u8, language: u16| {
+            SetupPacket::parse(&[
+                0x80,
+                0x06,
+                index,
+                0x03,
+                language as u8,
+                (language >> 8) as u8,
+                0xFF,
+                0x00,
+            ])
+        };
+
+        let desc = device
+            .handle_urb(device.ep0_in, None, 0xFF, get_string(0, 0), &[])
+            .await
+            .unwrap();
+        assert_eq!(desc, [0x08, 0x03, 0x09, 0x04, 0x07, 0x04, 0x11, 0x04]);
+
+        let product = device.product_string_index();
+        let desc = device
+            .handle_urb(
+                device.ep0_in,
+                None,
+                0xFF,
+                get_string(product, LANGUAGE_ID_DE_DE),
+                &[],
+            )
+            .await
+            .unwrap();
+        assert_eq!(desc.len(), 2 + 2 * "Produkt".len());
+
+        // missing translation falls back to the default language
+        let desc = device
+            .handle_urb(device.ep0_in, None, 0xFF, get_string(product, 0x0411), &[])
+            .await
+            .unwrap();
+        assert_eq!(desc.len(), 2 + 2 * "Product".len());
+
+        // unknown index stalls
+        let err = device
+            .handle_urb(device.ep0_in, None, 0xFF, get_string(0x42, 0x0409), &[])
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::BrokenPipe);
+    }
 }
@@ -12,6 +12,62 @@ pub struct UsbEndpoint {
     pub max_packet_size: u16,
     /// bInterval
     pub interval: u8,
+    /// SuperSpeed Endpoint Companion, a default one is generated for SuperSpeed devices if absent
+    pub superspeed_companion: Option<SuperSpeedEndpointCompanion>,
+    /// Parameters at the other speed of a dual-speed device, derived from the current ones if absent
+    pub other_speed: Option<UsbEndpointSpeedParams>,
+}
+
+/// Speed dependent parameters of an endpoint
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct UsbEndpointSpeedParams {
+    /// wMaxPacketSize
+    pub max_packet_size: u16,
+    /// bInterval
+    pub interval: u8,
+}
+
+/// Represent a SuperSpeed Endpoint Companion descriptor
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct SuperSpeedEndpointCompanion {
+    /// bMaxBurst: number of packets in a burst minus one
+    pub max_burst: u8,
+    /// bmAttributes: MaxStreams for bulk, Mult for isochronous endpoints
+    pub attributes: u8,
+    /// wBytesPerInterval: bytes transferred per service interval of periodic endpoints
+    pub bytes_per_interval: u16,
+}
+
+impl SuperSpeedEndpointCompanion {
+    /// Find a companion descriptor in the extra descriptors following an endpoint descriptor
+    pub fn find(extra: &[u8]) -> Option<Self> {
+        let mut offset = 0;
+        while offset + 2 <= extra.len() && extra[offset] >= 2 {
+            let desc = &extra[offset..(offset + extra[offset] as usize).min(extra.len())];
+            if desc[1] == DescriptorType::SuperSpeedEndpointCompanion as u8 && desc.len() >= 6 {
+                return Some(Self {
+                    max_burst: desc[2],
+                    attributes: desc[3],
+                    bytes_per_interval: u16::from_le_bytes([desc[4], desc[5]]),
+                });
+            }
+            offset += desc.len();
+        }
+        None
+    }
+
+    pub fn to_bytes(self) -> Vec<u8> {
+        vec![
+            0x06,                                              // bLength
+            DescriptorType::SuperSpeedEndpointCompanion as u8, // bDescriptorType
+            self.max_burst,                                    // bMaxBurst
+            self.attributes,                                   // bmAttributes
+            self.bytes_per_interval as u8,
+            (self.bytes_per_interval >> 8) as u8, // wBytesPerInterval
+        ]
+    }
 }
 
 impl UsbEndpoint {
@@ -28,4 +84,84 @@ impl UsbEndpoint {
     pub fn is_ep0(&self) -> bool {
         self.address & 0x7F == 0
     }
+
+    /// Parameters of this endpoint when a dual-speed device operating at `speed` switches to the other one
+    pub fn other_speed_params(&self, speed: UsbSpeed) -> UsbEndpointSpeedParams {
+        if let Some(params) = self.other_speed {
+            return params;
+        }
+        let to_high_speed = speed != UsbSpeed::High;
+        let (max_packet_size, interval) = match FromPrimitive::from_u8(self.attributes & 0x3) {
+            Some(EndpointAttributes::Control) => (EP0_MAX_PACKET_SIZE, 0),
+            Some(EndpointAttributes::Bulk) if to_high_speed => (512, 0),
+            Some(EndpointAttributes::Bulk) => (self.max_packet_size.min(64), 0),
+            Some(EndpointAttributes::Interrupt) if to_high_speed => {
+                // bInterval in frames becomes 2^(bInterval-1) microframes
+                let microframes = self.interval.max(1) as u32 * 8;
+                let exponent = (u32::BITS - microframes.leading_zeros()).min(16);
+                (self.max_packet_size.min(64), exponent as u8)
+            }
+            Some(EndpointAttributes::Interrupt) => {
+                let microframes = 1u32 << (self.interval.clamp(1, 16) - 1);
+                let frames = (microframes / 8).clamp(1, 255);
+                ((self.max_packet_size & 0x7FF).min(64), frames as u8)
+            }
+            // isochronous bInterval is an exponent at both speeds
+            _ if to_high_speed => (
+                self.max_packet_size.min(1024),
+                (self.interval.max(1) + 3).min(16),
+            ),
+            _ => (
+                (self.max_packet_size & 0x7FF).min(1023),
+                self.interval.saturating_sub(3).max(1),
+            ),
+        };
+        UsbEndpointSpeedParams {
+            max_packet_size,
+            interval,
+        }
+    }
+
+    /// Generate the endpoint descriptor for the other speed of a dual-speed device operating at `speed`
+    pub(crate) fn other_speed_descriptor(&self, speed: UsbSpeed) -> Vec<u8> {
+        let params = self.other_speed_params(speed);
+        vec![
+            0x07,                           // bLength
+            DescriptorType::Endpoint as u8, // bDescriptorType: Endpoint
+            self.address,                   // bEndpointAddress
+            self.attributes,                // bmAttributes
+            params.max_packet_size as u8,
+            (params.max_packet_size >> 8) as u8, // wMaxPacketSize
+            params.interval,                     // bInterval
+        ]
+    }
+
+    /// Generate the endpoint descriptor, followed by a companion descriptor for SuperSpeed
+    pub(crate) fn descriptor(&self, superspeed: bool) -> Vec<u8> {
+        let transfer_type = FromPrimitive::from_u8(self.attributes & 0x3);
+        let max_packet_size = self.max_packet_size;
+        let mut desc = vec![
+            0x07,                           // bLength
+            DescriptorType::Endpoint as u8, // bDescriptorType: Endpoint
+            self.address,                   // bEndpointAddress
+            self.attributes,                // bmAttributes
+            max_packet_size as u8,
+            (max_packet_size >> 8) as u8, // wMaxPacketSize
+            self.interval,                // bInterval
+        ];
+        if superspeed {
+            let companion = self.superspeed_companion.unwrap_or_else(|| {
+                let periodic = matches!(
+                    transfer_type,
+                    Some(EndpointAttributes::Interrupt | EndpointAttributes::Isochronous)
+                );
+                SuperSpeedEndpointCompanion {
+                    bytes_per_interval: if periodic { max_packet_size } else { 0 },
+                    ..Default::default()
+                }
+            });
+            desc.extend(companion.to_bytes());
+        }
+        desc
+    }
 }
@@ -0,0 +1,124 @@
+//! Errors of the server and of the USB/IP protocol
+use std::fmt;
+use std::io::ErrorKind;
+
+/// An error of this crate
+///
+/// Converts to and from [std::io::Error]: an [Error] converted to an [std::io::Error] is recovered when converted
+/// back, so it can be matched on after passing through APIs returning [std::io::Result].
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error {
+    /// The peer speaks an unsupported version of the USB/IP protocol
+    UnsupportedVersion(u16),
+    /// An operation or command unknown to the USB/IP protocol
+    UnknownCommand(u32),
+    /// A request larger than allowed, e.g. a transfer buffer length
+    LimitExceeded {
+        what: &'static str,
+        value: u64,
+        limit: u64,
+    },
+    /// The device is imported by a client
+    DeviceBusy(String),
+    /// No device is exported under the bus id
+    DeviceNotFound(String),
+    /// The device is not imported by any client
+    DeviceNotAttached(String),
+    /// Another device is exported under the bus id
+    DuplicateBusId(String),
+    /// A device or interface handler failed
+    Handler(std::io::Error),
+    /// Reading from or writing to a connection failed
+    Io(std::io::Error),
+}
+
+impl Error {
+    /// The closest [ErrorKind], as given to the [std::io::Error] converted from this error
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            Self::UnsupportedVersion(_) | Self::UnknownCommand(_) | Self::LimitExceeded { .. } => {
+                ErrorKind::InvalidData
+            }
+            Self::DeviceBusy(_) => ErrorKind::ResourceBusy,
+            Self::DeviceNotFound(_) | Self::DeviceNotAttached(_) => ErrorKind::NotFound,
+            Self::DuplicateBusId(_) => ErrorKind::AlreadyExists,
+            Self::Handler(err) | Self::Io(err) => err.kind(),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnsupportedVersion(version) => write!(f, "Unknown version: {:#04X}", version),
+            Self::UnknownCommand(command) => write!(f, "Unknown command: {:#04X}", command),
+            Self::LimitExceeded { what, value, limit } => {
+                write!(f, "{} of {} exceeds the limit of {}", what, value, limit)
+            }
+            Self::DeviceBusy(bus_id) => write!(f, "Device {} is in use", bus_id),
+            Self::DeviceNotFound(bus_id) => write!(f, "Device {} not found", bus_id),
+            Self::DeviceNotAttached(bus_id) => write!(f, "Device {} is not in use", bus_id),
+            Self::DuplicateBusId(bus_id) => write!(f, "Device {} already exists", bus_id),
+            Self::Handler(err) => write!(f, "Handler failed: {}", err),
+            Self::Io(err) => err.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Handler(err) | Self::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(err: std::io::Error) -> Self {
+        // e.g. OS errors, which have no inner error
+        if err.get_ref().is_none() {
+            return Self::Io(err);
+        }
+        let kind = err.kind();
+        match err.into_inner().map(|inner| inner.downcast::<Error>()) {
+            // an error of this crate, converted to an io::Error before
+            Some(Ok(err)) => *err,
+            Some(Err(inner)) => Self::Io(std::io::Error::new(kind, inner)),
+            None => Self::Io(kind.into()),
+        }
+    }
+}
+
+impl From<Error> for std::io::Error {
+    fn from(err: Error) -> Self {
+        match err {
+            Error::Io(err) => err,
+            err => std::io::Error::new(err.kind(), err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::tests::*;
+
+    #[test]
+    fn converted_to_io_errors_and_back() {
+        setup_test_logger();
+        let err = std::io::Error::from(Error::DeviceBusy("1-1".to_string()));
+        assert_eq!(err.kind(), ErrorKind::ResourceBusy);
+        assert_eq!(err.to_string(), "Device 1-1 is in use");
+        assert!(matches!(Error::from(err), Error::DeviceBusy(bus_id) if bus_id == "1-1"));
+
+        let err = std::io::Error::from(Error::Io(ErrorKind::UnexpectedEof.into()));
+        assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+        assert!(matches!(Error::from(err), Error::Io(_)));
+
+        let err = Error::from(std::io::Error::new(ErrorKind::TimedOut, "slow"));
+        assert!(matches!(&err, Error::Io(err) if err.kind() == ErrorKind::TimedOut));
+        assert_eq!(err.to_string(), "slow");
+    }
+}
@@ -1,19 +1,653 @@
 //! Host USB
 use super::*;
+use std::os::raw::c_void;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Weak;
+use std::time::Duration;
+use tokio::sync::oneshot;
+
+/// Raw libusb transfer, only touched under the lock of [HostTransfer::transfer]
+struct RawTransfer(*mut ffi::libusb_transfer);
+
+// SAFETY: libusb transfers can be cancelled from any thread
+unsafe impl Send for RawTransfer {}
+
+/// State of an asynchronous libusb transfer, owned by the transfer until its callback runs
+struct HostTransfer {
+    buffer: Vec<u8>,
+    control: bool,
+    direction_in: bool,
+    sender: oneshot::Sender<Result<Vec<u8>>>,
+    /// Cleared on completion, so that a late cancellation does not touch a freed transfer
+    transfer: Arc<Mutex<Option<RawTransfer>>>,
+    /// Keep the device open while the transfer is in flight
+    _handle: Box<dyn Any + Send>,
+    /// Keep handling events until the transfer completes
+    _events: Arc<EventThread>,
+}
+
+/// Handles libusb events of a context on a dedicated thread, which completes asynchronous transfers
+///
+/// Shared by the users of the context, the thread is stopped and joined once the last of them drops it.
+pub(crate) struct EventThread {
+    /// Kept alive by the thread, so that no other context gets this address meanwhile
+    context: usize,
+    stop: Arc<AtomicBool>,
+    thread: Option<std::thread::JoinHandle<()>>,
+}
+
+impl EventThread {
+    /// Get the thread handling events of `context`, starting it if needed
+    pub(crate) fn acquire<T: UsbContext + 'static>(context: &T) -> Arc<Self> {
+        static THREADS: Mutex<Vec<Weak<EventThread>>> = Mutex::new(Vec::new());
+        let mut threads = THREADS.lock().unwrap();
+        threads.retain(|thread| thread.strong_count() > 0);
+        let raw = context.as_raw() as usize;
+        if let Some(thread) = threads
+            .iter()
+            .filter_map(Weak::upgrade)
+            .find(|thread| thread.context == raw)
+        {
+            return thread;
+        }
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let context = context.clone();
+            let stop = stop.clone();
+            std::thread::Builder::new()
+                .name("usbip-libusb-events".to_string())
+                .spawn(move || {
+                    while !stop.load(Ordering::SeqCst) {
+                        match context.handle_events(None) {
+                            Ok(()) | Err(rusb::Error::Interrupted) => {}
+                            Err(err) => {
+                                warn!("Failed to handle libusb events: {}", err);
+                                std::thread::sleep(EVENT_RETRY_DELAY);
+                            }
+                        }
+                    }
+                })
+                .expect("spawn libusb event thread")
+        };
+        let thread = Arc::new(Self {
+            context: raw,
+            stop,
+            thread: Some(thread),
+        });
+        threads.push(Arc::downgrade(&thread));
+        thread
+    }
+}
+
+impl Drop for EventThread {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        // SAFETY: the context is alive until the thread ends
+        unsafe { ffi::libusb_interrupt_event_handler(self.context as *mut ffi::libusb_context) };
+        if let Some(thread) = self.thread.take() {
+            // dropped by a transfer callback, the thread ends once it returns
+            if thread.thread().id() != std::thread::current().id() {
+                thread.join().ok();
+            }
+        }
+    }
+}
+
+/// Delay before handling libusb events again after a failure
+const EVENT_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+extern "system" fn transfer_callback(transfer: *mut ffi::libusb_transfer) {
+    // SAFETY: user_data was created by Box::into_raw in submit_transfer and the callback runs once
+    let context = unsafe { Box::from_raw((*transfer).user_data as *mut HostTransfer) };
+    context.transfer.lock().unwrap().take();
+    let (status, actual_length) = unsafe { ((*transfer).status, (*transfer).actual_length) };
+    unsafe { ffi::libusb_free_transfer(transfer) };
+
+    let res = match status {
+        constants::LIBUSB_TRANSFER_COMPLETED => {
+            if context.direction_in {
+                let offset = if context.control { 8 } else { 0 };
+                let end = (offset + actual_length.max(0) as usize).min(context.buffer.len());
+                Ok(context.buffer[offset..end].to_vec())
+            } else {
+                Ok(vec![])
+            }
+        }
+        constants::LIBUSB_TRANSFER_STALL => Err(transfer_error(rusb::Error::Pipe)),
+        constants::LIBUSB_TRANSFER_CANCELLED => Err(transfer_error(rusb::Error::Interrupted)),
+        constants::LIBUSB_TRANSFER_TIMED_OUT => Err(transfer_error(rusb::Error::Timeout)),
+        constants::LIBUSB_TRANSFER_NO_DEVICE => Err(transfer_error(rusb::Error::NoDevice)),
+        constants::LIBUSB_TRANSFER_OVERFLOW => Err(transfer_error(rusb::Error::Overflow)),
+        _ => Err(transfer_error(rusb::Error::Io)),
+    };
+    // the receiver is gone when the URB was unlinked
+    context.sender.send(res).ok();
+}
+
+/// Submit an asynchronous libusb transfer to `ep`, timing out after `timeout` unless it is zero
+///
+/// Returns `None` for isochronous endpoints, which are not supported asynchronously.
+pub(crate) fn submit_transfer<T: UsbContext + 'static>(
+    handle: &Arc<Mutex<DeviceHandle<T>>>,
+    ep: UsbEndpoint,
+    transfer_buffer_length: u32,
+    setup: SetupPacket,
+    req: &[u8],
+    timeout: Duration,
+) -> Option<PendingTransfer> {
+    let transfer_type = match FromPrimitive::from_u8(ep.attributes & 0x3)? {
+        EndpointAttributes::Control => constants::LIBUSB_TRANSFER_TYPE_CONTROL,
+        EndpointAttributes::Bulk => constants::LIBUSB_TRANSFER_TYPE_BULK,
+        EndpointAttributes::Interrupt => constants::LIBUSB_TRANSFER_TYPE_INTERRUPT,
+        EndpointAttributes::Isochronous => return None,
+    };
+    let control = transfer_type == constants::LIBUSB_TRANSFER_TYPE_CONTROL;
+    let (endpoint, direction_in) = if control {
+        (0, setup.request_type & 0x80 != 0)
+    } else {
+        (ep.address, ep.direction() == Direction::In)
+    };
+
+    let mut buffer = vec![];
+    if control {
+        // control transfers start with the setup packet
+        let length = if direction_in {
+            setup.length
+        } else {
+            req.len() as u16
+        };
+        buffer.push(setup.request_type);
+        buffer.push(setup.request);
+        buffer.extend_from_slice(&setup.value.to_le_bytes());
+        buffer.extend_from_slice(&setup.index.to_le_bytes());
+        buffer.extend_from_slice(&length.to_le_bytes());
+        if direction_in {
+            buffer.resize(8 + length as usize, 0);
+        }
+    } else if direction_in {
+        buffer.resize(transfer_buffer_length as usize, 0);
+    }
+    if !direction_in {
+        buffer.extend_from_slice(req);
+    }
+
+    let events = EventThread::acquire(handle.lock().unwrap().context());
+    let (sender, receiver) = oneshot::channel();
+    let state = Arc::new(Mutex::new(None));
+    let mut context = Box::new(HostTransfer {
+        buffer,
+        control,
+        direction_in,
+        sender,
+        transfer: state.clone(),
+        _handle: Box::new(handle.clone()),
+        _events: events,
+    });
+
+    // SAFETY: the buffer and the context outlive the transfer, they are freed by its callback
+    unsafe {
+        let transfer = ffi::libusb_alloc_transfer(0);
+        if transfer.is_null() {
+            return Some(PendingTransfer::new(failed_transfer(transfer_error(
+                rusb::Error::NoMem,
+            ))));
+        }
+        (*transfer).dev_handle = handle.lock().unwrap().as_raw();
+        (*transfer).endpoint = endpoint;
+        (*transfer).transfer_type = transfer_type;
+        (*transfer).timeout = timeout.as_millis().min(u32::MAX as u128) as u32;
+        (*transfer).length = context.buffer.len() as i32;
+        (*transfer).buffer = context.buffer.as_mut_ptr();
+        (*transfer).callback = transfer_callback;
+        (*transfer).num_iso_packets = 0;
+
+        // hold the lock so that a cancellation waits for the submission
+        let mut raw = state.lock().unwrap();
+        (*transfer).user_data = Box::into_raw(context) as *mut c_void;
+        let rc = ffi::libusb_submit_transfer(transfer);
+        if rc != constants::LIBUSB_SUCCESS {
+            drop(Box::from_raw((*transfer).user_data as *mut HostTransfer));
+            ffi::libusb_free_transfer(transfer);
+            let err = libusb_error(rc);
+            warn!(
+                "Failed to submit transfer to ep {:02x}: {}",
+                ep.address, err
+            );
+            return Some(PendingTransfer::new(failed_transfer(transfer_error(err))));
+        }
+        *raw = Some(RawTransfer(transfer));
+    }
+
+    Some(PendingTransfer::new(receiver).with_cancel(move || {
+        if let Some(transfer) = state.lock().unwrap().as_ref() {
+            // SAFETY: the transfer is not freed before its callback clears the state
+            unsafe { ffi::libusb_cancel_transfer(transfer.0) };
+        }
+    }))
+}
+
+/// Wrap a libusb error, keeping it so that the URB status reported to the client is accurate
+pub(crate) fn transfer_error(err: rusb::Error) -> std::io::Error {
+    let kind = match err {
+        rusb::Error::Pipe => ErrorKind::BrokenPipe,
+        rusb::Error::Timeout => ErrorKind::TimedOut,
+        rusb::Error::NoDevice => ErrorKind::NotConnected,
+        rusb::Error::Interrupted => ErrorKind::Interrupted,
+        rusb::Error::NotFound => ErrorKind::NotFound,
+        rusb::Error::Access => ErrorKind::PermissionDenied,
+        rusb::Error::InvalidParam => ErrorKind::InvalidInput,
+        rusb::Error::NoMem => ErrorKind::OutOfMemory,
+        rusb::Error::NotSupported => ErrorKind::Unsupported,
+        rusb::Error::Busy => ErrorKind::ResourceBusy,
+        _ => ErrorKind::Other,
+    };
+    std::io::Error::new(kind, err)
+}
+
+/// Convert an error code returned by libusb
+pub(crate) fn libusb_error(rc: i32) -> rusb::Error {
+    match rc {
+        constants::LIBUSB_ERROR_IO => rusb::Error::Io,
+        constants::LIBUSB_ERROR_INVALID_PARAM => rusb::Error::InvalidParam,
+        constants::LIBUSB_ERROR_ACCESS => rusb::Error::Access,
+        constants::LIBUSB_ERROR_NO_DEVICE => rusb::Error::NoDevice,
+        constants::LIBUSB_ERROR_NOT_FOUND => rusb::Error::NotFound,
+        constants::LIBUSB_ERROR_BUSY => rusb::Error::Busy,
+        constants::LIBUSB_ERROR_TIMEOUT => rusb::Error::Timeout,
+        constants::LIBUSB_ERROR_OVERFLOW => rusb::Error::Overflow,
+        constants::LIBUSB_ERROR_PIPE => rusb::Error::Pipe,
+        constants::LIBUSB_ERROR_INTERRUPTED => rusb::Error::Interrupted,
+        constants::LIBUSB_ERROR_NO_MEM => rusb::Error::NoMem,
+        constants::LIBUSB_ERROR_NOT_SUPPORTED => rusb::Error::NotSupported,
+        _ => rusb::Error::Other,
+    }
+}
+
+/// Bus id of a device of the host, named like the Linux kernel does from its port chain, e.g. `1-1.4`
+pub(crate) fn host_bus_id<D: UsbHostDevice>(dev: &D) -> String {
+    match dev.port_numbers() {
+        Ok(ports) => bus_id_from_ports(dev.bus_number(), &ports),
+        Err(err) => {
+            warn!(
+                "Failed to get port numbers of device {} on bus {}: {}",
+                dev.address(),
+                dev.bus_number(),
+                err
+            );
+            // unique at least
+            format!("{}-0.{}", dev.bus_number(), dev.address())
+        }
+    }
+}
+
+fn bus_id_from_ports(bus_number: u8, ports: &[u8]) -> String {
+    if ports.is_empty() {
+        // root hub
+        return format!("usb{}", bus_number);
+    }
+    let ports: Vec<String> = ports.iter().map(|port| port.to_string()).collect();
+    format!("{}-{}", bus_number, ports.join("."))
+}
+
+/// Sysfs path of a device of the host, as reported by the Linux usbipd
+///
+/// Falls back to the symlink below `/sys/bus/usb/devices` when it cannot be resolved, e.g. on other platforms.
+pub(crate) fn host_sysfs_path(bus_id: &str) -> String {
+    let link = format!("/sys/bus/usb/devices/{}", bus_id);
+    match std::fs::canonicalize(&link) {
+        Ok(path) => path.to_string_lossy().into_owned(),
+        Err(_) => link,
+    }
+}
+
+/// Map the speed reported by libusb to the speed of USB/IP
+pub(crate) fn host_speed(speed: rusb::Speed) -> UsbSpeed {
+    match speed {
+        rusb::Speed::Low => UsbSpeed::Low,
+        rusb::Speed::Full => UsbSpeed::Full,
+        rusb::Speed::High => UsbSpeed::High,
+        rusb::Speed::Super => UsbSpeed::Super,
+        rusb::Speed::SuperPlus => UsbSpeed::SuperPlus,
+        _ => UsbSpeed::Unknown,
+    }
+}
+
+/// A device plugged into or unplugged from the host
+pub(crate) enum HotplugEvent {
+    Arrived(Device<GlobalContext>),
+    Left(Device<GlobalContext>),
+}
+
+/// Forward hotplug callbacks out of the libusb event thread, where blocking calls are not allowed
+struct HotplugForwarder(mpsc::UnboundedSender<HotplugEvent>);
+
+impl Hotplug<GlobalContext> for HotplugForwarder {
+    fn device_arrived(&mut self, device: Device<GlobalContext>) {
+        self.0.send(HotplugEvent::Arrived(device)).ok();
+    }
+
+    fn device_left(&mut self, device: Device<GlobalContext>) {
+        self.0.send(HotplugEvent::Left(device)).ok();
+    }
+}
+
+/// Registration of libusb hotplug callbacks, see [UsbIpServer::watch_host_devices]
+///
+/// Devices are no longer exported or withdrawn automatically once it is dropped.
+pub struct UsbHostHotplug {
+    _registration: Registration<GlobalContext>,
+    /// Delivers the hotplug callbacks
+    _events: Arc<EventThread>,
+}
+
+impl UsbHostHotplug {
+    /// Register for hotplug events, including devices already plugged in
+    pub(crate) fn register() -> Result<(Self, mpsc::UnboundedReceiver<HotplugEvent>)> {
+        if !rusb::has_hotplug() {
+            return Err(std::io::Error::new(
+                ErrorKind::Unsupported,
+                "libusb does not support hotplug on this platform",
+            ));
+        }
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let registration = HotplugBuilder::new()
+            .enumerate(true)
+            .register(GlobalContext::default(), Box::new(HotplugForwarder(sender)))
+            .map_err(transfer_error)?;
+        Ok((
+            Self {
+                _registration: registration,
+                _events: EventThread::acquire(&GlobalContext::default()),
+            },
+            receiver,
+        ))
+    }
+}
+
+/// Split raw descriptors, ignoring a truncated one at the end
+fn split_descriptors(desc: &[u8]) -> Vec<&[u8]> {
+    let mut result = vec![];
+    let mut offset = 0;
+    while offset + 2 <= desc.len() && desc[offset] >= 2 {
+        let end = offset + desc[offset] as usize;
+        if end > desc.len() {
+            break;
+        }
+        result.push(&desc[offset..end]);
+        offset = end;
+    }
+    result
+}
+
+/// Numbers of the interfaces of a raw configuration descriptor
+pub(crate) fn config_interface_numbers(config: &[u8]) -> Vec<u8> {
+    let mut result = vec![];
+    for desc in split_descriptors(config) {
+        if desc[1] == DescriptorType::Interface as u8
+            && desc.len() >= 9
+            && !result.contains(&desc[2])
+        {
+            result.push(desc[2]);
+        }
+    }
+    result
+}
+
+/// An interface of a host device, as described by its first alternate setting
+#[derive(Clone, Copy, Debug)]
+pub struct UsbHostInterfaceInfo {
+    /// bInterfaceNumber on the host
+    pub number: u8,
+    pub class: u8,
+    pub subclass: u8,
+    pub protocol: u8,
+}
+
+/// The part of the active configuration of a host device chosen for export
+pub(crate) struct HostConfiguration {
+    pub(crate) interfaces: Vec<UsbInterface>,
+    /// Host numbers of `interfaces`
+    pub(crate) numbers: Vec<u8>,
+    /// Raw configuration descriptor with the chosen interfaces only, numbered consecutively
+    pub(crate) descriptor: Vec<u8>,
+}
+
+/// Mirror the interfaces of a raw configuration descriptor chosen by `select`, passing their requests to `handle`
+///
+/// Endpoints of all alternate settings are kept with their interface. Interface associations are kept
+/// when all of their interfaces are exported.
+pub(crate) fn host_configuration<H: UsbHostHandle>(
+    config: &[u8],
+    handle: &Arc<Mutex<H>>,
+    transfer_timeout: Duration,
+    mut select: impl FnMut(&UsbHostInterfaceInfo) -> bool,
+) -> HostConfiguration {
+    let descriptors = split_descriptors(config);
+    let mut numbers = vec![];
+    let mut seen = vec![];
+    for desc in &descriptors {
+        if desc[1] == DescriptorType::Interface as u8 && desc.len() >= 9 && !seen.contains(&desc[2])
+        {
+            seen.push(desc[2]);
+            let info = UsbHostInterfaceInfo {
+                number: desc[2],
+                class: desc[5],
+                subclass: desc[6],
+                protocol: desc[7],
+            };
+            if select(&info) {
+                numbers.push(info.number);
+            }
+        }
+    }
+    let exported = |number: u8| numbers.iter().position(|&n| n == number).map(|i| i as u8);
+
+    let mut interfaces: Vec<UsbInterface> = vec![];
+    let mut descriptor = descriptors.first().map(|d| d.to_vec()).unwrap_or_default();
+    // the interface the following descriptors belong to, and whether it is an alternate setting
+    let mut current: Option<(usize, bool)> = None;
+    let mut keep = true;
+    for desc in descriptors.iter().skip(1) {
+        let descriptor_type = desc[1];
+        if descriptor_type == DescriptorType::Interface as u8 && desc.len() >= 9 {
+            current = None;
+            keep = false;
+            let Some(number) = exported(desc[2]) else {
+                continue;
+            };
+            keep = true;
+            let mut desc = desc.to_vec();
+            desc[2] = number;
+            descriptor.extend_from_slice(&desc);
+
+            let index = number as usize;
+            if index == interfaces.len() {
+                let handler = Arc::new(Mutex::new(Box::new(
+                    UsbHostInterfaceHandler::new(handle.clone())
+                        .with_interface_number(numbers[index])
+                        .with_transfer_timeout(transfer_timeout),
+                )
+                    as Box<dyn UsbInterfaceHandler + Send>));
+                interfaces.push(UsbInterface {
+                    interface_class: desc[5],
+                    interface_subclass: desc[6],
+                    interface_protocol: desc[7],
+                    endpoints: vec![],
+                    string_interface: desc[8],
+                    class_specific_descriptor: vec![],
+                    handler,
+                });
+            }
+            current = Some((index, desc[3] != 0));
+        } else if descriptor_type == DescriptorType::InterfaceAssociation as u8 && desc.len() >= 8 {
+            // refers to host interface numbers, all of them must be exported in a row
+            let first = exported(desc[2]).filter(|&first| {
+                (0..desc[3]).all(|i| exported(desc[2].wrapping_add(i)) == Some(first + i))
+            });
+            match first {
+                Some(first) => {
+                    let mut desc = desc.to_vec();
+                    desc[2] = first;
+                    descriptor.extend_from_slice(&desc);
+                }
+                None => debug!("Drop association of interfaces not all exported"),
+            }
+        } else if keep {
+            descriptor.extend_from_slice(desc);
+            let Some((index, alternate_setting)) = current else {
+                continue;
+            };
+            let intf = &mut interfaces[index];
+            if descriptor_type == DescriptorType::Endpoint as u8 && desc.len() >= 7 {
+                if intf.endpoints.iter().all(|ep| ep.address != desc[2]) {
+                    intf.endpoints.push(UsbEndpoint {
+                        address: desc[2],
+                        attributes: desc[3],
+                        max_packet_size: u16::from_le_bytes([desc[4], desc[5]]),
+                        interval: desc[6],
+                        ..Default::default()
+                    });
+                }
+            } else if descriptor_type == DescriptorType::SuperSpeedEndpointCompanion as u8 {
+                if let Some(ep) = intf.endpoints.last_mut() {
+                    ep.superspeed_companion = SuperSpeedEndpointCompanion::find(desc);
+                }
+            } else if intf.endpoints.is_empty() && !alternate_setting {
+                // class specific descriptors between interface and endpoint descriptors
+                intf.class_specific_descriptor.extend_from_slice(desc);
+            }
+        }
+    }
+
+    if descriptor.len() >= 9 {
+        descriptor[4] = numbers.len() as u8; // bNumInterfaces
+        let total_length = (descriptor.len() as u16).to_le_bytes();
+        descriptor[2..4].copy_from_slice(&total_length); // wTotalLength
+    }
+    HostConfiguration {
+        interfaces,
+        numbers,
+        descriptor,
+    }
+}
+
+/// Mirror the strings at `indices` of a host device in all its languages
+///
+/// Strings which cannot be read are left out, a client asking for them gets a stall.
+pub(crate) fn mirror_host_strings<H: UsbHostHandle>(
+    device: &mut UsbDevice,
+    handle: &H,
+    indices: &[u8],
+    timeout: Duration,
+) {
+    let languages = match handle.read_languages(timeout) {
+        Ok(languages) => languages,
+        Err(err) => {
+            warn!("Failed to read languages of {}: {}", device.bus_id, err);
+            return;
+        }
+    };
+    if let Some(&language) = languages.first() {
+        device.default_language = language;
+    }
+    for &language in &languages {
+        if language != device.default_language {
+            device.string_translations.entry(language).or_default();
+        }
+        for &index in indices {
+            match handle.read_string_descriptor(language, index, timeout) {
+                Ok(string) => {
+                    device.set_string_translation(language, index, &string);
+                }
+                Err(err) => warn!(
+                    "Failed to read string {} in language {:04x} of {}: {}",
+                    index, language, device.bus_id, err
+                ),
+            }
+        }
+    }
+}
+
+/// String indices referenced by a raw configuration descriptor
+pub(crate) fn config_string_indices(config: &[u8]) -> Vec<u8> {
+    let mut result = vec![];
+    for desc in split_descriptors(config) {
+        let index = if desc[1] == DescriptorType::Configuration as u8 && desc.len() >= 9 {
+            desc[6]
+        } else if desc[1] == DescriptorType::Interface as u8 && desc.len() >= 9 {
+            desc[8]
+        } else if desc[1] == DescriptorType::InterfaceAssociation as u8 && desc.len() >= 8 {
+            desc[7]
+        } else {
+            0
+        };
+        if index != 0 && !result.contains(&index) {
+            result.push(index);
+        }
+    }
+    result
+}
+
+/// A receiver already holding an error
+pub(crate) fn failed_transfer(err: std::io::Error) -> oneshot::Receiver<Result<Vec<u8>>> {
+    let (sender, receiver) = oneshot::channel();
+    sender.send(Err(err)).ok();
+    receiver
+}
 
 /// A handler to pass requests to a USB device of the host
-#[derive(Clone)]
-pub struct UsbHostInterfaceHandler {
-    handle: Arc<Mutex<DeviceHandle<GlobalContext>>>,
+pub struct UsbHostInterfaceHandler<H: UsbHostHandle = DeviceHandle<GlobalContext>> {
+    handle: Arc<Mutex<H>>,
+    interface_number: Option<u8>,
+    transfer_timeout: Duration,
+}
+
+impl<H: UsbHostHandle> Clone for UsbHostInterfaceHandler<H> {
+    fn clone(&self) -> Self {
+        Self {
+            handle: self.handle.clone(),
+            interface_number: self.interface_number,
+            transfer_timeout: self.transfer_timeout,
+        }
+    }
 }
 
-impl UsbHostInterfaceHandler {
-    pub fn new(handle: Arc<Mutex<DeviceHandle<GlobalContext>>>) -> Self {
-        Self { handle }
+impl<H: UsbHostHandle> UsbHostInterfaceHandler<H> {
+    pub fn new(handle: Arc<Mutex<H>>) -> Self {
+        Self {
+            handle,
+            interface_number: None,
+            transfer_timeout: DEFAULT_TRANSFER_TIMEOUT,
+        }
+    }
+
+    /// Set the timeout of transfers passed to the device, see [UsbHostOptions::transfer_timeout]
+    pub fn with_transfer_timeout(mut self, transfer_timeout: Duration) -> Self {
+        self.transfer_timeout = transfer_timeout;
+        self
+    }
+
+    /// Set the number of the interface on the host, when it is exported under another number
+    ///
+    /// wIndex of requests to the interface is rewritten accordingly.
+    pub fn with_interface_number(mut self, interface_number: u8) -> Self {
+        self.interface_number = Some(interface_number);
+        self
+    }
+
+    fn translate_setup(&self, mut setup: SetupPacket) -> SetupPacket {
+        if let Some(number) = self.interface_number {
+            if setup.request_type & 0x1F == 0x01 {
+                // to interface, only low 8 bits are the interface number
+                setup.index = (setup.index & 0xFF00) | number as u16;
+            }
+        }
+        setup
     }
 }
 
-impl UsbInterfaceHandler for UsbHostInterfaceHandler {
+impl<H: UsbHostHandle> UsbInterfaceHandler for UsbHostInterfaceHandler<H> {
     fn handle_urb(
         &mut self,
         _interface: &UsbInterface,
@@ -22,27 +656,30 @@ impl UsbInterfaceHandler for UsbHostInterfaceHandler {
         setup: SetupPacket,
         req: &[u8],
     ) -> Result<Vec<u8>> {
+        let setup = self.translate_setup(setup);
         debug!(
             "To host device: ep={:?} setup={:?} req={:?}",
             ep, setup, req
         );
         let mut buffer = vec![0u8; transfer_buffer_length as usize];
-        let timeout = std::time::Duration::new(1, 0);
+        let timeout = self.transfer_timeout;
         let handle = self.handle.lock().unwrap();
-        if ep.attributes == EndpointAttributes::Control as u8 {
+        let transfer_type = ep.attributes & 0x3;
+        if transfer_type == EndpointAttributes::Control as u8 {
             // control
             if let Direction::In = ep.direction() {
                 // control in
-                if let Ok(len) = handle.read_control(
-                    setup.request_type,
-                    setup.request,
-                    setup.value,
-                    setup.index,
-                    &mut buffer,
-                    timeout,
-                ) {
-                    return Ok(Vec::from(&buffer[..len]));
-                }
+                let len = handle
+                    .read_control(
+                        setup.request_type,
+                        setup.request,
+                        setup.value,
+                        setup.index,
+                        &mut buffer,
+                        timeout,
+                    )
+                    .map_err(transfer_error)?;
+                return Ok(Vec::from(&buffer[..len]));
             } else {
                 // control out
                 handle
@@ -54,35 +691,67 @@ impl UsbInterfaceHandler for UsbHostInterfaceHandler {
                         req,
                         timeout,
                     )
-                    .ok();
+                    .map_err(transfer_error)?;
             }
-        } else if ep.attributes == EndpointAttributes::Interrupt as u8 {
+        } else if transfer_type == EndpointAttributes::Interrupt as u8 {
             // interrupt
             if let Direction::In = ep.direction() {
                 // interrupt in
-                if let Ok(len) = handle.read_interrupt(ep.address, &mut buffer, timeout) {
-                    info!("intr in {:?}", &buffer[..len]);
-                    return Ok(Vec::from(&buffer[..len]));
-                }
+                let len = handle
+                    .read_interrupt(ep.address, &mut buffer, timeout)
+                    .map_err(transfer_error)?;
+                info!("intr in {:?}", &buffer[..len]);
+                return Ok(Vec::from(&buffer[..len]));
             } else {
                 // interrupt out
-                handle.write_interrupt(ep.address, req, timeout).ok();
+                handle
+                    .write_interrupt(ep.address, req, timeout)
+                    .map_err(transfer_error)?;
             }
-        } else if ep.attributes == EndpointAttributes::Bulk as u8 {
+        } else if transfer_type == EndpointAttributes::Bulk as u8 {
             // bulk
             if let Direction::In = ep.direction() {
                 // bulk in
-                if let Ok(len) = handle.read_bulk(ep.address, &mut buffer, timeout) {
-                    return Ok(Vec::from(&buffer[..len]));
-                }
+                let len = handle
+                    .read_bulk(ep.address, &mut buffer, timeout)
+                    .map_err(transfer_error)?;
+                return Ok(Vec::from(&buffer[..len]));
             } else {
                 // bulk out
-                handle.write_bulk(ep.address, req, timeout).ok();
+                handle
+                    .write_bulk(ep.address, req, timeout)
+                    .map_err(transfer_error)?;
             }
+        } else {
+            warn!("Isochronous transfers are not supported");
+            return Err(transfer_error(rusb::Error::NotSupported));
         }
         Ok(vec![])
     }
 
+    fn submit_urb(
+        &mut self,
+        _interface: &UsbInterface,
+        ep: UsbEndpoint,
+        transfer_buffer_length: u32,
+        setup: SetupPacket,
+        req: &[u8],
+    ) -> Option<PendingTransfer> {
+        let setup = self.translate_setup(setup);
+        debug!(
+            "Submit to host device: ep={:?} setup={:?} req={:?}",
+            ep, setup, req
+        );
+        H::submit_transfer(
+            &self.handle,
+            ep,
+            transfer_buffer_length,
+            setup,
+            req,
+            self.transfer_timeout,
+        )
+    }
+
     fn get_class_specific_descriptor(&self) -> Vec<u8> {
         vec![]
     }
@@ -92,19 +761,186 @@ impl UsbInterfaceHandler for UsbHostInterfaceHandler {
     }
 }
 
+/// Timeout of transfers passed to host devices unless set in [UsbHostOptions]: none, they wait until they complete
+pub const DEFAULT_TRANSFER_TIMEOUT: Duration = Duration::ZERO;
+
+/// Options for sharing devices of the host
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct UsbHostOptions {
+    /// Reset devices when a client detaches them, before giving them back to host drivers
+    pub reset_on_detach: bool,
+    /// Timeout of transfers passed to the device, failing with `-ETIMEDOUT`, see [DEFAULT_TRANSFER_TIMEOUT]
+    ///
+    /// Also applies to the requests reading its descriptors. Zero waits until transfers complete or the client
+    /// unlinks them, as interrupt transfers may wait for input indefinitely.
+    #[cfg_attr(feature = "serde", serde(default = "default_transfer_timeout"))]
+    pub transfer_timeout: Duration,
+}
+
+#[cfg(feature = "serde")]
+fn default_transfer_timeout() -> Duration {
+    DEFAULT_TRANSFER_TIMEOUT
+}
+
+impl Default for UsbHostOptions {
+    fn default() -> Self {
+        Self {
+            reset_on_detach: false,
+            transfer_timeout: DEFAULT_TRANSFER_TIMEOUT,
+        }
+    }
+}
+
 /// A handler to pass requests to a USB device of the host
-#[derive(Clone)]
-pub struct UsbHostDeviceHandler {
-    handle: Arc<Mutex<DeviceHandle<GlobalContext>>>,
+pub struct UsbHostDeviceHandler<H: UsbHostHandle = DeviceHandle<GlobalContext>> {
+    handle: Arc<Mutex<H>>,
+    /// Interfaces claimed while a client has imported the device
+    claimed_interfaces: Vec<u8>,
+    /// Interfaces whose kernel driver was detached, reattached when the client detaches
+    detached_drivers: Vec<u8>,
+    reset_on_detach: bool,
+    /// Host numbers of the exported interfaces, all interfaces are exported when `None`
+    interfaces: Option<Vec<u8>>,
+    transfer_timeout: Duration,
+    /// Completes asynchronous transfers while a client has imported the device
+    events: Option<Arc<dyn Any + Send + Sync>>,
 }
 
-impl UsbHostDeviceHandler {
-    pub fn new(handle: Arc<Mutex<DeviceHandle<GlobalContext>>>) -> Self {
-        Self { handle }
+impl<H: UsbHostHandle> Clone for UsbHostDeviceHandler<H> {
+    fn clone(&self) -> Self {
+        Self {
+            handle: self.handle.clone(),
+            claimed_interfaces: self.claimed_interfaces.clone(),
+            detached_drivers: self.detached_drivers.clone(),
+            reset_on_detach: self.reset_on_detach,
+            interfaces: self.interfaces.clone(),
+            transfer_timeout: self.transfer_timeout,
+            events: self.events.clone(),
+        }
     }
 }
 
-impl UsbDeviceHandler for UsbHostDeviceHandler {
+impl<H: UsbHostHandle> UsbHostDeviceHandler<H> {
+    pub fn new(handle: Arc<Mutex<H>>) -> Self {
+        Self {
+            handle,
+            claimed_interfaces: vec![],
+            detached_drivers: vec![],
+            reset_on_detach: false,
+            interfaces: None,
+            transfer_timeout: DEFAULT_TRANSFER_TIMEOUT,
+            events: None,
+        }
+    }
+
+    /// Only claim and use the interfaces with these host numbers, exported under their index
+    pub fn with_interfaces(mut self, interfaces: Vec<u8>) -> Self {
+        self.interfaces = Some(interfaces);
+        self
+    }
+
+    /// Host number of an exported interface
+    fn host_interface(&self, interface: u8) -> Result<u8> {
+        match &self.interfaces {
+            None => Ok(interface),
+            Some(interfaces) => interfaces
+                .get(interface as usize)
+                .copied()
+                .ok_or_else(|| transfer_error(rusb::Error::NotFound)),
+        }
+    }
+
+    /// Host numbers of the exported interfaces of a raw configuration descriptor
+    fn exported_interfaces(&self, config: &[u8]) -> Vec<u8> {
+        config_interface_numbers(config)
+            .into_iter()
+            .filter(|i| {
+                self.interfaces
+                    .as_ref()
+                    .is_none_or(|chosen| chosen.contains(i))
+            })
+            .collect()
+    }
+
+    /// Reset the device when the client detaches it, before reattaching host drivers
+    pub fn with_reset_on_detach(mut self, reset_on_detach: bool) -> Self {
+        self.reset_on_detach = reset_on_detach;
+        self
+    }
+
+    /// Set the timeout of transfers passed to the device, see [UsbHostOptions::transfer_timeout]
+    pub fn with_transfer_timeout(mut self, transfer_timeout: Duration) -> Self {
+        self.transfer_timeout = transfer_timeout;
+        self
+    }
+
+    /// Claim an interface, detaching the kernel driver bound to it
+    fn claim_interface(&mut self, handle: &H, interface: u8) -> Result<()> {
+        if self.claimed_interfaces.contains(&interface) {
+            return Ok(());
+        }
+        // unsupported on some platforms, where claiming reports conflicts instead
+        if let Ok(true) = handle.kernel_driver_active(interface) {
+            debug!("Detach kernel driver of interface {}", interface);
+            handle
+                .detach_kernel_driver(interface)
+                .map_err(|err| claim_error(interface, err))?;
+            self.detached_drivers.push(interface);
+        }
+        handle
+            .claim_interface(interface)
+            .map_err(|err| claim_error(interface, err))?;
+        self.claimed_interfaces.push(interface);
+        Ok(())
+    }
+
+    fn release_interfaces(&mut self, handle: &H) {
+        for interface in self.claimed_interfaces.drain(..) {
+            if let Err(err) = handle.release_interface(interface) {
+                warn!("Failed to release interface {}: {}", interface, err);
+            }
+        }
+    }
+
+    fn reattach_kernel_drivers(&mut self, handle: &H) {
+        for interface in self.detached_drivers.drain(..) {
+            debug!("Reattach kernel driver of interface {}", interface);
+            match handle.attach_kernel_driver(interface) {
+                // bound again by the kernel, e.g. after a reset
+                Ok(()) | Err(rusb::Error::Busy) => {}
+                Err(err) => warn!(
+                    "Failed to reattach kernel driver of interface {}: {}",
+                    interface, err
+                ),
+            }
+        }
+    }
+}
+
+/// Explain why an interface could not be claimed
+fn claim_error(interface: u8, err: rusb::Error) -> std::io::Error {
+    let kind = transfer_error(err).kind();
+    match err {
+        rusb::Error::Busy => std::io::Error::new(
+            kind,
+            format!(
+                "Interface {} is busy, claimed by another process or driver",
+                interface
+            ),
+        ),
+        rusb::Error::Access => std::io::Error::new(
+            kind,
+            format!("No permission to claim interface {}", interface),
+        ),
+        _ => std::io::Error::new(
+            kind,
+            format!("Failed to claim interface {}: {}", interface, err),
+        ),
+    }
+}
+
+impl<H: UsbHostHandle> UsbDeviceHandler for UsbHostDeviceHandler<H> {
     fn handle_urb(
         &mut self,
         transfer_buffer_length: u32,
@@ -113,7 +949,7 @@ impl UsbDeviceHandler for UsbHostDeviceHandler {
     ) -> Result<Vec<u8>> {
         debug!("To host device: setup={:?} req={:?}", setup, req);
         let mut buffer = vec![0u8; transfer_buffer_length as usize];
-        let timeout = std::time::Duration::new(1, 0);
+        let timeout = self.transfer_timeout;
         let handle = self.handle.lock().unwrap();
         // control
         if setup.request_type & 0x80 == 0 {
@@ -127,24 +963,195 @@ impl UsbDeviceHandler for UsbHostDeviceHandler {
                     req,
                     timeout,
                 )
-                .ok();
+                .map_err(transfer_error)?;
         } else {
             // control in
-            if let Ok(len) = handle.read_control(
-                setup.request_type,
-                setup.request,
-                setup.value,
-                setup.index,
-                &mut buffer,
-                timeout,
-            ) {
-                return Ok(Vec::from(&buffer[..len]));
-            }
+            let len = handle
+                .read_control(
+                    setup.request_type,
+                    setup.request,
+                    setup.value,
+                    setup.index,
+                    &mut buffer,
+                    timeout,
+                )
+                .map_err(transfer_error)?;
+            return Ok(Vec::from(&buffer[..len]));
         }
         Ok(vec![])
     }
 
+    fn submit_urb(
+        &mut self,
+        transfer_buffer_length: u32,
+        setup: SetupPacket,
+        req: &[u8],
+    ) -> Option<PendingTransfer> {
+        debug!("Submit to host device: setup={:?} req={:?}", setup, req);
+        let ep0 = UsbEndpoint {
+            attributes: EndpointAttributes::Control as u8,
+            ..Default::default()
+        };
+        H::submit_transfer(
+            &self.handle,
+            ep0,
+            transfer_buffer_length,
+            setup,
+            req,
+            self.transfer_timeout,
+        )
+    }
+
+    fn attach(&mut self) -> Result<()> {
+        let handle = self.handle.clone();
+        let mut handle = handle.lock().unwrap();
+        handle.open_session().map_err(transfer_error)?;
+        let config = match handle.active_config_descriptor(self.transfer_timeout) {
+            Ok(config) => config,
+            Err(err) => {
+                handle.close_session();
+                return Err(transfer_error(err));
+            }
+        };
+        for interface in self.exported_interfaces(&config) {
+            if let Err(err) = self.claim_interface(&handle, interface) {
+                // leave the device usable by the host
+                self.release_interfaces(&handle);
+                self.reattach_kernel_drivers(&handle);
+                handle.close_session();
+                return Err(err);
+            }
+        }
+        self.events = handle.handle_events();
+        Ok(())
+    }
+
+    fn detach(&mut self) -> Result<()> {
+        let handle = self.handle.clone();
+        let mut handle = handle.lock().unwrap();
+        self.release_interfaces(&handle);
+        let res = if self.reset_on_detach {
+            debug!("Reset host device");
+            handle.reset().map_err(transfer_error)
+        } else {
+            Ok(())
+        };
+        self.reattach_kernel_drivers(&handle);
+        handle.close_session();
+        self.events = None;
+        res
+    }
+
+    fn set_configuration(&mut self, configuration: u8) -> Result<()> {
+        debug!("Set configuration of host device to {}", configuration);
+        let handle = self.handle.clone();
+        let handle = handle.lock().unwrap();
+        self.release_interfaces(&handle);
+        if configuration == 0 {
+            return handle.unconfigure().map_err(transfer_error);
+        }
+        handle
+            .set_active_configuration(configuration)
+            .map_err(transfer_error)?;
+
+        // interfaces of the new configuration must be claimed before use
+        let config = handle
+            .active_config_descriptor(self.transfer_timeout)
+            .map_err(transfer_error)?;
+        for interface in self.exported_interfaces(&config) {
+            if let Err(err) = self.claim_interface(&handle, interface) {
+                warn!("{}", err);
+            }
+        }
+        Ok(())
+    }
+
+    fn set_interface(&mut self, interface: u8, alternate_setting: u8) -> Result<()> {
+        let handle = self.handle.clone();
+        let handle = handle.lock().unwrap();
+        let interface = self.host_interface(interface)?;
+        self.claim_interface(&handle, interface)?;
+        handle
+            .set_alternate_setting(interface, alternate_setting)
+            .map_err(transfer_error)
+    }
+
+    fn clear_halt(&mut self, endpoint: u8) -> Result<()> {
+        let handle = self.handle.lock().unwrap();
+        handle.clear_halt(endpoint).map_err(transfer_error)
+    }
+
+    fn reset(&mut self) -> Result<()> {
+        let handle = self.handle.lock().unwrap();
+        handle.reset().map_err(transfer_error)
+    }
+
     fn as_any(&mut self) -> &mut dyn Any {
         self
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn kernel_bus_ids() {
+        assert_eq!(bus_id_from_ports(1, &[]), "usb1");
+        assert_eq!(bus_id_from_ports(1, &[2]), "1-2");
+        assert_eq!(bus_id_from_ports(3, &[1, 4, 2]), "3-1.4.2");
+    }
+
+    #[test]
+    fn event_thread_shared_and_stopped() {
+        let context = match rusb::Context::new() {
+            Ok(context) => context,
+            // libusb unavailable
+            Err(_) => return,
+        };
+        let events = EventThread::acquire(&context);
+        assert!(Arc::ptr_eq(&events, &EventThread::acquire(&context)));
+
+        // joined when dropped, instead of handling events forever
+        let stop = events.stop.clone();
+        std::mem::drop(events);
+        assert!(stop.load(Ordering::SeqCst));
+        let events = EventThread::acquire(&context);
+        assert!(!events.stop.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn attach_rolls_back_on_busy_interface() {
+        // two interfaces, the second one claimed by another process
+        let config = vec![
+            0x09, 0x02, 0x20, 0x00, 0x02, 0x01, 0x00, 0x80, 0x32, // configuration
+            0x09, 0x04, 0x00, 0x00, 0x00, 0xFF, 0x00, 0x00, 0x00, // interface 0
+            0x09, 0x04, 0x01, 0x00, 0x00, 0xFF, 0x00, 0x00, 0x00, // interface 1
+        ];
+        let device = MockUsbHostDevice::new(1, vec![], config);
+        {
+            let mut state = device.state.lock().unwrap();
+            state.kernel_drivers.push(0);
+            state.busy_interfaces.push(1);
+        }
+        let handle = Arc::new(Mutex::new(device.open().unwrap()));
+        let mut handler = UsbHostDeviceHandler::new(handle);
+
+        let err = handler.attach().unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::ResourceBusy);
+        assert!(err.to_string().contains("busy"));
+        let state = device.state.lock().unwrap();
+        assert!(state.claimed_interfaces.is_empty());
+        assert_eq!(state.kernel_drivers, [0]);
+    }
+
+    #[test]
+    fn speeds_match_usbip() {
+        assert_eq!(host_speed(rusb::Speed::Low) as u32, 1);
+        assert_eq!(host_speed(rusb::Speed::Full) as u32, 2);
+        assert_eq!(host_speed(rusb::Speed::High) as u32, 3);
+        assert_eq!(host_speed(rusb::Speed::Super) as u32, 5);
+        assert_eq!(host_speed(rusb::Speed::SuperPlus) as u32, 6);
+        assert_eq!(host_speed(rusb::Speed::Unknown) as u32, 0);
+    }
+}
@@ -15,6 +15,26 @@ pub struct UsbInterface {
     pub handler: Arc<Mutex<Box<dyn UsbInterfaceHandler + Send>>>,
 }
 
+impl UsbInterface {
+    /// Pass a URB to the handler, waiting for the transfer without holding its lock
+    pub(crate) async fn handle_urb(
+        &self,
+        ep: UsbEndpoint,
+        transfer_buffer_length: u32,
+        setup: SetupPacket,
+        req: &[u8],
+    ) -> Result<Vec<u8>> {
+        let pending = {
+            let mut handler = self.handler.lock().unwrap();
+            match handler.submit_urb(self, ep, transfer_buffer_length, setup, req) {
+                Some(pending) => pending,
+                None => return handler.handle_urb(self, ep, transfer_buffer_length, setup, req),
+            }
+        };
+        pending.await
+    }
+}
+
 /// A handler of a custom usb interface
 pub trait UsbInterfaceHandler {
     /// Return the class specific descriptor which is inserted between interface descriptor and endpoint descriptor
@@ -33,6 +53,21 @@ pub trait UsbInterfaceHandler {
         req: &[u8],
     ) -> Result<Vec<u8>>;
 
+    /// Submit a URB(USB Request Block) targeting at this interface without waiting for its completion
+    ///
+    /// Handlers backed by real hardware should implement this, so that other URBs are not blocked meanwhile.
+    /// When `None` is returned, [UsbInterfaceHandler::handle_urb] is called instead.
+    fn submit_urb(
+        &mut self,
+        _interface: &UsbInterface,
+        _ep: UsbEndpoint,
+        _transfer_buffer_length: u32,
+        _setup: SetupPacket,
+        _req: &[u8],
+    ) -> Option<PendingTransfer> {
+        None
+    }
+
     /// Helper to downcast to actual struct
     ///
     /// Please implement it as:
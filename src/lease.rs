@@ -0,0 +1,225 @@
+//! Limits on how long clients may hold devices
+use super::*;
+use std::net::IpAddr;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Limits on the sessions importing a device, see [UsbIpServer::set_lease_policy]
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct LeasePolicy {
+    /// Detach sessions after they have been attached this long
+    pub max_duration: Option<Duration>,
+    /// Detach sessions after this long without submitting a URB, while none of their URBs is pending
+    pub idle_timeout: Option<Duration>,
+    /// Windows during which only a given client may import the device
+    pub reservations: Vec<Reservation>,
+}
+
+/// A window during which only `peer` may import a device
+///
+/// A session of another client is detached when the window starts, and the session of `peer` when it ends.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Reservation {
+    pub peer: IpAddr,
+    pub start: SystemTime,
+    pub end: SystemTime,
+}
+
+impl Reservation {
+    fn is_active(&self, now: SystemTime) -> bool {
+        self.start <= now && now < self.end
+    }
+}
+
+/// Why a lease expired
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum LeaseExpiry {
+    /// Attached for [LeasePolicy::max_duration]
+    MaxDuration,
+    /// No URB submitted or pending for [LeasePolicy::idle_timeout]
+    Idle,
+    /// The device got reserved for another client
+    Reserved,
+    /// The reservation of the client ended
+    ReservationEnded,
+}
+
+/// A session detached as its lease expired, see [UsbIpServer::lease_events]
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct LeaseEvent {
+    pub bus_id: String,
+    pub peer: Option<SocketAddr>,
+    pub expiry: LeaseExpiry,
+}
+
+/// Lease bookkeeping of a session
+#[derive(Default)]
+pub(crate) struct LeaseState {
+    /// Bus id of the imported device, with when it got imported
+    imported: Option<(String, Instant)>,
+    /// When a URB was last submitted or completed
+    last_urb: Option<Instant>,
+    /// URBs waiting for the device, e.g. an interrupt IN of a keyboard nobody types on
+    pending_urbs: usize,
+    /// Notified when the last pending URB completes, restarting the idle timeout
+    idle: Arc<Notify>,
+}
+
+impl LeaseState {
+    pub(crate) fn imported(&mut self, bus_id: &str) {
+        let now = Instant::now();
+        self.imported = Some((bus_id.to_string(), now));
+        self.last_urb = Some(now);
+    }
+
+    pub(crate) fn urb_submitted(&mut self) {
+        self.last_urb = Some(Instant::now());
+    }
+
+    pub(crate) fn urb_pending(&mut self) {
+        self.pending_urbs += 1;
+    }
+
+    pub(crate) fn urb_completed(&mut self) {
+        self.pending_urbs = self.pending_urbs.saturating_sub(1);
+        self.last_urb = Some(Instant::now());
+        if self.pending_urbs == 0 {
+            self.idle.notify_waiters();
+        }
+    }
+}
+
+impl UsbIpServer {
+    /// Limit the sessions importing the device `bus_id`, including the current one
+    pub async fn set_lease_policy(&self, bus_id: &str, policy: LeasePolicy) {
+        self.lease_policies
+            .write()
+            .await
+            .insert(bus_id.to_string(), policy);
+        self.leases_changed.notify_waiters();
+    }
+
+    /// Remove the limits on the sessions importing the device `bus_id`
+    pub async fn clear_lease_policy(&self, bus_id: &str) {
+        self.lease_policies.write().await.remove(bus_id);
+        self.leases_changed.notify_waiters();
+    }
+
+    /// Receive the events of sessions detached from now on as their lease expired
+    pub fn lease_events(&self) -> mpsc::UnboundedReceiver<LeaseEvent> {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        self.lease_subscribers.lock().unwrap().push(sender);
+        receiver
+    }
+
+    pub(crate) fn report_lease_event(&self, event: LeaseEvent) {
+        info!(
+            "Lease of device {} by {:?} expired: {:?}",
+            event.bus_id, event.peer, event.expiry
+        );
+        self.lease_subscribers
+            .lock()
+            .unwrap()
+            .retain(|subscriber| subscriber.send(event.clone()).is_ok());
+    }
+
+    /// Whether the device `bus_id` is reserved for a client other than `peer` at the moment
+    pub(crate) async fn reserved_for_other(&self, bus_id: &str, peer: Option<SocketAddr>) -> bool {
+        let now = SystemTime::now();
+        self.lease_policies
+            .read()
+            .await
+            .get(bus_id)
+            .is_some_and(|policy| {
+                policy.reservations.iter().any(|reservation| {
+                    reservation.is_active(now) && Some(reservation.peer) != peer.map(|p| p.ip())
+                })
+            })
+    }
+}
+
+/// Completes with the device and the reason when the lease of `session` expires
+pub(crate) async fn lease_expiry(server: &UsbIpServer, session: &Session) -> (String, LeaseExpiry) {
+    let idle = session.lease.lock().unwrap().idle.clone();
+    loop {
+        // registered before reading the policy, so that a change in between is not missed
+        let changed = server.leases_changed.notified();
+        tokio::pin!(changed);
+        changed.as_mut().enable();
+        let became_idle = idle.notified();
+        tokio::pin!(became_idle);
+        became_idle.as_mut().enable();
+
+        // the session is not idle while URBs are pending
+        let (bus_id, attached, last_urb) = {
+            let lease = session.lease.lock().unwrap();
+            match &lease.imported {
+                Some((bus_id, attached)) => (
+                    bus_id.clone(),
+                    *attached,
+                    Some(lease.last_urb.unwrap_or(*attached)).filter(|_| lease.pending_urbs == 0),
+                ),
+                None => (String::new(), Instant::now(), None),
+            }
+        };
+        let policy = if bus_id.is_empty() {
+            None
+        } else {
+            server.lease_policies.read().await.get(&bus_id).cloned()
+        };
+        let Some(policy) = policy else {
+            changed.await;
+            continue;
+        };
+
+        let now = Instant::now();
+        let system_now = SystemTime::now();
+        // the first expiry to come, with when
+        let mut next: Option<(Instant, LeaseExpiry)> = None;
+        let mut consider = |at: Instant, expiry: LeaseExpiry| {
+            if next.is_none_or(|(next_at, _)| at < next_at) {
+                next = Some((at, expiry));
+            }
+        };
+        if let Some(max_duration) = policy.max_duration {
+            consider(attached + max_duration, LeaseExpiry::MaxDuration);
+        }
+        if let (Some(idle_timeout), Some(last_urb)) = (policy.idle_timeout, last_urb) {
+            consider(last_urb + idle_timeout, LeaseExpiry::Idle);
+        }
+        let peer = session.peer.map(|peer| peer.ip());
+        let attached_at = system_now - now.saturating_duration_since(attached);
+        for reservation in &policy.reservations {
+            // passed times are now
+            let at = |time: SystemTime| now + time.duration_since(system_now).unwrap_or_default();
+            if Some(reservation.peer) == peer {
+                if attached_at < reservation.end {
+                    consider(at(reservation.end), LeaseExpiry::ReservationEnded);
+                }
+            } else if system_now < reservation.end {
+                consider(at(reservation.start), LeaseExpiry::Reserved);
+            }
+        }
+
+        match next {
+            Some((at, expiry)) if at <= now => return (bus_id, expiry),
+            Some((at, _)) => {
+                tokio::select! {
+                    _ = tokio::time::sleep_until(at) => {}
+                    _ = changed => {}
+                    _ = became_idle => {}
+                }
+            }
+            None => {
+                tokio::select! {
+                    _ = changed => {}
+                    _ = became_idle => {}
+                }
+            }
+        }
+    }
+}
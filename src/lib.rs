@@ -5,35 +5,61 @@ use num_derive::FromPrimitive;
 use num_traits::FromPrimitive;
 use rusb::*;
 use std::any::Any;
-use std::collections::{HashMap, VecDeque};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::future::Future;
 use std::io::{ErrorKind, Result};
 use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
+use std::task::Poll;
+use std::time::SystemTime;
 use tokio::io::AsyncReadExt;
 use tokio::io::AsyncWriteExt;
 use tokio::net::TcpListener;
-use tokio::sync::RwLock;
+use tokio::sync::{mpsc, oneshot, Notify, RwLock};
 use usbip_protocol::UsbIpCommand;
 
 #[cfg(feature = "serde")]
 use serde::{Deserialize, Serialize};
 
+mod admin;
+mod backend;
+mod bos;
+mod builder;
 pub mod cdc;
+mod client;
+#[cfg(feature = "config")]
+mod config;
 mod consts;
 mod device;
 mod endpoint;
+mod error;
 pub mod hid;
 mod host;
 mod interface;
+mod lease;
+pub mod msos;
 mod setup;
+mod transfer;
 pub mod usbip_protocol;
 mod util;
+pub mod webusb;
+pub use admin::*;
+pub use backend::*;
+pub use bos::*;
+pub use builder::*;
+pub use client::*;
+#[cfg(feature = "config")]
+pub use config::*;
 pub use consts::*;
 pub use device::*;
 pub use endpoint::*;
+pub use error::Error;
 pub use host::*;
 pub use interface::*;
+pub use lease::*;
 pub use setup::*;
+pub use transfer::*;
 pub use util::*;
 
 use crate::usbip_protocol::{UsbIpResponse, USBIP_RET_SUBMIT, USBIP_RET_UNLINK};
@@ -43,161 +69,259 @@ use crate::usbip_protocol::{UsbIpResponse, USBIP_RET_SUBMIT, USBIP_RET_UNLINK};
 pub struct UsbIpServer {
     available_devices: RwLock<Vec<UsbDevice>>,
     used_devices: RwLock<HashMap<String, UsbDevice>>,
+    /// Sessions which imported a device, by bus id
+    sessions: RwLock<HashMap<String, Session>>,
+    lease_policies: RwLock<HashMap<String, LeasePolicy>>,
+    /// Signal sessions to check their lease again, as a policy changed or a device got imported
+    leases_changed: Notify,
+    lease_subscribers: Mutex<Vec<mpsc::UnboundedSender<LeaseEvent>>>,
+    settings: ServerSettings,
+    /// Connections being handled, see [UsbIpServerBuilder::with_max_connections]
+    connections: std::sync::atomic::AtomicUsize,
 }
 
+/// Bus of the simulated devices, whose bus ids are allocated as `0-<port>`
+const SIMULATED_BUS: u32 = 0;
+
 impl UsbIpServer {
     /// Create a [UsbIpServer] with simulated devices
+    ///
+    /// Devices without bus id get the first free ones, devices whose bus id is taken already are ignored.
     pub fn new_simulated(devices: Vec<UsbDevice>) -> Self {
+        let (assigned, errors) = Self::assign_bus_ids(devices);
+        for err in errors {
+            warn!("{}, ignoring device", err);
+        }
         Self {
-            available_devices: RwLock::new(devices),
-            used_devices: RwLock::new(HashMap::new()),
+            available_devices: RwLock::new(assigned),
+            ..Default::default()
+        }
+    }
+
+    /// Like [UsbIpServer::new_simulated], failing with [Error::DuplicateBusId] if devices choose the same bus id
+    pub fn try_new_simulated(devices: Vec<UsbDevice>) -> std::result::Result<Self, Error> {
+        let (assigned, errors) = Self::assign_bus_ids(devices);
+        match errors.into_iter().next() {
+            Some(err) => Err(err),
+            None => Ok(Self {
+                available_devices: RwLock::new(assigned),
+                ..Default::default()
+            }),
+        }
+    }
+
+    /// Give bus ids to `devices`, returning the ones which got one along with why the others did not
+    fn assign_bus_ids(devices: Vec<UsbDevice>) -> (Vec<UsbDevice>, Vec<Error>) {
+        // bus ids chosen by devices are not allocated to others
+        let chosen: HashSet<String> = devices
+            .iter()
+            .filter(|dev| !dev.bus_id.is_empty())
+            .map(|dev| dev.bus_id.clone())
+            .collect();
+        let mut assigned: Vec<UsbDevice> = vec![];
+        let mut errors = vec![];
+        for mut device in devices {
+            let allocated = device.bus_id.is_empty();
+            let res = Self::assign_bus_id(&mut device, |bus_id| {
+                (allocated && chosen.contains(bus_id))
+                    || assigned.iter().any(|d| d.bus_id == bus_id)
+            });
+            match res {
+                Ok(()) => assigned.push(device),
+                Err(err) => errors.push(err),
+            }
+        }
+        (assigned, errors)
+    }
+
+    /// Give `device` the first free bus id unless it has one, failing if its bus id is `taken`
+    fn assign_bus_id<F: Fn(&str) -> bool>(
+        device: &mut UsbDevice,
+        taken: F,
+    ) -> std::result::Result<(), Error> {
+        if device.bus_id.is_empty() {
+            device.bus_id = (1..)
+                .map(|port| format!("{}-{}", SIMULATED_BUS, port))
+                .find(|bus_id| !taken(bus_id))
+                .unwrap();
+            device.bus_num = SIMULATED_BUS;
+        } else if taken(&device.bus_id) {
+            return Err(Error::DuplicateBusId(device.bus_id.clone()));
+        }
+        if device.path.is_empty() {
+            device.path = format!("/sys/bus/usb/devices/{}", device.bus_id);
         }
+        Ok(())
+    }
+
+    /// Create a [UsbIpServer] with the simulated devices of a TOML or JSON file, see [DevicesConfig]
+    #[cfg(feature = "config")]
+    pub fn new_from_config<P: AsRef<std::path::Path>>(
+        path: P,
+        registry: &HandlerRegistry,
+    ) -> Result<Self> {
+        let devices = DevicesConfig::load(path)?.build(registry)?;
+        Ok(Self::try_new_simulated(devices)?)
     }
 
-    fn with_devices(device_list: Vec<Device<GlobalContext>>) -> Vec<UsbDevice> {
+    fn with_devices<D, G>(
+        device_list: Vec<D>,
+        mut select: G,
+        options: &UsbHostOptions,
+    ) -> Vec<UsbDevice>
+    where
+        D: UsbHostDevice,
+        G: FnMut(&D, &UsbHostInterfaceInfo) -> bool,
+    {
         let mut devices = vec![];
 
         for dev in device_list {
+            let bus_id = dev.bus_id();
             let open_device = match dev.open() {
                 Ok(dev) => dev,
                 Err(err) => {
-                    warn!("Impossible to share {:?}: {}, ignoring device", dev, err);
+                    warn!("Impossible to share {}: {}, ignoring device", bus_id, err);
                     continue;
                 }
             };
             let desc = match dev.device_descriptor() {
-                Ok(desc) => desc,
+                Ok(desc) if desc.len() >= 18 => desc,
+                Ok(_) => {
+                    warn!(
+                        "Truncated device descriptor for {}, ignoring device",
+                        bus_id
+                    );
+                    continue;
+                }
                 Err(err) => {
                     warn!(
-                        "Impossible to get device descriptor for {:?}: {}, ignoring device",
-                        dev, err
+                        "Impossible to get device descriptor for {}: {}, ignoring device",
+                        bus_id, err
                     );
                     continue;
                 }
             };
-            let cfg = match dev.active_config_descriptor() {
-                Ok(desc) => desc,
+            let cfg = match open_device.active_config_descriptor(options.transfer_timeout) {
+                Ok(desc) if desc.len() >= 9 => desc,
+                Ok(_) => {
+                    warn!(
+                        "Truncated config descriptor for {}, ignoring device",
+                        bus_id
+                    );
+                    continue;
+                }
                 Err(err) => {
                     warn!(
-                        "Impossible to get config descriptor for {:?}: {}, ignoring device",
-                        dev, err
+                        "Impossible to get config descriptor for {}: {}, ignoring device",
+                        bus_id, err
                     );
                     continue;
                 }
             };
 
-            let handle = Arc::new(Mutex::new(open_device));
-            let mut interfaces = vec![];
-            handle
-                .lock()
-                .unwrap()
-                .set_auto_detach_kernel_driver(true)
-                .ok();
-            for intf in cfg.interfaces() {
-                // ignore alternate settings
-                let intf_desc = intf.descriptors().next().unwrap();
-                handle
-                    .lock()
-                    .unwrap()
-                    .set_auto_detach_kernel_driver(true)
-                    .ok();
-                let mut endpoints = vec![];
-
-                for ep_desc in intf_desc.endpoint_descriptors() {
-                    endpoints.push(UsbEndpoint {
-                        address: ep_desc.address(),
-                        attributes: ep_desc.transfer_type() as u8,
-                        max_packet_size: ep_desc.max_packet_size(),
-                        interval: ep_desc.interval(),
-                    });
-                }
+            let usb_version = device::Version::from_bcd(u16::from_le_bytes([desc[2], desc[3]]));
+            // bMaxPacketSize0 is an exponent for SuperSpeed devices
+            let ep0_max_packet_size = if usb_version.major >= 3 {
+                1u16 << desc[7].min(15)
+            } else {
+                desc[7] as u16
+            };
 
-                let handler = Arc::new(Mutex::new(Box::new(UsbHostInterfaceHandler::new(
-                    handle.clone(),
-                ))
-                    as Box<dyn UsbInterfaceHandler + Send>));
-                interfaces.push(UsbInterface {
-                    interface_class: intf_desc.class_code(),
-                    interface_subclass: intf_desc.sub_class_code(),
-                    interface_protocol: intf_desc.protocol_code(),
-                    endpoints,
-                    string_interface: intf_desc.description_string_index().unwrap_or(0),
-                    class_specific_descriptor: Vec::from(intf_desc.extra()),
-                    handler,
-                });
+            let handle = Arc::new(Mutex::new(open_device));
+            let config = host_configuration(&cfg, &handle, options.transfer_timeout, |intf| {
+                select(&dev, intf)
+            });
+            if config.interfaces.is_empty() && cfg[4] != 0 {
+                info!("No interface of {} selected, ignoring device", bus_id);
+                continue;
             }
+            let mut string_indices = config_string_indices(&config.descriptor);
+            string_indices.extend(desc[14..17].iter().filter(|&&i| i != 0));
+            string_indices.sort_unstable();
+            string_indices.dedup();
+            let speed = dev.speed();
+            // bMaxPower is in 8mA units for SuperSpeed and 2mA units otherwise
+            let power_unit = if matches!(speed, UsbSpeed::Super | UsbSpeed::SuperPlus) {
+                8
+            } else {
+                2
+            };
             let mut device = UsbDevice {
-                path: format!(
-                    "/sys/bus/{}/{}/{}",
-                    dev.bus_number(),
-                    dev.address(),
-                    dev.port_number()
-                ),
-                bus_id: format!(
-                    "{}-{}-{}",
-                    dev.bus_number(),
-                    dev.address(),
-                    dev.port_number()
-                ),
+                path: host_sysfs_path(&bus_id),
+                bus_id,
                 bus_num: dev.bus_number() as u32,
-                dev_num: dev.port_number() as u32,
-                speed: dev.speed() as u32,
-                vendor_id: desc.vendor_id(),
-                product_id: desc.product_id(),
-                device_class: desc.class_code(),
-                device_subclass: desc.sub_class_code(),
-                device_protocol: desc.protocol_code(),
-                device_bcd: desc.device_version().into(),
-                configuration_value: cfg.number(),
-                num_configurations: desc.num_configurations(),
+                dev_num: dev.address() as u32,
+                speed: speed as u32,
+                // the other speed of the device is unknown here, so its qualifier is stalled
+                // rather than guessed from the descriptors at the current speed
+                supported_speeds: vec![],
+                vendor_id: u16::from_le_bytes([desc[8], desc[9]]),
+                product_id: u16::from_le_bytes([desc[10], desc[11]]),
+                device_class: desc[4],
+                device_subclass: desc[5],
+                device_protocol: desc[6],
+                device_bcd: device::Version::from_bcd(u16::from_le_bytes([desc[12], desc[13]])),
+                configuration_value: cfg[5],
+                string_configuration: cfg[6],
+                self_powered: cfg[7] & 0x40 != 0,
+                remote_wakeup: cfg[7] & 0x20 != 0,
+                max_power: cfg[8] as u16 * power_unit,
+                num_configurations: desc[17],
                 ep0_in: UsbEndpoint {
                     address: 0x80,
                     attributes: EndpointAttributes::Control as u8,
-                    max_packet_size: desc.max_packet_size() as u16,
+                    max_packet_size: ep0_max_packet_size,
                     interval: 0,
+                    ..Default::default()
                 },
                 ep0_out: UsbEndpoint {
                     address: 0x00,
                     attributes: EndpointAttributes::Control as u8,
-                    max_packet_size: desc.max_packet_size() as u16,
+                    max_packet_size: ep0_max_packet_size,
                     interval: 0,
+                    ..Default::default()
                 },
-                interfaces,
-                device_handler: Some(Arc::new(Mutex::new(Box::new(UsbHostDeviceHandler::new(
-                    handle.clone(),
-                ))))),
-                usb_version: desc.usb_version().into(),
+                interfaces: config.interfaces,
+                raw_configuration: Some(config.descriptor),
+                device_handler: Some(Arc::new(Mutex::new(Box::new(
+                    UsbHostDeviceHandler::new(handle.clone())
+                        .with_interfaces(config.numbers)
+                        .with_reset_on_detach(options.reset_on_detach)
+                        .with_transfer_timeout(options.transfer_timeout),
+                )))),
+                usb_version,
+                default_language: LANGUAGE_ID_EN_US,
+                string_manufacturer: desc[14],
+                string_product: desc[15],
+                string_serial: desc[16],
                 ..UsbDevice::default()
             };
 
             // set strings
-            if let Some(index) = desc.manufacturer_string_index() {
-                device.string_manufacturer = device.new_string(
-                    &handle
-                        .lock()
-                        .unwrap()
-                        .read_string_descriptor_ascii(index)
-                        .unwrap(),
-                )
-            }
-            if let Some(index) = desc.product_string_index() {
-                device.string_product = device.new_string(
-                    &handle
-                        .lock()
-                        .unwrap()
-                        .read_string_descriptor_ascii(index)
-                        .unwrap(),
-                )
-            }
-            if let Some(index) = desc.serial_number_string_index() {
-                device.string_serial = device.new_string(
-                    &handle
-                        .lock()
-                        .unwrap()
-                        .read_string_descriptor_ascii(index)
-                        .unwrap(),
-                )
+            mirror_host_strings(
+                &mut device,
+                &*handle.lock().unwrap(),
+                &string_indices,
+                options.transfer_timeout,
+            );
+
+            // BOS descriptors exist since USB 2.1
+            if device.usb_version.to_bcd() >= 0x0201 {
+                match handle
+                    .lock()
+                    .unwrap()
+                    .read_bos_descriptor(options.transfer_timeout)
+                {
+                    Ok(bos) => device.raw_bos = Some(bos),
+                    Err(err) => warn!(
+                        "Failed to read BOS descriptor of {}: {}",
+                        device.bus_id, err
+                    ),
+                }
             }
+            // released until a client imports the device
+            handle.lock().unwrap().close_session();
+
             devices.push(device);
         }
         devices
@@ -205,14 +329,68 @@ impl UsbIpServer {
 
     /// Create a [UsbIpServer] exposing devices in the host, and redirect all USB transfers to them using libusb
     pub fn new_from_host() -> Self {
-        match rusb::devices() {
+        Self::new_from_host_with_filter(|_| true)
+    }
+
+    pub fn new_from_host_with_filter<F>(filter: F) -> Self
+    where
+        F: FnMut(&Device<GlobalContext>) -> bool,
+    {
+        Self::new_from_host_with_options(filter, UsbHostOptions::default())
+    }
+
+    /// Create a [UsbIpServer] exposing devices in the host selected by `filter`, shared according to `options`
+    pub fn new_from_host_with_options<F>(filter: F, options: UsbHostOptions) -> Self
+    where
+        F: FnMut(&Device<GlobalContext>) -> bool,
+    {
+        Self::new_from_backend(&GlobalContext::default(), filter, options)
+    }
+
+    /// Create a [UsbIpServer] exposing devices in the host selected by `filter`, and only their interfaces chosen by `select`
+    ///
+    /// Interfaces left out stay bound to host drivers, for example the keyboard interface of a composite security key.
+    /// Exported interfaces are numbered consecutively.
+    pub fn new_from_host_with_interfaces<F, G>(
+        filter: F,
+        select: G,
+        options: UsbHostOptions,
+    ) -> Self
+    where
+        F: FnMut(&Device<GlobalContext>) -> bool,
+        G: FnMut(&Device<GlobalContext>, &UsbHostInterfaceInfo) -> bool,
+    {
+        Self::new_from_backend_with_interfaces(&GlobalContext::default(), filter, select, options)
+    }
+
+    /// Create a [UsbIpServer] exposing devices of `backend` selected by `filter`, shared according to `options`
+    ///
+    /// Use a [rusb::Context] as backend to share devices of a libusb context other than the global one.
+    pub fn new_from_backend<B, F>(backend: &B, filter: F, options: UsbHostOptions) -> Self
+    where
+        B: UsbHostBackend,
+        F: FnMut(&B::Device) -> bool,
+    {
+        Self::new_from_backend_with_interfaces(backend, filter, |_, _| true, options)
+    }
+
+    /// Create a [UsbIpServer] exposing devices of `backend` selected by `filter`, and only their interfaces chosen by `select`
+    pub fn new_from_backend_with_interfaces<B, F, G>(
+        backend: &B,
+        filter: F,
+        select: G,
+        options: UsbHostOptions,
+    ) -> Self
+    where
+        B: UsbHostBackend,
+        F: FnMut(&B::Device) -> bool,
+        G: FnMut(&B::Device, &UsbHostInterfaceInfo) -> bool,
+    {
+        match backend.devices() {
             Ok(list) => {
-                let mut devs = vec![];
-                for d in list.iter() {
-                    devs.push(d)
-                }
+                let devs = list.into_iter().filter(filter).collect();
                 Self {
-                    available_devices: RwLock::new(Self::with_devices(devs)),
+                    available_devices: RwLock::new(Self::with_devices(devs, select, &options)),
                     ..Default::default()
                 }
             }
@@ -220,230 +398,792 @@ impl UsbIpServer {
         }
     }
 
-    pub fn new_from_host_with_filter<F>(filter: F) -> Self
+    /// Export devices of the host matching `filter` as they are plugged in, and withdraw them when unplugged
+    ///
+    /// Only their interfaces chosen by `select` are exported, see [UsbIpServer::new_from_host_with_interfaces].
+    /// Devices already plugged in are exported as well, so start from a server without host devices.
+    /// Sessions using a device which gets unplugged are detached.
+    /// Hotplug stops when the returned [UsbHostHotplug] is dropped. Must be called within a tokio runtime.
+    pub fn watch_host_devices<F, G>(
+        self: &Arc<Self>,
+        mut filter: F,
+        select: G,
+        options: UsbHostOptions,
+    ) -> Result<UsbHostHotplug>
     where
-        F: FnMut(&Device<GlobalContext>) -> bool,
+        F: FnMut(&Device<GlobalContext>) -> bool + Send + 'static,
+        G: FnMut(&Device<GlobalContext>, &UsbHostInterfaceInfo) -> bool + Send + 'static,
     {
-        match rusb::devices() {
-            Ok(list) => {
-                let mut devs = vec![];
-                for d in list.iter().filter(filter) {
-                    devs.push(d)
-                }
-                Self {
-                    available_devices: RwLock::new(Self::with_devices(devs)),
-                    ..Default::default()
+        let (hotplug, mut events) = UsbHostHotplug::register()?;
+        let server = self.clone();
+        // used by the blocking tasks opening plugged in devices
+        let select = Arc::new(Mutex::new(select));
+        tokio::spawn(async move {
+            while let Some(event) = events.recv().await {
+                match event {
+                    HotplugEvent::Arrived(dev) => {
+                        if !filter(&dev) || server.has_device(&host_bus_id(&dev)).await {
+                            continue;
+                        }
+                        let options = options.clone();
+                        let select = select.clone();
+                        // opening the device and reading its descriptors blocks
+                        match tokio::task::spawn_blocking(move || {
+                            let mut select = select.lock().unwrap();
+                            Self::with_devices(vec![dev], &mut *select, &options)
+                        })
+                        .await
+                        {
+                            Ok(devices) => {
+                                for device in devices {
+                                    match server.add_device(device).await {
+                                        Ok(bus_id) => info!("Device {} plugged in", bus_id),
+                                        Err(err) => warn!("{}, ignoring plugged in device", err),
+                                    }
+                                }
+                            }
+                            Err(err) => warn!("Failed to open plugged in device: {}", err),
+                        }
+                    }
+                    HotplugEvent::Left(dev) => {
+                        let bus_id = host_bus_id(&dev);
+                        info!("Device {} unplugged", bus_id);
+                        server.withdraw_device(&bus_id).await;
+                    }
                 }
             }
-            Err(_) => Default::default(),
+        });
+        Ok(hotplug)
+    }
+
+    async fn has_device(&self, bus_id: &str) -> bool {
+        self.used_devices.read().await.contains_key(bus_id)
+            || self
+                .available_devices
+                .read()
+                .await
+                .iter()
+                .any(|d| d.bus_id == bus_id)
+    }
+
+    /// Remove a device which is gone, detaching the session using it
+    async fn withdraw_device(&self, bus_id: &str) {
+        if self.remove_device(bus_id).await.is_ok() {
+            return;
+        }
+        if let Some(session) = self.sessions.read().await.get(bus_id) {
+            session.withdrawn.store(true, Ordering::SeqCst);
+            session.unplugged.notify_one();
+            return;
+        }
+        // the session ended meanwhile, giving the device back
+        self.remove_device(bus_id).await.ok();
+    }
+
+    /// Re-export the devices of another USB/IP server, along with the devices of this one
+    ///
+    /// Returns how many devices were added.
+    pub async fn add_upstream_devices(&self, upstream: &UsbIpBackend) -> Result<usize> {
+        let upstream = upstream.clone();
+        // querying the upstream server and reading the descriptors blocks
+        let devices = tokio::task::spawn_blocking(move || {
+            let list = upstream.devices().map_err(transfer_error)?;
+            Ok::<_, std::io::Error>(Self::with_devices(
+                list,
+                |_, _| true,
+                &UsbHostOptions::default(),
+            ))
+        })
+        .await
+        .map_err(std::io::Error::other)??;
+        let mut count = 0;
+        for device in devices {
+            match self.add_device(device).await {
+                Ok(_) => count += 1,
+                Err(err) => warn!("{}, ignoring upstream device", err),
+            }
         }
+        Ok(count)
     }
 
-    pub async fn add_device(&self, device: UsbDevice) {
-        self.available_devices.write().await.push(device);
+    /// Export a device, returning its bus id
+    ///
+    /// A device without bus id, as created by [UsbDevice::new], gets the first free one, e.g. `0-1`.
+    /// Fails if another device is exported under the same bus id.
+    pub async fn add_device(&self, mut device: UsbDevice) -> std::result::Result<String, Error> {
+        let used_devices = self.used_devices.read().await;
+        let mut available_devices = self.available_devices.write().await;
+        Self::assign_bus_id(&mut device, |bus_id| {
+            used_devices.contains_key(bus_id)
+                || available_devices.iter().any(|d| d.bus_id == bus_id)
+        })?;
+        let bus_id = device.bus_id.clone();
+        available_devices.push(device);
+        Ok(bus_id)
     }
 
-    pub async fn remove_device(&self, bus_id: &str) -> Result<()> {
+    /// Remove an available device, failing with [Error::DeviceBusy] while a client imported it
+    pub async fn remove_device(&self, bus_id: &str) -> std::result::Result<(), Error> {
+        // locked in the same order as when importing
+        let used_devices = self.used_devices.read().await;
         let mut available_devices = self.available_devices.write().await;
 
         if let Some(device) = available_devices.iter().position(|d| d.bus_id == bus_id) {
             available_devices.remove(device);
             Ok(())
-        } else if let Some(device) = self
-            .used_devices
-            .read()
-            .await
-            .values()
-            .find(|d| d.bus_id == bus_id)
-        {
-            Err(std::io::Error::new(
-                ErrorKind::Other,
-                format!("Device {} is in use", device.bus_id),
-            ))
+        } else if used_devices.contains_key(bus_id) {
+            Err(Error::DeviceBusy(bus_id.to_string()))
         } else {
-            Err(std::io::Error::new(
-                ErrorKind::NotFound,
-                format!("Device {} not found", bus_id),
-            ))
+            Err(Error::DeviceNotFound(bus_id.to_string()))
         }
     }
 }
 
+/// A client session which imported a device
+#[derive(Clone)]
+struct Session {
+    peer: Option<SocketAddr>,
+    attached_at: SystemTime,
+    /// Signal detaching the session, as the device got unplugged
+    unplugged: Arc<Notify>,
+    /// Set when the host confirmed that the device is gone, withdrawing it once the session ended
+    withdrawn: Arc<AtomicBool>,
+    /// Signal closing the connection, giving the device back
+    close: Arc<Notify>,
+    /// Signal closing the connection as the device got unplugged by the server, see [UsbIpServer::unplug_device]
+    remove: Arc<Notify>,
+    /// Signalled once the device is given back or withdrawn after the session ended
+    ended: Arc<Notify>,
+    lease: Arc<Mutex<LeaseState>>,
+}
+
+/// URBs waiting for the device, with the signal cancelling them, by seqnum
+///
+/// A cancelled URB fails with the status sent, if any, or gets no USBIP_RET_SUBMIT at all.
+type InFlightUrbs = Arc<Mutex<HashMap<u32, oneshot::Sender<Option<i32>>>>>;
+
 pub async fn handler<T: AsyncReadExt + AsyncWriteExt + Unpin>(
-    mut socket: &mut T,
+    socket: &mut T,
+    server: Arc<UsbIpServer>,
+) -> Result<()> {
+    handler_with_peer(socket, server, None).await
+}
+
+/// Like [handler], recording the address of the client in the session, see [UsbIpServer::list_devices]
+pub async fn handler_with_peer<T: AsyncReadExt + AsyncWriteExt + Unpin>(
+    socket: &mut T,
     server: Arc<UsbIpServer>,
+    peer: Option<SocketAddr>,
 ) -> Result<()> {
+    handler_with_shutdown(socket, server, peer, std::future::pending()).await
+}
+
+/// Like [handler_with_peer], closing the connection gracefully once `shutdown` completes
+async fn handler_with_shutdown<T: AsyncReadExt + AsyncWriteExt + Unpin, F: Future<Output = ()>>(
+    socket: &mut T,
+    server: Arc<UsbIpServer>,
+    peer: Option<SocketAddr>,
+    shutdown: F,
+) -> Result<()> {
+    let _connection = match ConnectionGuard::new(&server) {
+        Ok(guard) => guard,
+        Err(err) => {
+            warn!("Refusing connection from {:?}: {}", peer, err);
+            return Err(err.into());
+        }
+    };
+    let (mut reader, mut writer) = tokio::io::split(socket);
+    let (response_sender, mut response_receiver) = mpsc::unbounded_channel::<UsbIpResponse>();
+    let in_flight = InFlightUrbs::default();
     let mut current_import_device_id: Option<String> = None;
-    loop {
-        let command = UsbIpCommand::read_from_socket(&mut socket).await;
-        if let Err(err) = command {
-            if let Some(dev_id) = current_import_device_id {
-                let mut used_devices = server.used_devices.write().await;
-                let mut available_devices = server.available_devices.write().await;
-                match used_devices.remove(&dev_id) {
-                    Some(dev) => available_devices.push(dev),
-                    None => unreachable!(),
+    let session = Session {
+        peer,
+        attached_at: SystemTime::now(),
+        unplugged: Arc::new(Notify::new()),
+        withdrawn: Arc::new(AtomicBool::new(false)),
+        close: Arc::new(Notify::new()),
+        remove: Arc::new(Notify::new()),
+        ended: Arc::new(Notify::new()),
+        lease: Default::default(),
+    };
+    let mut device_unplugged = false;
+    let mut device_removed = false;
+
+    // responses are written as URBs complete, which is not necessarily in submission order
+    let write_responses = async {
+        while let Some(res) = response_receiver.recv().await {
+            res.write_to_socket(&mut writer).await?;
+        }
+        Ok(())
+    };
+    let read_commands = async {
+        let res = tokio::select! {
+            res = read_commands(
+                &mut reader,
+                &server,
+                &mut current_import_device_id,
+                &in_flight,
+                response_sender,
+                &session,
+            ) => res,
+            // the device got unplugged, noticed by a URB waiting for it or by hotplug
+            _ = session.unplugged.notified() => Ok(()),
+            _ = shutdown => Err(ClosedByServer::error("server shutting down")),
+            _ = session.close.notified() => Err(ClosedByServer::error("detached by the server")),
+            _ = session.remove.notified() => {
+                device_removed = true;
+                Err(ClosedByServer::error("device unplugged by the server"))
+            }
+            (bus_id, expiry) = lease_expiry(&server, &session) => {
+                server.report_lease_event(LeaseEvent {
+                    bus_id,
+                    peer: session.peer,
+                    expiry,
+                });
+                Err(ClosedByServer::error("lease expired"))
+            }
+        };
+        // detach the session, like a device unplugged from the client
+        device_unplugged = res.is_ok();
+        // cancel pending URBs, so that the responses channel gets closed, failing them if the device is gone
+        let status = (device_unplugged || device_removed).then_some(-usbip_protocol::ENODEV);
+        for (_, cancel) in in_flight.lock().unwrap().drain() {
+            cancel.send(status).ok();
+        }
+        res
+    };
+    // the writer flushes the remaining responses once the reader stops
+    let (read_res, write_res) = tokio::join!(read_commands, write_responses);
+
+    in_flight.lock().unwrap().clear();
+    if let Some(dev_id) = current_import_device_id {
+        server.sessions.write().await.remove(&dev_id);
+        let mut used_devices = server.used_devices.write().await;
+        let mut available_devices = server.available_devices.write().await;
+        match used_devices.remove(&dev_id) {
+            Some(_) if session.withdrawn.load(Ordering::SeqCst) => {
+                info!("Device {} disconnected, detached from the session", dev_id)
+            }
+            Some(dev) if device_removed => {
+                info!("Device {} unplugged, detached from the session", dev_id);
+                if let Err(err) = dev.detach() {
+                    warn!("Failed to detach device {}: {}", dev_id, err);
                 }
             }
-
-            if err.kind() == ErrorKind::UnexpectedEof {
-                info!("Remote closed the connection");
-                return Ok(());
-            } else {
-                return Err(err);
+            Some(dev) => {
+                if device_unplugged {
+                    // a URB failing with -ENODEV does not prove that the device is gone, e.g. after a reset
+                    info!(
+                        "Device {} reported as gone, detached from the session",
+                        dev_id
+                    );
+                }
+                if let Err(err) = dev.detach() {
+                    warn!("Failed to detach device {}: {}", dev_id, err);
+                }
+                available_devices.push(dev)
             }
+            None => unreachable!(),
+        }
+        session.ended.notify_waiters();
+    }
+
+    match read_res.and(write_res) {
+        Ok(_) => Ok(()),
+        Err(err) if err.kind() == ErrorKind::UnexpectedEof => {
+            info!("Remote closed the connection");
+            Ok(())
+        }
+        Err(err)
+            if err
+                .get_ref()
+                .is_some_and(|inner| inner.is::<ClosedByServer>()) =>
+        {
+            info!("Connection closed: {}", err);
+            Ok(())
+        }
+        Err(err) => Err(err),
+    }
+}
+
+/// Why the server closed a connection, which is not an error of the session
+#[derive(Debug)]
+struct ClosedByServer(&'static str);
+
+impl ClosedByServer {
+    fn error(reason: &'static str) -> std::io::Error {
+        std::io::Error::new(ErrorKind::ConnectionAborted, Self(reason))
+    }
+}
+
+impl std::fmt::Display for ClosedByServer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.0)
+    }
+}
+
+impl std::error::Error for ClosedByServer {}
+
+/// Counts a connection in [UsbIpServer::connections] while alive
+struct ConnectionGuard<'a> {
+    connections: &'a std::sync::atomic::AtomicUsize,
+}
+
+impl<'a> ConnectionGuard<'a> {
+    fn new(server: &'a UsbIpServer) -> std::result::Result<Self, Error> {
+        use std::sync::atomic::Ordering;
+        let connections = &server.connections;
+        let count = connections.fetch_add(1, Ordering::SeqCst) + 1;
+        let guard = Self { connections };
+        match server.settings.max_connections {
+            Some(limit) if count > limit => Err(Error::LimitExceeded {
+                what: "connections",
+                value: count as u64,
+                limit: limit as u64,
+            }),
+            _ => Ok(guard),
         }
+    }
+}
 
-        let used_devices = server.used_devices.read().await;
-        let mut current_import_device = current_import_device_id
-            .clone()
-            .and_then(|ref id| used_devices.get(id));
+impl Drop for ConnectionGuard<'_> {
+    fn drop(&mut self) {
+        self.connections
+            .fetch_sub(1, std::sync::atomic::Ordering::SeqCst);
+    }
+}
 
-        match command.unwrap() {
+/// Process commands until the connection is closed, queueing their responses to `responses`
+///
+/// Returns `Ok` when the imported device got unplugged.
+async fn read_commands<T: AsyncReadExt + Unpin>(
+    mut socket: &mut T,
+    server: &UsbIpServer,
+    current_import_device_id: &mut Option<String>,
+    in_flight: &InFlightUrbs,
+    responses: mpsc::UnboundedSender<UsbIpResponse>,
+    session: &Session,
+) -> Result<()> {
+    let unplugged = &session.unplugged;
+    let settings = &server.settings;
+    let mut current_import_device: Option<Arc<UsbDevice>> = None;
+    let handshake_deadline = settings
+        .handshake_timeout
+        .map(|timeout| tokio::time::Instant::now() + timeout);
+    // notified by pending URBs as they complete
+    let urb_completed = Arc::new(Notify::new());
+    loop {
+        let command =
+            UsbIpCommand::read_from_socket_with_limit(&mut socket, settings.max_transfer_size);
+        tokio::pin!(command);
+        // the handshake ends once a device is imported
+        let handshake_deadline = handshake_deadline.filter(|_| current_import_device.is_none());
+        let mut idle_since = tokio::time::Instant::now();
+        let command = loop {
+            // clients waiting for pending URBs send nothing meanwhile, so they are only idle without any
+            let idle_deadline = settings
+                .idle_timeout
+                .filter(|_| in_flight.lock().unwrap().is_empty())
+                .map(|timeout| idle_since + timeout);
+            let deadline = handshake_deadline.into_iter().chain(idle_deadline).min();
+            tokio::select! {
+                command = &mut command => break command?,
+                _ = urb_completed.notified() => idle_since = tokio::time::Instant::now(),
+                _ = async {
+                    match deadline {
+                        Some(deadline) => tokio::time::sleep_until(deadline).await,
+                        None => std::future::pending().await,
+                    }
+                } => {
+                    let reason = if deadline == handshake_deadline {
+                        "no device imported before the handshake timeout"
+                    } else {
+                        "no command received before the idle timeout"
+                    };
+                    return Err(std::io::Error::new(ErrorKind::TimedOut, reason));
+                }
+            }
+        };
+        match command {
             UsbIpCommand::OpReqDevlist { .. } => {
                 trace!("Got OP_REQ_DEVLIST");
                 let devices = server.available_devices.read().await;
 
                 // OP_REP_DEVLIST
-                UsbIpResponse::op_rep_devlist(&devices)
-                    .write_to_socket(socket)
-                    .await?;
+                responses.send(UsbIpResponse::op_rep_devlist(&devices)).ok();
                 trace!("Sent OP_REP_DEVLIST");
             }
             UsbIpCommand::OpReqImport { busid, .. } => {
                 trace!("Got OP_REQ_IMPORT");
 
-                current_import_device_id = None;
+                // importing again gives back the device imported before, like detaching it
                 current_import_device = None;
-                std::mem::drop(used_devices);
+                if let Some(dev_id) = current_import_device_id.take() {
+                    for (_, cancel) in in_flight.lock().unwrap().drain() {
+                        cancel.send(None).ok();
+                    }
+                    server.sessions.write().await.remove(&dev_id);
+                    let mut used_devices = server.used_devices.write().await;
+                    match used_devices.remove(&dev_id) {
+                        Some(_) if session.withdrawn.swap(false, Ordering::SeqCst) => {
+                            info!("Device {} disconnected, detached from the session", dev_id)
+                        }
+                        Some(dev) => {
+                            debug!("Device {} released by the session", dev_id);
+                            if let Err(err) = dev.detach() {
+                                warn!("Failed to detach device {}: {}", dev_id, err);
+                            }
+                            server.available_devices.write().await.push(dev);
+                        }
+                        None => {}
+                    }
+                    std::mem::drop(used_devices);
+                    session.ended.notify_waiters();
+                }
 
-                let mut used_devices = server.used_devices.write().await;
-                let mut available_devices = server.available_devices.write().await;
                 let busid_compare =
                     &busid[..busid.iter().position(|&x| x == 0).unwrap_or(busid.len())];
+                let reserved = match std::str::from_utf8(busid_compare) {
+                    Ok(bus_id) => server.reserved_for_other(bus_id, session.peer).await,
+                    Err(_) => false,
+                };
+                let mut used_devices = server.used_devices.write().await;
+                let mut available_devices = server.available_devices.write().await;
+                // why the import failed, telling clients whether to wait for the device
+                let mut status = match std::str::from_utf8(busid_compare) {
+                    Ok(bus_id) if used_devices.contains_key(bus_id) => {
+                        warn!("Device {} is in use", bus_id);
+                        usbip_protocol::ST_DEV_BUSY
+                    }
+                    _ => usbip_protocol::ST_NODEV,
+                };
+                // marked as used while attaching, which may wait for the device or an upstream server
+                let mut attaching = None;
                 for (i, dev) in available_devices.iter().enumerate() {
                     if busid_compare == dev.bus_id.as_bytes() {
+                        if reserved {
+                            warn!("Device {} is reserved for another client", dev.bus_id);
+                            status = usbip_protocol::ST_NA;
+                            break;
+                        }
                         let dev = available_devices.remove(i);
-                        let dev_id = dev.bus_id.clone();
-                        used_devices.insert(dev.bus_id.clone(), dev);
-                        current_import_device_id = dev_id.clone().into();
-                        current_import_device = Some(used_devices.get(&dev_id).unwrap());
+                        used_devices.insert(dev.bus_id.clone(), dev.clone());
+                        attaching = Some((i, dev));
                         break;
                     }
                 }
+                std::mem::drop(available_devices);
+                std::mem::drop(used_devices);
+
+                if let Some((index, dev)) = attaching {
+                    let device = dev.clone();
+                    let res = match tokio::task::spawn_blocking(move || device.attach()).await {
+                        Ok(res) => res,
+                        // the handler panicked
+                        Err(err) => Err(Error::Handler(std::io::Error::other(err))),
+                    };
+                    match res {
+                        Ok(()) => {
+                            *current_import_device_id = Some(dev.bus_id.clone());
+                            // URBs run concurrently with a copy sharing the handlers
+                            current_import_device = Some(Arc::new(dev));
+                        }
+                        Err(err) => {
+                            warn!("Failed to attach device {}: {}", dev.bus_id, err);
+                            status = usbip_protocol::ST_DEV_ERR;
+                            let mut used_devices = server.used_devices.write().await;
+                            let mut available_devices = server.available_devices.write().await;
+                            used_devices.remove(&dev.bus_id);
+                            let index = index.min(available_devices.len());
+                            available_devices.insert(index, dev);
+                        }
+                    }
+                }
+                if let Some(dev_id) = current_import_device_id.as_ref() {
+                    let mut sessions = server.sessions.write().await;
+                    let imported = Session {
+                        attached_at: SystemTime::now(),
+                        ..session.clone()
+                    };
+                    sessions.insert(dev_id.clone(), imported);
+                    std::mem::drop(sessions);
+                    session.lease.lock().unwrap().imported(dev_id);
+                    server.leases_changed.notify_waiters();
+                }
 
-                let res = if let Some(dev) = current_import_device {
+                let res = if let Some(dev) = current_import_device.as_ref() {
                     UsbIpResponse::op_rep_import_success(dev)
                 } else {
-                    UsbIpResponse::op_rep_import_fail()
+                    if status == usbip_protocol::ST_NODEV {
+                        warn!(
+                            "Device {} not found",
+                            String::from_utf8_lossy(busid_compare)
+                        );
+                    }
+                    UsbIpResponse::op_rep_import_fail_with_status(status)
                 };
-                res.write_to_socket(socket).await?;
+                responses.send(res).ok();
                 trace!("Sent OP_REP_IMPORT");
             }
             UsbIpCommand::UsbIpCmdSubmit {
-                mut header,
+                header,
                 transfer_buffer_length,
                 setup,
                 data,
                 ..
             } => {
                 trace!("Got USBIP_CMD_SUBMIT");
-                let device = current_import_device.unwrap();
-
-                let out = header.direction == 0;
-                let real_ep = if out { header.ep } else { header.ep | 0x80 };
-
-                header.command = USBIP_RET_SUBMIT.into();
-
-                let res = match device.find_ep(real_ep as u8) {
-                    None => {
-                        warn!("Endpoint {:02x?} not found", real_ep);
-                        UsbIpResponse::usbip_ret_submit_fail(&header)
+                session.lease.lock().unwrap().urb_submitted();
+                let device = current_import_device.clone().unwrap();
+                let seqnum = header.seqnum;
+                let mut ret_header = header.clone();
+                ret_header.command = USBIP_RET_SUBMIT.into();
+                if let Some(limit) = settings.max_in_flight_urbs {
+                    if in_flight.lock().unwrap().len() >= limit {
+                        warn!("{} URBs pending, failing URB {}", limit, seqnum);
+                        responses
+                            .send(UsbIpResponse::usbip_ret_submit_fail_with_status(
+                                &ret_header,
+                                -usbip_protocol::EBUSY,
+                            ))
+                            .ok();
+                        continue;
                     }
-                    Some((ep, intf)) => {
-                        trace!("->Endpoint {:02x?}", ep);
-                        trace!("->Setup {:02x?}", setup);
-                        trace!("->Request {:02x?}", data);
-                        let resp = device
-                            .handle_urb(
-                                ep,
-                                intf,
-                                transfer_buffer_length,
-                                SetupPacket::parse(&setup),
-                                &data,
-                            )
-                            .await?;
-
-                        if out {
-                            trace!("<-Wrote {}", data.len());
-                        } else {
-                            trace!("<-Resp {:02x?}", resp);
+                }
+                let mut urb = Box::pin(handle_cmd_submit(
+                    device,
+                    header,
+                    transfer_buffer_length,
+                    setup,
+                    data,
+                ));
+
+                // poll once before reading the next command, so that URBs reach the device in order
+                match std::future::poll_fn(|cx| Poll::Ready(urb.as_mut().poll(cx))).await {
+                    Poll::Ready(res) => {
+                        if send_ret_submit(&responses, res, unplugged) {
+                            return Ok(());
                         }
-
-                        UsbIpResponse::usbip_ret_submit_success(&header, 0, 0, resp, vec![])
                     }
-                };
-                res.write_to_socket(socket).await?;
-                trace!("Sent USBIP_RET_SUBMIT");
+                    Poll::Pending => {
+                        let (cancel_sender, cancel_receiver) = oneshot::channel();
+                        in_flight.lock().unwrap().insert(seqnum, cancel_sender);
+                        let in_flight = in_flight.clone();
+                        let responses = responses.clone();
+                        let unplugged = unplugged.clone();
+                        let urb_completed = urb_completed.clone();
+                        session.lease.lock().unwrap().urb_pending();
+                        let lease = session.lease.clone();
+                        tokio::spawn(async move {
+                            tokio::select! {
+                                biased;
+                                res = urb => {
+                                    // unlinked URBs get no USBIP_RET_SUBMIT
+                                    if in_flight.lock().unwrap().remove(&seqnum).is_some() {
+                                        send_ret_submit(&responses, res, &unplugged);
+                                    }
+                                }
+                                status = cancel_receiver => {
+                                    debug!("URB {} cancelled", seqnum);
+                                    if let Ok(Some(status)) = status {
+                                        responses.send(UsbIpResponse::usbip_ret_submit_fail_with_status(
+                                            &ret_header,
+                                            status,
+                                        )).ok();
+                                    }
+                                }
+                            }
+                            urb_completed.notify_one();
+                            lease.lock().unwrap().urb_completed();
+                        });
+                    }
+                }
             }
             UsbIpCommand::UsbIpCmdUnlink {
                 mut header,
-                unlink_seqnum: _,
+                unlink_seqnum,
             } => {
                 trace!("Got USBIP_CMD_UNLINK");
 
-                std::mem::drop(used_devices);
-
-                let mut used_devices = server.used_devices.write().await;
-                let mut available_devices = server.available_devices.write().await;
-
-                let dev = current_import_device_id
-                    .clone()
-                    .and_then(|ref k| used_devices.remove(k));
-
                 header.command = USBIP_RET_UNLINK.into();
 
-                let res = match dev {
-                    Some(dev) => {
-                        available_devices.push(dev);
-                        current_import_device_id = None;
-                        UsbIpResponse::usbip_ret_unlink_success(&header)
+                let cancel = in_flight.lock().unwrap().remove(&unlink_seqnum);
+                let res = match cancel {
+                    Some(cancel) => {
+                        debug!("Unlink URB {}", unlink_seqnum);
+                        cancel.send(None).ok();
+                        UsbIpResponse::usbip_ret_unlink_with_status(
+                            &header,
+                            -usbip_protocol::ECONNRESET,
+                        )
                     }
                     None => {
-                        warn!("Device not found");
-                        UsbIpResponse::usbip_ret_unlink_fail(&header)
+                        // completed before the unlink request arrived
+                        debug!("URB {} to unlink not found", unlink_seqnum);
+                        UsbIpResponse::usbip_ret_unlink_success(&header)
                     }
                 };
-                res.write_to_socket(socket).await?;
+                responses.send(res).ok();
                 trace!("Sent USBIP_RET_UNLINK");
             }
         }
     }
 }
 
+/// Pass a USBIP_CMD_SUBMIT to the device and build its USBIP_RET_SUBMIT
+async fn handle_cmd_submit(
+    device: Arc<UsbDevice>,
+    mut header: usbip_protocol::UsbIpHeaderBasic,
+    transfer_buffer_length: u32,
+    setup: [u8; 8],
+    data: Vec<u8>,
+) -> UsbIpResponse {
+    let out = header.direction == 0;
+    let real_ep = if out { header.ep } else { header.ep | 0x80 };
+
+    header.command = USBIP_RET_SUBMIT.into();
+
+    match device.find_ep(real_ep as u8) {
+        None => {
+            warn!("Endpoint {:02x?} not found", real_ep);
+            UsbIpResponse::usbip_ret_submit_fail(&header)
+        }
+        Some((ep, intf)) => {
+            trace!("->Endpoint {:02x?}", ep);
+            trace!("->Setup {:02x?}", setup);
+            trace!("->Request {:02x?}", data);
+            match device
+                .handle_urb(
+                    ep,
+                    intf,
+                    transfer_buffer_length,
+                    SetupPacket::parse(&setup),
+                    &data,
+                )
+                .await
+            {
+                Ok(resp) => {
+                    if out {
+                        trace!("<-Wrote {}", data.len());
+                    } else {
+                        trace!("<-Resp {:02x?}", resp);
+                    }
+
+                    UsbIpResponse::usbip_ret_submit_success(&header, 0, 0, resp, vec![])
+                }
+                Err(err) => {
+                    warn!("URB to endpoint {:02x?} failed: {}", real_ep, err);
+                    UsbIpResponse::usbip_ret_submit_fail_with_status(&header, urb_status(&err))
+                }
+            }
+        }
+    }
+}
+
+/// Map an error returned by a handler to the URB status reported to the client
+fn urb_status(err: &std::io::Error) -> i32 {
+    use usbip_protocol::*;
+    // errors from libusb keep their exact cause
+    if let Some(err) = err.get_ref().and_then(|e| e.downcast_ref::<rusb::Error>()) {
+        return match err {
+            rusb::Error::Pipe => -EPIPE,
+            rusb::Error::Timeout => -ETIMEDOUT,
+            rusb::Error::NoDevice => -ENODEV,
+            rusb::Error::Overflow => -EOVERFLOW,
+            rusb::Error::Interrupted => -ECONNRESET,
+            rusb::Error::Io => -EIO,
+            rusb::Error::Busy => -EBUSY,
+            rusb::Error::NotFound => -ENOENT,
+            rusb::Error::InvalidParam => -EINVAL,
+            rusb::Error::Access => -EACCES,
+            rusb::Error::NoMem => -ENOMEM,
+            rusb::Error::NotSupported => -EOPNOTSUPP,
+            rusb::Error::BadDescriptor | rusb::Error::Other => -EPROTO,
+        };
+    }
+    match err.kind() {
+        ErrorKind::BrokenPipe => -EPIPE,
+        ErrorKind::TimedOut => -ETIMEDOUT,
+        ErrorKind::NotConnected => -ENODEV,
+        ErrorKind::Interrupted => -ECONNRESET,
+        _ => -EPROTO,
+    }
+}
+
+/// Queue a USBIP_RET_SUBMIT, signalling `unplugged` and returning `true` when it reports the device as gone
+fn send_ret_submit(
+    responses: &mpsc::UnboundedSender<UsbIpResponse>,
+    res: UsbIpResponse,
+    unplugged: &Notify,
+) -> bool {
+    let gone = matches!(
+        res,
+        UsbIpResponse::UsbIpRetSubmit { status, .. } if status == -usbip_protocol::ENODEV as u32
+    );
+    responses.send(res).ok();
+    trace!("Sent USBIP_RET_SUBMIT");
+    if gone {
+        unplugged.notify_one();
+    }
+    gone
+}
+
 /// Spawn a USB/IP server at `addr` using [TcpListener]
 pub async fn server(addr: SocketAddr, server: Arc<UsbIpServer>) {
-    let listener = TcpListener::bind(addr).await.expect("bind to addr");
+    server_with_shutdown(addr, server, std::future::pending())
+        .await
+        .expect("bind to addr")
+}
+
+/// Serve `server` on `addr` until `shutdown` completes
+///
+/// Then no connection is accepted anymore, and the sessions are ended, detaching their devices gracefully,
+/// before returning.
+pub async fn server_with_shutdown<F: Future<Output = ()>>(
+    addr: SocketAddr,
+    server: Arc<UsbIpServer>,
+    shutdown: F,
+) -> Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    let mut sessions = tokio::task::JoinSet::new();
+    // ends the sessions of this listener only
+    let (stop, _) = tokio::sync::broadcast::channel::<()>(1);
+    tokio::pin!(shutdown);
 
-    let server = async move {
-        loop {
-            match listener.accept().await {
+    loop {
+        tokio::select! {
+            res = listener.accept() => match res {
                 Ok((mut socket, _addr)) => {
                     info!("Got connection from {:?}", socket.peer_addr());
+                    if let Err(err) = server.settings.configure_socket(&socket) {
+                        warn!("Failed to set socket options: {}", err);
+                    }
                     let new_server = server.clone();
-                    tokio::spawn(async move {
-                        let res = handler(&mut socket, new_server).await;
+                    let peer = socket.peer_addr().ok();
+                    let mut stopped = stop.subscribe();
+                    sessions.spawn(async move {
+                        let shutdown = async move {
+                            stopped.recv().await.ok();
+                        };
+                        let res = handler_with_shutdown(&mut socket, new_server, peer, shutdown).await;
                         info!("Handler ended with {:?}", res);
                     });
                 }
                 Err(err) => {
                     warn!("Got error {:?}", err);
                 }
-            }
+            },
+            // forget about sessions which ended
+            Some(_) = sessions.join_next(), if !sessions.is_empty() => {}
+            _ = &mut shutdown => break,
         }
-    };
+    }
 
-    server.await
+    info!("Shutting down, ending {} sessions", sessions.len());
+    std::mem::drop(listener);
+    stop.send(()).ok();
+    while sessions.join_next().await.is_some() {}
+    Ok(())
 }
 
 #[cfg(test)]
@@ -456,10 +1196,10 @@ mod tests {
         util::tests::*,
     };
 
-    const SINGLE_DEVICE_BUSID: &str = "0-0-0";
+    const SINGLE_DEVICE_BUSID: &str = "0-1";
 
-    fn new_server_with_single_device() -> UsbIpServer {
-        UsbIpServer::new_simulated(vec![UsbDevice::new(0).with_interface(
+    fn single_device() -> UsbDevice {
+        UsbDevice::new(0).with_interface(
             ClassCode::CDC as u8,
             cdc::CDC_ACM_SUBCLASS,
             0x00,
@@ -468,7 +1208,11 @@ mod tests {
             Arc::new(Mutex::new(
                 Box::new(cdc::UsbCdcAcmHandler::new()) as Box<dyn UsbInterfaceHandler + Send>
             )),
-        )])
+        )
+    }
+
+    fn new_server_with_single_device() -> UsbIpServer {
+        UsbIpServer::new_simulated(vec![single_device()])
     }
 
     fn op_req_import(busid: &str) -> Vec<u8> {
@@ -544,13 +1288,15 @@ mod tests {
         tokio::spawn(server(addr, server_.clone()));
 
         let mut join_set = JoinSet::new();
-        let devices = (0..10).map(UsbDevice::new).collect::<Vec<_>>();
+        let devices = (0..10)
+            .map(|i| UsbDevice::new(i).with_bus_id(&format!("1-{}", i + 1)))
+            .collect::<Vec<_>>();
 
         for device in devices.iter() {
             let new_server = server_.clone();
             let new_device = device.clone();
             join_set.spawn(async move {
-                new_server.add_device(new_device).await;
+                new_server.add_device(new_device).await.unwrap();
             });
         }
 
@@ -569,6 +1315,54 @@ mod tests {
         assert_eq!(device_len, 0);
     }
 
+    #[tokio::test]
+    async fn bus_ids_allocated_and_unique() {
+        setup_test_logger();
+        let server = UsbIpServer::new_simulated(vec![
+            UsbDevice::new(0),
+            UsbDevice::new(1).with_bus_id("0-1"),
+            UsbDevice::new(2).with_bus_id("0-1"),
+        ]);
+        let devices = server.list_devices().await;
+        // the bus id chosen by the second device is not allocated to the first one, the third is ignored
+        assert_eq!(
+            devices
+                .iter()
+                .map(|d| d.bus_id.as_str())
+                .collect::<Vec<_>>(),
+            ["0-2", "0-1"]
+        );
+        assert_eq!(
+            server.available_devices.read().await[0].path,
+            "/sys/bus/usb/devices/0-2"
+        );
+
+        assert_eq!(server.add_device(UsbDevice::new(3)).await.unwrap(), "0-3");
+        assert!(matches!(
+            server
+                .add_device(UsbDevice::new(4).with_bus_id("0-3"))
+                .await,
+            Err(Error::DuplicateBusId(bus_id)) if bus_id == "0-3"
+        ));
+        server.remove_device("0-1").await.unwrap();
+        assert_eq!(server.add_device(UsbDevice::new(5)).await.unwrap(), "0-1");
+
+        assert!(matches!(
+            UsbIpServer::try_new_simulated(vec![
+                UsbDevice::new(0).with_bus_id("0-1"),
+                UsbDevice::new(1).with_bus_id("0-1"),
+            ]),
+            Err(Error::DuplicateBusId(bus_id)) if bus_id == "0-1"
+        ));
+        assert!(matches!(
+            UsbIpServer::builder()
+                .with_device(UsbDevice::new(0))
+                .with_device(UsbDevice::new(1))
+                .build(),
+            Ok(server) if server.available_devices.read().await.len() == 2
+        ));
+    }
+
     #[tokio::test]
     async fn send_usb_traffic_while_adding_and_removing_devices() {
         setup_test_logger();
@@ -612,14 +1406,16 @@ mod tests {
 
         let add_and_remove_device_handle = tokio::spawn(async move {
             let mut join_set = JoinSet::new();
-            let devices = (1..4).map(UsbDevice::new).collect::<Vec<_>>();
+            let devices = (1..4)
+                .map(|i| UsbDevice::new(i).with_bus_id(&format!("1-{}", i)))
+                .collect::<Vec<_>>();
 
             loop {
                 for device in devices.iter() {
                     let new_server = server_.clone();
                     let new_device = device.clone();
                     join_set.spawn(async move {
-                        new_server.add_device(new_device).await;
+                        new_server.add_device(new_device).await.unwrap();
                     });
                 }
 
@@ -655,16 +1451,691 @@ mod tests {
         assert_eq!(result, 0);
 
         let result = attach_device(&mut second_connection, SINGLE_DEVICE_BUSID).await;
-        assert_eq!(result, 1);
+        assert_eq!(result, usbip_protocol::ST_DEV_BUSY);
+        let result = attach_device(&mut second_connection, "9-9").await;
+        assert_eq!(result, usbip_protocol::ST_NODEV);
     }
 
-    #[tokio::test]
-    async fn device_gets_released_on_cmd_unlink() {
-        setup_test_logger();
-        let server_ = Arc::new(new_server_with_single_device());
+    /// A handler whose transfers never complete
+    struct PendingHandler {
+        cancelled: Arc<std::sync::atomic::AtomicUsize>,
+        transfers: Vec<oneshot::Sender<Result<Vec<u8>>>>,
+    }
 
-        let addr = get_free_address().await;
-        tokio::spawn(server(addr, server_.clone()));
+    impl UsbInterfaceHandler for PendingHandler {
+        fn get_class_specific_descriptor(&self) -> Vec<u8> {
+            vec![]
+        }
+
+        fn handle_urb(
+            &mut self,
+            _interface: &UsbInterface,
+            _ep: UsbEndpoint,
+            _transfer_buffer_length: u32,
+            _setup: SetupPacket,
+            _req: &[u8],
+        ) -> Result<Vec<u8>> {
+            unreachable!()
+        }
+
+        fn submit_urb(
+            &mut self,
+            _interface: &UsbInterface,
+            _ep: UsbEndpoint,
+            _transfer_buffer_length: u32,
+            _setup: SetupPacket,
+            _req: &[u8],
+        ) -> Option<PendingTransfer> {
+            let (sender, receiver) = oneshot::channel();
+            self.transfers.push(sender);
+            let cancelled = self.cancelled.clone();
+            Some(PendingTransfer::new(receiver).with_cancel(move || {
+                cancelled.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            }))
+        }
+
+        fn as_any(&mut self) -> &mut dyn Any {
+            self
+        }
+    }
+
+    fn cmd_submit(seqnum: u32, direction: u32, ep: u32, setup: [u8; 8]) -> Vec<u8> {
+        UsbIpCommand::UsbIpCmdSubmit {
+            header: UsbIpHeaderBasic {
+                command: USBIP_CMD_SUBMIT.into(),
+                seqnum,
+                devid: 0,
+                direction,
+                ep,
+            },
+            transfer_flags: 0,
+            transfer_buffer_length: 0x40,
+            start_frame: 0,
+            number_of_packets: 0,
+            interval: 0,
+            setup,
+            data: vec![],
+            iso_packet_descriptor: vec![],
+        }
+        .to_bytes()
+    }
+
+    fn cmd_unlink(seqnum: u32, unlink_seqnum: u32) -> Vec<u8> {
+        UsbIpCommand::UsbIpCmdUnlink {
+            header: UsbIpHeaderBasic {
+                command: USBIP_CMD_UNLINK.into(),
+                seqnum,
+                devid: 0,
+                direction: 0,
+                ep: 0,
+            },
+            unlink_seqnum,
+        }
+        .to_bytes()
+    }
+
+    #[tokio::test]
+    async fn cmd_unlink_cancels_pending_urb() {
+        setup_test_logger();
+        let cancelled = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let server = UsbIpServer::new_simulated(vec![UsbDevice::new(0).with_interface(
+            0xFF,
+            0x00,
+            0x00,
+            "Pending",
+            vec![UsbEndpoint {
+                address: 0x81,
+                attributes: EndpointAttributes::Interrupt as u8,
+                max_packet_size: 0x08,
+                interval: 10,
+                ..Default::default()
+            }],
+            Arc::new(Mutex::new(Box::new(PendingHandler {
+                cancelled: cancelled.clone(),
+                transfers: vec![],
+            })
+                as Box<dyn UsbInterfaceHandler + Send>)),
+        )]);
+
+        let mut req = op_req_import(SINGLE_DEVICE_BUSID);
+        // interrupt IN waiting for the device
+        req.extend(cmd_submit(1, 1, 1, [0; 8]));
+        // GetDescriptor to Device is not blocked by it
+        req.extend(cmd_submit(
+            2,
+            1,
+            0,
+            [0x80, 0x06, 0x00, 0x01, 0x00, 0x00, 0x40, 0x00],
+        ));
+        req.extend(cmd_unlink(3, 1));
+        // already completed
+        req.extend(cmd_unlink(4, 2));
+
+        let mut mock_socket = MockSocket::new(req);
+        handler(&mut mock_socket, Arc::new(server)).await.ok();
+
+        // OP_REQ_IMPORT + USBIP_RET_SUBMIT + Device Descriptor + 2 * USBIP_RET_UNLINK
+        let output = &mock_socket.output;
+        assert_eq!(output.len(), 0x140 + 0x30 + 0x12 + 2 * 0x30);
+        let ret_submit = &output[0x140..];
+        assert_eq!(ret_submit[4..8], 2u32.to_be_bytes()); // seqnum
+
+        let ret_unlink = &output[0x140 + 0x30 + 0x12..];
+        assert_eq!(ret_unlink[4..8], 3u32.to_be_bytes()); // seqnum
+        assert_eq!(
+            ret_unlink[20..24],
+            (-usbip_protocol::ECONNRESET).to_be_bytes()
+        ); // status
+        let ret_unlink = &ret_unlink[0x30..];
+        assert_eq!(ret_unlink[4..8], 4u32.to_be_bytes()); // seqnum
+        assert_eq!(ret_unlink[20..24], [0; 4]); // status
+
+        assert_eq!(cancelled.load(std::sync::atomic::Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn urb_status_from_errors() {
+        use usbip_protocol::*;
+        setup_test_logger();
+        assert_eq!(urb_status(&transfer_error(rusb::Error::Pipe)), -EPIPE);
+        assert_eq!(
+            urb_status(&transfer_error(rusb::Error::Timeout)),
+            -ETIMEDOUT
+        );
+        assert_eq!(urb_status(&transfer_error(rusb::Error::NoDevice)), -ENODEV);
+        assert_eq!(
+            urb_status(&transfer_error(rusb::Error::Overflow)),
+            -EOVERFLOW
+        );
+        assert_eq!(urb_status(&stall()), -EPIPE);
+        assert_eq!(
+            urb_status(&std::io::Error::new(ErrorKind::TimedOut, "timeout")),
+            -ETIMEDOUT
+        );
+        assert_eq!(urb_status(&std::io::Error::other("other")), -EPROTO);
+    }
+
+    /// A handler of a device which has been unplugged
+    struct UnpluggedHandler;
+
+    impl UsbInterfaceHandler for UnpluggedHandler {
+        fn get_class_specific_descriptor(&self) -> Vec<u8> {
+            vec![]
+        }
+
+        fn handle_urb(
+            &mut self,
+            _interface: &UsbInterface,
+            _ep: UsbEndpoint,
+            _transfer_buffer_length: u32,
+            _setup: SetupPacket,
+            _req: &[u8],
+        ) -> Result<Vec<u8>> {
+            Err(transfer_error(rusb::Error::NoDevice))
+        }
+
+        fn as_any(&mut self) -> &mut dyn Any {
+            self
+        }
+    }
+
+    #[tokio::test]
+    async fn unplugged_device_gets_detached() {
+        setup_test_logger();
+        let server = Arc::new(UsbIpServer::new_simulated(vec![UsbDevice::new(0)
+            .with_interface(
+                0xFF,
+                0x00,
+                0x00,
+                "Unplugged",
+                vec![UsbEndpoint {
+                    address: 0x81,
+                    attributes: EndpointAttributes::Bulk as u8,
+                    max_packet_size: 512,
+                    interval: 0,
+                    ..Default::default()
+                }],
+                Arc::new(Mutex::new(
+                    Box::new(UnpluggedHandler) as Box<dyn UsbInterfaceHandler + Send>
+                )),
+            )]));
+
+        let mut req = op_req_import(SINGLE_DEVICE_BUSID);
+        req.extend(cmd_submit(1, 1, 1, [0; 8]));
+        // not answered, the session is over
+        req.extend(UsbIpCommand::OpReqDevlist { status: 0 }.to_bytes());
+
+        let mut mock_socket = MockSocket::new(req);
+        handler(&mut mock_socket, server.clone()).await.ok();
+
+        // OP_REQ_IMPORT + USBIP_RET_SUBMIT
+        let output = &mock_socket.output;
+        assert_eq!(output.len(), 0x140 + 0x30);
+        assert_eq!(
+            output[0x140 + 20..0x140 + 24],
+            (-usbip_protocol::ENODEV).to_be_bytes()
+        ); // status
+
+        // given back until the host confirms that it is gone
+        assert_eq!(server.available_devices.read().await.len(), 1);
+        assert!(server.used_devices.read().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn host_passthrough_with_mock_backend() {
+        setup_test_logger();
+        let simulated = new_server_with_single_device()
+            .available_devices
+            .into_inner()
+            .remove(0);
+        let config = simulated.configuration_descriptor(DescriptorType::Configuration);
+        let device_descriptor = vec![
+            0x12, 0x01, 0x00, 0x02, 0x00, 0x00, 0x00, 0x40, 0x34, 0x12, 0x78, 0x56, 0x00, 0x01,
+            0x01, 0x00, 0x00, 0x01,
+        ];
+        let mock = MockUsbHostDevice::new(2, device_descriptor, config);
+        {
+            let mut state = mock.state.lock().unwrap();
+            state
+                .strings
+                .insert((LANGUAGE_ID_EN_US, 1), "Mock Inc".to_string());
+            state.kernel_drivers.push(0);
+            state.responses.insert(0x80, [Ok(vec![1, 2])].into());
+            state
+                .responses
+                .insert(0x82, [Err(rusb::Error::Pipe)].into());
+        }
+        let server = Arc::new(UsbIpServer::new_from_backend(
+            &MockUsbHost::new(vec![mock.clone()]),
+            |_| true,
+            Default::default(),
+        ));
+
+        {
+            let devices = server.available_devices.read().await;
+            let device = &devices[0];
+            assert_eq!(device.bus_id, "1-2");
+            assert_eq!(device.dev_num, 2);
+            assert_eq!(device.vendor_id, 0x1234);
+            assert_eq!(device.product_id, 0x5678);
+            assert_eq!(device.get_string(1, LANGUAGE_ID_EN_US), Some("Mock Inc"));
+            assert_eq!(device.interfaces.len(), simulated.interfaces.len());
+            for (intf, expected) in device.interfaces.iter().zip(&simulated.interfaces) {
+                let endpoints = |intf: &UsbInterface| {
+                    intf.endpoints
+                        .iter()
+                        .map(|ep| (ep.address, ep.attributes, ep.max_packet_size, ep.interval))
+                        .collect::<Vec<_>>()
+                };
+                assert_eq!(endpoints(intf), endpoints(expected));
+                assert_eq!(
+                    intf.class_specific_descriptor,
+                    expected.class_specific_descriptor
+                );
+            }
+        }
+
+        let mut req = op_req_import("1-2");
+        // vendor request to the device
+        req.extend(cmd_submit(
+            1,
+            1,
+            0,
+            [0xC0, 0x01, 0x00, 0x00, 0x00, 0x00, 0x02, 0x00],
+        ));
+        // stalled bulk in
+        req.extend(cmd_submit(2, 1, 2, [0; 8]));
+        let mut mock_socket = MockSocket::new(req);
+        handler(&mut mock_socket, server.clone()).await.ok();
+
+        let output = &mock_socket.output;
+        // OP_REP_IMPORT + USBIP_RET_SUBMIT with data + USBIP_RET_SUBMIT
+        assert_eq!(output.len(), 0x140 + 0x30 + 2 + 0x30);
+        assert_eq!(output[0x140 + 20..0x140 + 24], [0; 4]); // status
+        assert_eq!(output[0x140 + 0x30..0x140 + 0x32], [1, 2]); // data
+        let second = 0x140 + 0x32;
+        assert_eq!(
+            output[second + 20..second + 24],
+            (-usbip_protocol::EPIPE).to_be_bytes()
+        ); // status
+
+        let state = mock.state.lock().unwrap();
+        assert_eq!(state.control_requests[0].request_type, 0xC0);
+        // interfaces were claimed during the session, then given back to the host driver
+        assert!(state.claimed_interfaces.is_empty());
+        assert_eq!(state.kernel_drivers, [0]);
+    }
+
+    #[tokio::test]
+    async fn host_transfers_time_out() {
+        setup_test_logger();
+        let config = vec![
+            0x09, 0x02, 0x19, 0x00, 0x01, 0x01, 0x00, 0x80, 0x32, // configuration
+            0x09, 0x04, 0x00, 0x00, 0x01, 0xFF, 0x00, 0x00, 0x00, // vendor interface
+            0x07, 0x05, 0x81, 0x03, 0x08, 0x00, 0x0A, // interrupt in
+        ];
+        let device_descriptor = vec![
+            0x12, 0x01, 0x00, 0x02, 0x00, 0x00, 0x00, 0x40, 0x34, 0x12, 0x78, 0x56, 0x00, 0x01,
+            0x00, 0x00, 0x00, 0x01,
+        ];
+        let mock = MockUsbHostDevice::new(2, device_descriptor, config);
+        mock.state.lock().unwrap().unresponsive_endpoints.push(0x81);
+        let server = Arc::new(
+            UsbIpServer::builder()
+                .with_host_devices(MockUsbHost::new(vec![mock]), |_| true)
+                .with_transfer_timeout(std::time::Duration::from_millis(50))
+                .build()
+                .unwrap(),
+        );
+        let addr = get_free_address().await;
+        tokio::spawn(super::server(addr, server.clone()));
+        let mut connection = poll_connect(addr).await;
+        assert_eq!(attach_device(&mut connection, "1-2").await, 0);
+
+        connection
+            .write_all(&cmd_submit(1, 1, 1, [0; 8]))
+            .await
+            .unwrap();
+        let mut ret_submit = [0; 0x30];
+        connection.read_exact(&mut ret_submit).await.unwrap();
+        assert_eq!(ret_submit[4..8], 1u32.to_be_bytes()); // seqnum
+        assert_eq!(
+            ret_submit[20..24],
+            (-usbip_protocol::ETIMEDOUT).to_be_bytes()
+        ); // status
+    }
+
+    #[tokio::test]
+    async fn upstream_devices_reexported() {
+        setup_test_logger();
+        let mut cdc = cdc::UsbCdcAcmHandler::new();
+        cdc.tx_buffer = b"hello".to_vec();
+        let mut device = UsbDevice::new(0).with_interface(
+            ClassCode::CDC as u8,
+            cdc::CDC_ACM_SUBCLASS,
+            0x00,
+            "Test CDC ACM",
+            cdc::UsbCdcAcmHandler::endpoints(),
+            Arc::new(Mutex::new(
+                Box::new(cdc) as Box<dyn UsbInterfaceHandler + Send>
+            )),
+        );
+        device.vendor_id = 0x1234;
+        let expected = device.clone();
+        let upstream = Arc::new(UsbIpServer::new_simulated(vec![device]));
+        let addr = get_free_address().await;
+        // served by the same runtime, which the blocking calls to it do not stall
+        tokio::spawn(server(addr, upstream.clone()));
+        poll_connect(addr).await;
+
+        // listing does not import the devices
+        let backend = UsbIpBackend::new(addr);
+        let listed = tokio::task::spawn_blocking(move || backend.devices())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(listed.len(), 1);
+        let desc = listed[0].device_descriptor().unwrap();
+        assert_eq!(desc[8..10], 0x1234u16.to_le_bytes()); // idVendor
+        assert!(upstream.used_devices.read().await.is_empty());
+
+        let server = Arc::new(UsbIpServer::default());
+        let count = server
+            .add_upstream_devices(&UsbIpBackend::new(addr))
+            .await
+            .unwrap();
+        assert_eq!(count, 1);
+        {
+            let devices = server.available_devices.read().await;
+            let device = &devices[0];
+            assert_eq!(device.bus_id, SINGLE_DEVICE_BUSID);
+            assert_eq!(device.vendor_id, 0x1234);
+            assert_eq!(device.interfaces.len(), 1);
+            let intf = &device.interfaces[0];
+            assert_eq!(intf.interface_class, ClassCode::CDC as u8);
+            assert_eq!(
+                intf.endpoints
+                    .iter()
+                    .map(|ep| (ep.address, ep.attributes))
+                    .collect::<Vec<_>>(),
+                expected.interfaces[0]
+                    .endpoints
+                    .iter()
+                    .map(|ep| (ep.address, ep.attributes))
+                    .collect::<Vec<_>>()
+            );
+            assert_eq!(
+                device.get_string(intf.string_interface, LANGUAGE_ID_EN_US),
+                Some("Test CDC ACM")
+            );
+        }
+
+        // released upstream once the descriptors have been read
+        let released = || async {
+            for _ in 0..100 {
+                if upstream.used_devices.read().await.is_empty() {
+                    return true;
+                }
+                tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            }
+            false
+        };
+        assert!(released().await);
+
+        let addr = get_free_address().await;
+        tokio::spawn(super::server(addr, server.clone()));
+        let mut connection = poll_connect(addr).await;
+        assert_eq!(attach_device(&mut connection, SINGLE_DEVICE_BUSID).await, 0);
+        // bulk in, forwarded upstream
+        connection
+            .write_all(&cmd_submit(1, 1, 2, [0; 8]))
+            .await
+            .unwrap();
+        let mut ret_submit = [0; 0x30 + 5];
+        connection.read_exact(&mut ret_submit).await.unwrap();
+        assert_eq!(ret_submit[20..24], [0; 4]); // status
+        assert_eq!(&ret_submit[0x30..], b"hello");
+
+        // the upstream import ends with the session
+        std::mem::drop(connection);
+        assert!(released().await);
+    }
+
+    #[tokio::test]
+    async fn host_interfaces_selected_and_renumbered() {
+        setup_test_logger();
+        let config = vec![
+            0x09, 0x02, 0x3C, 0x00, 0x02, 0x01, 0x00, 0x80, 0x32, // configuration
+            0x09, 0x04, 0x00, 0x00, 0x01, 0x03, 0x01, 0x01, 0x00, // keyboard interface
+            0x09, 0x21, 0x11, 0x01, 0x00, 0x01, 0x22, 0x3F, 0x00, // HID
+            0x07, 0x05, 0x81, 0x03, 0x08, 0x00, 0x0A, // interrupt in
+            0x08, 0x0B, 0x01, 0x01, 0x0B, 0x00, 0x00, 0x00, // association of interface 1
+            0x09, 0x04, 0x01, 0x00, 0x02, 0x0B, 0x00, 0x00, 0x00, // smart card interface
+            0x07, 0x05, 0x82, 0x02, 0x40, 0x00, 0x00, // bulk in
+            0x07, 0x05, 0x02, 0x02, 0x40, 0x00, 0x00, // bulk out
+        ];
+        let device_descriptor = vec![
+            0x12, 0x01, 0x00, 0x02, 0x00, 0x00, 0x00, 0x40, 0x50, 0x10, 0x07, 0x04, 0x00, 0x01,
+            0x00, 0x00, 0x00, 0x01,
+        ];
+        let mock = MockUsbHostDevice::new(3, device_descriptor, config);
+        {
+            let mut state = mock.state.lock().unwrap();
+            // the keyboard stays with the host
+            state.kernel_drivers.push(0);
+            state.busy_interfaces.push(0);
+            state.responses.insert(0x80, [Ok(vec![0x55])].into());
+        }
+        let server = Arc::new(UsbIpServer::new_from_backend_with_interfaces(
+            &MockUsbHost::new(vec![mock.clone()]),
+            |_| true,
+            |_, intf| intf.class == 0x0B,
+            Default::default(),
+        ));
+
+        {
+            let devices = server.available_devices.read().await;
+            let interfaces = &devices[0].interfaces;
+            assert_eq!(interfaces.len(), 1);
+            assert_eq!(interfaces[0].interface_class, 0x0B);
+            assert!(interfaces[0].class_specific_descriptor.is_empty());
+            let addresses: Vec<u8> = interfaces[0]
+                .endpoints
+                .iter()
+                .map(|ep| ep.address)
+                .collect();
+            assert_eq!(addresses, [0x82, 0x02]);
+        }
+
+        {
+            let devices = server.available_devices.read().await;
+            let desc = devices[0].configuration_descriptor(DescriptorType::Configuration);
+            assert_eq!(desc.len(), 9 + 8 + 9 + 7 + 7);
+            assert_eq!(desc[4], 1); // bNumInterfaces
+            assert_eq!(desc[9..13], [0x08, 0x0B, 0x00, 0x01]); // association of interface 0
+            assert_eq!(desc[17..20], [0x09, 0x04, 0x00]); // interface 0
+        }
+
+        let mut req = op_req_import("1-3");
+        // class request to the exported interface 0
+        req.extend(cmd_submit(
+            1,
+            1,
+            0,
+            [0xA1, 0x01, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00],
+        ));
+        let mut mock_socket = MockSocket::new(req);
+        handler(&mut mock_socket, server.clone()).await.ok();
+
+        // OP_REP_IMPORT + USBIP_RET_SUBMIT with data
+        let output = &mock_socket.output;
+        assert_eq!(output.len(), 0x140 + 0x30 + 1);
+        assert_eq!(output[0x140 + 0x30], 0x55);
+        {
+            let state = mock.state.lock().unwrap();
+            // sent to interface 1 of the host
+            assert_eq!(state.control_requests[0].index, 1);
+            assert!(state.claimed_interfaces.is_empty());
+            assert_eq!(state.kernel_drivers, [0]);
+        }
+
+        // the builder exports the same interfaces, next to simulated devices
+        let server = UsbIpServer::builder()
+            .with_host_interfaces(
+                MockUsbHost::new(vec![mock]),
+                |_| true,
+                |_, intf| intf.class == 0x0B,
+            )
+            .with_device(UsbDevice::new(0))
+            .build()
+            .unwrap();
+        let devices = server.available_devices.read().await;
+        assert_eq!(devices.len(), 2);
+        assert_eq!(devices[0].interfaces.len(), 1);
+        assert_eq!(devices[0].interfaces[0].interface_class, 0x0B);
+    }
+
+    #[tokio::test]
+    async fn host_descriptors_mirrored() {
+        setup_test_logger();
+        let config = vec![
+            0x09, 0x02, 0x29, 0x00, 0x01, 0x01, 0x03, 0xA0, 0x32, // configuration
+            0x09, 0x04, 0x00, 0x00, 0x00, 0xFF, 0x00, 0x00, 0x04, // zero bandwidth setting
+            0x09, 0x04, 0x00, 0x01, 0x02, 0xFF, 0x00, 0x00, 0x04, // alternate setting
+            0x07, 0x05, 0x81, 0x23, 0x08, 0x00, 0x01, // interrupt in for notifications
+            0x07, 0x05, 0x02, 0x02, 0x00, 0x02, 0x00, // bulk out
+        ];
+        let device_descriptor = vec![
+            0x12, 0x01, 0x10, 0x02, 0x00, 0x00, 0x00, 0x40, 0x34, 0x12, 0x78, 0x56, 0x00, 0x01,
+            0x01, 0x02, 0x00, 0x01,
+        ];
+        let bos = vec![
+            0x05, 0x0F, 0x0C, 0x00, 0x01, 0x07, 0x10, 0x02, 0x02, 0x00, 0x00, 0x00,
+        ];
+        let mock = MockUsbHostDevice::new(1, device_descriptor, config.clone());
+        {
+            let mut state = mock.state.lock().unwrap();
+            state.strings.insert((0x0409, 1), "Maker".to_string());
+            state.strings.insert((0x0407, 1), "Hersteller".to_string());
+            state.strings.insert((0x0409, 3), "Config".to_string());
+            state.strings.insert((0x0409, 4), "Función".to_string());
+            // the product string 2 is broken
+            state.bos_descriptor = Some(bos.clone());
+        }
+        let server = UsbIpServer::new_from_backend(
+            &MockUsbHost::new(vec![mock]),
+            |_| true,
+            Default::default(),
+        );
+
+        let devices = server.available_devices.read().await;
+        let device = &devices[0];
+        assert_eq!(
+            device.configuration_descriptor(DescriptorType::Configuration),
+            config
+        );
+        assert_eq!(device.raw_bos, Some(bos));
+        // endpoints of the alternate setting, with their usage bits
+        let endpoints: Vec<(u8, u8)> = device.interfaces[0]
+            .endpoints
+            .iter()
+            .map(|ep| (ep.address, ep.attributes))
+            .collect();
+        assert_eq!(endpoints, [(0x81, 0x23), (0x02, 0x02)]);
+
+        assert_eq!(device.languages(), [0x0407, 0x0409]);
+        assert_eq!(device.get_string(1, 0x0409), Some("Maker"));
+        assert_eq!(device.get_string(1, 0x0407), Some("Hersteller"));
+        assert_eq!(device.get_string(4, 0x0409), Some("Función"));
+        assert_eq!(device.get_string(2, 0x0409), None);
+    }
+
+    /// A device handler counting attach and detach calls, refusing attach when `busy`
+    #[derive(Default)]
+    struct AttachHandler {
+        busy: bool,
+        attached: usize,
+        detached: usize,
+    }
+
+    impl UsbDeviceHandler for AttachHandler {
+        fn handle_urb(
+            &mut self,
+            _transfer_buffer_length: u32,
+            _setup: SetupPacket,
+            _req: &[u8],
+        ) -> Result<Vec<u8>> {
+            Ok(vec![])
+        }
+
+        fn attach(&mut self) -> Result<()> {
+            if self.busy {
+                return Err(std::io::Error::other("Interface 0 is busy"));
+            }
+            self.attached += 1;
+            Ok(())
+        }
+
+        fn detach(&mut self) -> Result<()> {
+            self.detached += 1;
+            Ok(())
+        }
+
+        fn as_any(&mut self) -> &mut dyn Any {
+            self
+        }
+    }
+
+    #[tokio::test]
+    async fn device_gets_attached_and_detached() {
+        setup_test_logger();
+        for busy in [false, true] {
+            let device_handler = Arc::new(Mutex::new(Box::new(AttachHandler {
+                busy,
+                ..Default::default()
+            })
+                as Box<dyn UsbDeviceHandler + Send>));
+            let mut device = UsbDevice::new(0);
+            device.device_handler = Some(device_handler.clone());
+            let server = Arc::new(UsbIpServer::new_simulated(vec![device]));
+
+            let mut mock_socket = MockSocket::new(op_req_import(SINGLE_DEVICE_BUSID));
+            handler(&mut mock_socket, server.clone()).await.ok();
+
+            assert_eq!(server.available_devices.read().await.len(), 1);
+            if busy {
+                let devices = server.available_devices.read().await;
+                assert!(matches!(devices[0].attach(), Err(Error::Handler(_))));
+            }
+
+            let mut device_handler = device_handler.lock().unwrap();
+            let device_handler = device_handler
+                .as_any()
+                .downcast_mut::<AttachHandler>()
+                .unwrap();
+            if busy {
+                // OP_REP_IMPORT without device
+                assert_eq!(mock_socket.output.len(), 8);
+                assert_eq!(
+                    mock_socket.output[4..8],
+                    usbip_protocol::ST_DEV_ERR.to_be_bytes()
+                );
+                assert_eq!(device_handler.attached, 0);
+            } else {
+                assert_eq!(mock_socket.output.len(), 0x140);
+                assert_eq!(device_handler.attached, 1);
+            }
+            assert_eq!(device_handler.detached, device_handler.attached);
+        }
+    }
+
+    #[tokio::test]
+    async fn device_gets_released_on_cmd_unlink() {
+        setup_test_logger();
+        let server_ = Arc::new(new_server_with_single_device());
+
+        let addr = get_free_address().await;
+        tokio::spawn(server(addr, server_.clone()));
 
         let mut connection = poll_connect(addr).await;
 
@@ -689,8 +2160,15 @@ mod tests {
         connection.read_exact(&mut [0; 4 * 6]).await.unwrap();
         assert_eq!(result, 0);
 
+        // the unlink only cancels URBs, importing again releases the device first
         let result = attach_device(&mut connection, SINGLE_DEVICE_BUSID).await;
         assert_eq!(result, 0);
+        assert!(server_
+            .used_devices
+            .read()
+            .await
+            .contains_key(SINGLE_DEVICE_BUSID));
+        assert!(server_.available_devices.read().await.is_empty());
     }
 
     #[tokio::test]
@@ -712,6 +2190,26 @@ mod tests {
         assert_eq!(result, 0);
     }
 
+    #[tokio::test]
+    async fn withdrawn_device_gets_detached() {
+        setup_test_logger();
+        let server_ = Arc::new(new_server_with_single_device());
+
+        let addr = get_free_address().await;
+        tokio::spawn(server(addr, server_.clone()));
+
+        let mut connection = poll_connect(addr).await;
+        let result = attach_device(&mut connection, SINGLE_DEVICE_BUSID).await;
+        assert_eq!(result, 0);
+        assert!(server_.has_device(SINGLE_DEVICE_BUSID).await);
+
+        server_.withdraw_device(SINGLE_DEVICE_BUSID).await;
+        // the session ends and the device is gone
+        let mut buf = [0; 1];
+        assert_eq!(connection.read(&mut buf).await.unwrap(), 0);
+        assert!(!server_.has_device(SINGLE_DEVICE_BUSID).await);
+    }
+
     #[tokio::test]
     async fn req_import_get_device_desc() {
         setup_test_logger();
@@ -745,4 +2243,505 @@ mod tests {
         // OP_REQ_IMPORT + USBIP_CMD_SUBMIT + Device Descriptor
         assert_eq!(mock_socket.output.len(), 0x140 + 0x30 + 0x12);
     }
+
+    #[tokio::test]
+    async fn shutdown_detaches_sessions() {
+        setup_test_logger();
+        let server = Arc::new(new_server_with_single_device());
+        let addr = get_free_address().await;
+        let (stop, stopped) = oneshot::channel::<()>();
+        let serving = tokio::spawn(server_with_shutdown(addr, server.clone(), async {
+            stopped.await.ok();
+        }));
+        let mut connection = poll_connect(addr).await;
+        assert_eq!(attach_device(&mut connection, SINGLE_DEVICE_BUSID).await, 0);
+        assert!(server.available_devices.read().await.is_empty());
+
+        stop.send(()).unwrap();
+        serving.await.unwrap().unwrap();
+        // the session was closed, and its device given back
+        assert_eq!(connection.read(&mut [0; 1]).await.unwrap(), 0);
+        assert!(server.used_devices.read().await.is_empty());
+        assert_eq!(server.available_devices.read().await.len(), 1);
+        assert!(TcpStream::connect(addr).await.is_err());
+
+        // served again afterwards
+        let addr = get_free_address().await;
+        tokio::spawn(super::server(addr, server.clone()));
+        let mut connection = poll_connect(addr).await;
+        assert_eq!(attach_device(&mut connection, SINGLE_DEVICE_BUSID).await, 0);
+        tokio::task::yield_now().await;
+        assert_eq!(server.used_devices.read().await.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn sessions_listed_and_detached() {
+        setup_test_logger();
+        let server = Arc::new(new_server_with_single_device());
+        let addr = get_free_address().await;
+        tokio::spawn(super::server(addr, server.clone()));
+        let mut connection = poll_connect(addr).await;
+        let before = SystemTime::now();
+        assert_eq!(attach_device(&mut connection, SINGLE_DEVICE_BUSID).await, 0);
+
+        let devices = server.list_devices().await;
+        assert_eq!(devices.len(), 1);
+        assert_eq!(devices[0].bus_id, SINGLE_DEVICE_BUSID);
+        let session = devices[0].session.as_ref().unwrap();
+        assert_eq!(session.peer, Some(connection.local_addr().unwrap()));
+        assert!(session.attached_at >= before);
+        assert!(matches!(
+            server.remove_device(SINGLE_DEVICE_BUSID).await,
+            Err(Error::DeviceBusy(_))
+        ));
+
+        server.detach_device(SINGLE_DEVICE_BUSID).await.unwrap();
+        assert_eq!(connection.read(&mut [0; 1]).await.unwrap(), 0);
+        assert_eq!(server.list_devices().await[0].session, None);
+        assert!(matches!(
+            server.detach_device(SINGLE_DEVICE_BUSID).await,
+            Err(Error::DeviceNotAttached(_))
+        ));
+        server.remove_device(SINGLE_DEVICE_BUSID).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn device_replugged_with_new_descriptors() {
+        setup_test_logger();
+        let cancelled = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let pending = Arc::new(Mutex::new(Box::new(PendingHandler {
+            cancelled: cancelled.clone(),
+            transfers: vec![],
+        })
+            as Box<dyn UsbInterfaceHandler + Send>));
+        let server = Arc::new(UsbIpServer::new_simulated(vec![UsbDevice::new(0)
+            .with_interface(
+                0xFF,
+                0x00,
+                0x00,
+                "Pending",
+                vec![UsbEndpoint {
+                    address: 0x81,
+                    attributes: EndpointAttributes::Interrupt as u8,
+                    max_packet_size: 0x08,
+                    interval: 10,
+                    ..Default::default()
+                }],
+                pending.clone(),
+            )]));
+        let addr = get_free_address().await;
+        tokio::spawn(super::server(addr, server.clone()));
+        let mut connection = poll_connect(addr).await;
+        assert_eq!(attach_device(&mut connection, SINGLE_DEVICE_BUSID).await, 0);
+        // interrupt IN waiting for the device
+        connection
+            .write_all(&cmd_submit(1, 1, 1, [0; 8]))
+            .await
+            .unwrap();
+        let submitted = || {
+            let mut handler = pending.lock().unwrap();
+            let handler = handler.as_any().downcast_mut::<PendingHandler>().unwrap();
+            !handler.transfers.is_empty()
+        };
+        while !submitted() {
+            tokio::task::yield_now().await;
+        }
+
+        let replug = tokio::spawn({
+            let server = server.clone();
+            async move {
+                server
+                    .replug_device(
+                        SINGLE_DEVICE_BUSID,
+                        std::time::Duration::from_millis(10),
+                        |dev| dev.product_id = 0xDF11,
+                    )
+                    .await
+            }
+        });
+        // the pending URB fails, then the connection is closed
+        let mut ret_submit = [0; 0x30];
+        connection.read_exact(&mut ret_submit).await.unwrap();
+        assert_eq!(ret_submit[4..8], 1u32.to_be_bytes()); // seqnum
+        assert_eq!(ret_submit[20..24], (-usbip_protocol::ENODEV).to_be_bytes()); // status
+        assert_eq!(connection.read(&mut [0; 1]).await.unwrap(), 0);
+        assert_eq!(cancelled.load(std::sync::atomic::Ordering::SeqCst), 1);
+
+        replug.await.unwrap().unwrap();
+        let devices = server.list_devices().await;
+        assert_eq!(devices.len(), 1);
+        assert_eq!(devices[0].product_id, 0xDF11);
+        assert_eq!(devices[0].session, None);
+        let mut connection = poll_connect(addr).await;
+        assert_eq!(attach_device(&mut connection, SINGLE_DEVICE_BUSID).await, 0);
+
+        assert!(matches!(
+            server.unplug_device("1-9").await,
+            Err(Error::DeviceNotFound(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn sessions_detached_when_lease_expires() {
+        setup_test_logger();
+        let server = Arc::new(new_server_with_single_device());
+        let mut events = server.lease_events();
+        let addr = get_free_address().await;
+        tokio::spawn(super::server(addr, server.clone()));
+
+        // idle, as no URB is submitted
+        server
+            .set_lease_policy(
+                SINGLE_DEVICE_BUSID,
+                LeasePolicy {
+                    idle_timeout: Some(std::time::Duration::from_millis(50)),
+                    ..Default::default()
+                },
+            )
+            .await;
+        let mut connection = poll_connect(addr).await;
+        assert_eq!(attach_device(&mut connection, SINGLE_DEVICE_BUSID).await, 0);
+        assert_eq!(connection.read(&mut [0; 1]).await.unwrap(), 0);
+        let event = events.recv().await.unwrap();
+        assert_eq!(event.bus_id, SINGLE_DEVICE_BUSID);
+        assert_eq!(event.peer, Some(connection.local_addr().unwrap()));
+        assert_eq!(event.expiry, LeaseExpiry::Idle);
+        assert_eq!(server.list_devices().await[0].session, None);
+
+        // applies to the current session as well
+        server.clear_lease_policy(SINGLE_DEVICE_BUSID).await;
+        let mut connection = poll_connect(addr).await;
+        assert_eq!(attach_device(&mut connection, SINGLE_DEVICE_BUSID).await, 0);
+        server
+            .set_lease_policy(
+                SINGLE_DEVICE_BUSID,
+                LeasePolicy {
+                    max_duration: Some(std::time::Duration::from_millis(10)),
+                    ..Default::default()
+                },
+            )
+            .await;
+        assert_eq!(connection.read(&mut [0; 1]).await.unwrap(), 0);
+        assert_eq!(
+            events.recv().await.unwrap().expiry,
+            LeaseExpiry::MaxDuration
+        );
+    }
+
+    #[tokio::test]
+    async fn idle_lease_waits_for_pending_urbs() {
+        setup_test_logger();
+        let timeout = std::time::Duration::from_millis(100);
+        let pending = Arc::new(Mutex::new(Box::new(PendingHandler {
+            cancelled: Default::default(),
+            transfers: vec![],
+        })
+            as Box<dyn UsbInterfaceHandler + Send>));
+        let server = Arc::new(UsbIpServer::new_simulated(vec![UsbDevice::new(0)
+            .with_interface(
+                0xFF,
+                0x00,
+                0x00,
+                "Pending",
+                vec![UsbEndpoint {
+                    address: 0x81,
+                    attributes: EndpointAttributes::Interrupt as u8,
+                    max_packet_size: 0x08,
+                    interval: 10,
+                    ..Default::default()
+                }],
+                pending.clone(),
+            )]));
+        server
+            .set_lease_policy(
+                SINGLE_DEVICE_BUSID,
+                LeasePolicy {
+                    idle_timeout: Some(timeout),
+                    ..Default::default()
+                },
+            )
+            .await;
+        let mut events = server.lease_events();
+        let addr = get_free_address().await;
+        tokio::spawn(super::server(addr, server.clone()));
+        let mut connection = poll_connect(addr).await;
+        assert_eq!(attach_device(&mut connection, SINGLE_DEVICE_BUSID).await, 0);
+        connection
+            .write_all(&cmd_submit(1, 1, 1, [0; 8]))
+            .await
+            .unwrap();
+
+        // still attached while the URB is pending
+        assert!(
+            tokio::time::timeout(timeout * 3, connection.read(&mut [0; 1]))
+                .await
+                .is_err()
+        );
+        assert!(events.try_recv().is_err());
+        let transfer = {
+            let mut handler = pending.lock().unwrap();
+            let handler = handler.as_any().downcast_mut::<PendingHandler>().unwrap();
+            handler.transfers.pop().unwrap()
+        };
+        transfer.send(Ok(vec![0x55])).unwrap();
+        let start = std::time::Instant::now();
+        let mut ret_submit = [0; 0x30 + 1];
+        connection.read_exact(&mut ret_submit).await.unwrap();
+
+        // idle from the completion on
+        assert_eq!(connection.read(&mut [0; 1]).await.unwrap(), 0);
+        assert!(start.elapsed() >= timeout);
+        assert_eq!(events.recv().await.unwrap().expiry, LeaseExpiry::Idle);
+    }
+
+    #[tokio::test]
+    async fn reserved_devices_imported_by_their_client_only() {
+        setup_test_logger();
+        let server = Arc::new(new_server_with_single_device());
+        let mut events = server.lease_events();
+        let addr = get_free_address().await;
+        tokio::spawn(super::server(addr, server.clone()));
+        let now = SystemTime::now();
+        let reservation = |peer: &str, end: std::time::Duration| LeasePolicy {
+            reservations: vec![Reservation {
+                peer: peer.parse().unwrap(),
+                start: now - std::time::Duration::from_secs(1),
+                end: now + end,
+            }],
+            ..Default::default()
+        };
+
+        server
+            .set_lease_policy(
+                SINGLE_DEVICE_BUSID,
+                reservation("10.0.0.1", std::time::Duration::from_secs(3600)),
+            )
+            .await;
+        let mut connection = poll_connect(addr).await;
+        assert_eq!(
+            attach_device(&mut connection, SINGLE_DEVICE_BUSID).await,
+            usbip_protocol::ST_NA
+        );
+
+        server
+            .set_lease_policy(
+                SINGLE_DEVICE_BUSID,
+                reservation("127.0.0.1", std::time::Duration::from_millis(100)),
+            )
+            .await;
+        let mut connection = poll_connect(addr).await;
+        assert_eq!(attach_device(&mut connection, SINGLE_DEVICE_BUSID).await, 0);
+        assert_eq!(connection.read(&mut [0; 1]).await.unwrap(), 0);
+        assert_eq!(
+            events.recv().await.unwrap().expiry,
+            LeaseExpiry::ReservationEnded
+        );
+    }
+
+    #[tokio::test]
+    async fn connections_limited_and_timed_out() {
+        setup_test_logger();
+        let timeout = std::time::Duration::from_millis(100);
+        let server = Arc::new(
+            UsbIpServer::builder()
+                .with_device(single_device())
+                .with_max_connections(1)
+                .with_handshake_timeout(timeout)
+                .with_tcp_nodelay(true)
+                .with_tcp_keepalive(std::time::Duration::from_secs(60))
+                .build()
+                .unwrap(),
+        );
+        let addr = get_free_address().await;
+        tokio::spawn(super::server(addr, server.clone()));
+        let mut connection = poll_connect(addr).await;
+        assert_eq!(attach_device(&mut connection, SINGLE_DEVICE_BUSID).await, 0);
+
+        // refused
+        let mut refused = TcpStream::connect(addr).await.unwrap();
+        assert_eq!(refused.read(&mut [0; 1]).await.unwrap(), 0);
+
+        // past the handshake, which ended with the import
+        tokio::time::sleep(2 * timeout).await;
+        let req = UsbIpCommand::OpReqDevlist { status: 0 };
+        connection.write_all(&req.to_bytes()).await.unwrap();
+        assert_eq!(connection.read_u32().await.unwrap(), 0x01110005);
+        std::mem::drop(connection);
+        while server.connections.load(std::sync::atomic::Ordering::SeqCst) > 0 {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+
+        let start = std::time::Instant::now();
+        let mut silent = TcpStream::connect(addr).await.unwrap();
+        assert_eq!(silent.read(&mut [0; 1]).await.unwrap(), 0);
+        assert!(start.elapsed() >= timeout);
+    }
+
+    #[tokio::test]
+    async fn idle_connections_closed() {
+        setup_test_logger();
+        let timeout = std::time::Duration::from_millis(100);
+        let server = Arc::new(
+            UsbIpServer::builder()
+                .with_device(single_device())
+                .with_idle_timeout(timeout)
+                .build()
+                .unwrap(),
+        );
+        let addr = get_free_address().await;
+        tokio::spawn(super::server(addr, server.clone()));
+        let mut connection = poll_connect(addr).await;
+        assert_eq!(attach_device(&mut connection, SINGLE_DEVICE_BUSID).await, 0);
+
+        let start = std::time::Instant::now();
+        assert_eq!(connection.read(&mut [0; 1]).await.unwrap(), 0);
+        assert!(start.elapsed() >= timeout);
+        // the device is given back
+        while server.list_devices().await[0].session.is_some() {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn idle_timeout_waits_for_pending_urbs() {
+        setup_test_logger();
+        let timeout = std::time::Duration::from_millis(100);
+        let pending = Arc::new(Mutex::new(Box::new(PendingHandler {
+            cancelled: Default::default(),
+            transfers: vec![],
+        })
+            as Box<dyn UsbInterfaceHandler + Send>));
+        let server = Arc::new(
+            UsbIpServer::builder()
+                .with_device(UsbDevice::new(0).with_interface(
+                    0xFF,
+                    0x00,
+                    0x00,
+                    "Pending",
+                    vec![UsbEndpoint {
+                        address: 0x81,
+                        attributes: EndpointAttributes::Interrupt as u8,
+                        max_packet_size: 0x08,
+                        interval: 10,
+                        ..Default::default()
+                    }],
+                    pending.clone(),
+                ))
+                .with_idle_timeout(timeout)
+                .build()
+                .unwrap(),
+        );
+        let addr = get_free_address().await;
+        tokio::spawn(super::server(addr, server.clone()));
+        let mut connection = poll_connect(addr).await;
+        assert_eq!(attach_device(&mut connection, SINGLE_DEVICE_BUSID).await, 0);
+        connection
+            .write_all(&cmd_submit(1, 1, 1, [0; 8]))
+            .await
+            .unwrap();
+
+        // still open while the URB is pending
+        assert!(
+            tokio::time::timeout(timeout * 3, connection.read(&mut [0; 1]))
+                .await
+                .is_err()
+        );
+        let transfer = {
+            let mut handler = pending.lock().unwrap();
+            let handler = handler.as_any().downcast_mut::<PendingHandler>().unwrap();
+            handler.transfers.pop().unwrap()
+        };
+        transfer.send(Ok(vec![0x55])).unwrap();
+        let start = std::time::Instant::now();
+        let mut ret_submit = [0; 0x30 + 1];
+        connection.read_exact(&mut ret_submit).await.unwrap();
+        assert_eq!(ret_submit[0x30], 0x55);
+
+        // idle from the completion on
+        assert_eq!(connection.read(&mut [0; 1]).await.unwrap(), 0);
+        assert!(start.elapsed() >= timeout);
+    }
+
+    #[tokio::test]
+    async fn in_flight_urbs_and_transfer_size_limited() {
+        setup_test_logger();
+        let server = UsbIpServer::builder()
+            .with_device(UsbDevice::new(0).with_interface(
+                0xFF,
+                0x00,
+                0x00,
+                "Pending",
+                vec![UsbEndpoint {
+                    address: 0x81,
+                    attributes: EndpointAttributes::Interrupt as u8,
+                    max_packet_size: 0x08,
+                    interval: 10,
+                    ..Default::default()
+                }],
+                Arc::new(Mutex::new(Box::new(PendingHandler {
+                    cancelled: Default::default(),
+                    transfers: vec![],
+                })
+                    as Box<dyn UsbInterfaceHandler + Send>)),
+            ))
+            .with_max_in_flight_urbs(1)
+            .with_max_transfer_size(0x40)
+            .build()
+            .unwrap();
+
+        let mut req = op_req_import(SINGLE_DEVICE_BUSID);
+        req.extend(cmd_submit(1, 1, 1, [0; 8]));
+        // beyond the URB pending
+        req.extend(cmd_submit(2, 1, 1, [0; 8]));
+        let mut too_large = cmd_submit(3, 1, 1, [0; 8]);
+        too_large[24..28].copy_from_slice(&0x41u32.to_be_bytes()); // transfer_buffer_length
+        req.extend(too_large);
+
+        let mut mock_socket = MockSocket::new(req);
+        let err = handler(&mut mock_socket, Arc::new(server))
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            Error::from(err),
+            Error::LimitExceeded {
+                value: 0x41,
+                limit: 0x40,
+                ..
+            }
+        ));
+
+        // OP_REQ_IMPORT + failed USBIP_RET_SUBMIT
+        let output = &mock_socket.output;
+        assert_eq!(output.len(), 0x140 + 0x30);
+        let ret_submit = &output[0x140..];
+        assert_eq!(ret_submit[4..8], 2u32.to_be_bytes()); // seqnum
+        assert_eq!(ret_submit[20..24], (-usbip_protocol::EBUSY).to_be_bytes()); // status
+    }
+
+    #[tokio::test]
+    async fn failed_devlist_reported() {
+        setup_test_logger();
+        let addr = get_free_address().await;
+        tokio::spawn(async move {
+            let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
+            loop {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                // connections probing the listener send nothing
+                if socket.read_exact(&mut [0; 8]).await.is_ok() {
+                    let reply = UsbIpResponse::op_rep_devlist_fail(usbip_protocol::ST_ERROR);
+                    socket.write_all(&reply.to_bytes()).await.unwrap();
+                }
+            }
+        });
+        std::mem::drop(poll_connect(addr).await);
+
+        // the backend blocks until the reply
+        let backend = UsbIpBackend::new(addr);
+        let err = tokio::task::spawn_blocking(move || backend.list())
+            .await
+            .unwrap()
+            .err()
+            .unwrap();
+        assert_eq!(err.to_string(), "Unexpected response (status 5)");
+    }
 }
@@ -0,0 +1,417 @@
+//! Implement Microsoft OS 2.0 and legacy 1.0 descriptors
+use super::*;
+
+// reference:
+// Microsoft OS 2.0 Descriptors Specification
+// Microsoft OS 1.0 Descriptors Specification: Extended Compat ID OS Feature Descriptor
+
+/// PlatformCapabilityUUID of Microsoft OS 2.0: {D8DD60DF-4589-4CC7-9CD2-659D9E648A9F}
+pub const MS_OS_20_UUID: [u8; 16] = [
+    0xDF, 0x60, 0xDD, 0xD8, 0x89, 0x45, 0xC7, 0x4C, 0x9C, 0xD2, 0x65, 0x9D, 0x9E, 0x64, 0x8A, 0x9F,
+];
+
+/// wIndex of the vendor request retrieving the MS OS 2.0 descriptor set
+pub const MS_OS_20_DESCRIPTOR_INDEX: u16 = 0x07;
+
+/// dwWindowsVersion of Windows 8.1, the first version supporting MS OS 2.0 descriptors
+pub const WINDOWS_VERSION_8_1: u32 = 0x06030000;
+
+/// String descriptor index of the MS OS 1.0 string descriptor
+pub const MS_OS_10_STRING_INDEX: u8 = 0xEE;
+
+/// wIndex of the vendor request retrieving the MS OS 1.0 extended compat ID descriptor
+pub const MS_OS_10_EXTENDED_COMPAT_ID_INDEX: u16 = 0x04;
+
+/// A list of MS OS 2.0 descriptor types
+/// from Microsoft OS 2.0 Descriptors Specification Table 9
+#[derive(Copy, Clone, Debug, FromPrimitive)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum MsOs20DescriptorType {
+    SetHeader = 0x00,
+    SubsetHeaderConfiguration = 0x01,
+    SubsetHeaderFunction = 0x02,
+    FeatureCompatibleId = 0x03,
+    FeatureRegProperty = 0x04,
+    FeatureMinResumeTime = 0x05,
+    FeatureModelId = 0x06,
+    FeatureCcgpDevice = 0x07,
+    FeatureVendorRevision = 0x08,
+}
+
+/// Value of a registry property
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum RegistryValue {
+    String(String),
+    ExpandString(String),
+    Binary(Vec<u8>),
+    DwordLittleEndian(u32),
+    DwordBigEndian(u32),
+    Link(String),
+    MultiString(Vec<String>),
+}
+
+fn utf16z(s: &str) -> Vec<u8> {
+    s.encode_utf16()
+        .chain(std::iter::once(0))
+        .flat_map(|c| c.to_le_bytes())
+        .collect()
+}
+
+impl RegistryValue {
+    /// wPropertyDataType
+    fn data_type(&self) -> u16 {
+        match self {
+            Self::String(_) => 1,
+            Self::ExpandString(_) => 2,
+            Self::Binary(_) => 3,
+            Self::DwordLittleEndian(_) => 4,
+            Self::DwordBigEndian(_) => 5,
+            Self::Link(_) => 6,
+            Self::MultiString(_) => 7,
+        }
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        match self {
+            Self::String(s) | Self::ExpandString(s) | Self::Link(s) => utf16z(s),
+            Self::Binary(b) => b.clone(),
+            Self::DwordLittleEndian(v) => v.to_le_bytes().to_vec(),
+            Self::DwordBigEndian(v) => v.to_be_bytes().to_vec(),
+            Self::MultiString(strings) => {
+                let mut result: Vec<u8> = strings.iter().flat_map(|s| utf16z(s)).collect();
+                result.extend_from_slice(&[0, 0]);
+                result
+            }
+        }
+    }
+}
+
+/// A MS OS 2.0 feature descriptor
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum MsOs20Feature {
+    /// Compatible ID, e.g. `WINUSB`
+    CompatibleId {
+        compatible_id: String,
+        sub_compatible_id: String,
+    },
+    /// Registry property, e.g. `DeviceInterfaceGUIDs`
+    RegistryProperty { name: String, value: RegistryValue },
+}
+
+impl MsOs20Feature {
+    /// Compatible ID `WINUSB`, binding the device or function to WinUSB
+    pub fn winusb() -> Self {
+        Self::CompatibleId {
+            compatible_id: "WINUSB".to_string(),
+            sub_compatible_id: String::new(),
+        }
+    }
+
+    /// Registry property `DeviceInterfaceGUIDs`, e.g. `{xxxxxxxx-xxxx-xxxx-xxxx-xxxxxxxxxxxx}`
+    pub fn device_interface_guid(guid: &str) -> Self {
+        Self::RegistryProperty {
+            name: "DeviceInterfaceGUIDs".to_string(),
+            value: RegistryValue::MultiString(vec![guid.to_string()]),
+        }
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut desc = vec![0x00, 0x00]; // wLength: to be filled below
+        match self {
+            Self::CompatibleId {
+                compatible_id,
+                sub_compatible_id,
+            } => {
+                desc.extend_from_slice(
+                    &(MsOs20DescriptorType::FeatureCompatibleId as u16).to_le_bytes(),
+                );
+                desc.extend_from_slice(&padded_id(compatible_id)); // CompatibleID
+                desc.extend_from_slice(&padded_id(sub_compatible_id)); // SubCompatibleID
+            }
+            Self::RegistryProperty { name, value } => {
+                let name = utf16z(name);
+                let data = value.to_bytes();
+                desc.extend_from_slice(
+                    &(MsOs20DescriptorType::FeatureRegProperty as u16).to_le_bytes(),
+                );
+                desc.extend_from_slice(&value.data_type().to_le_bytes()); // wPropertyDataType
+                desc.extend_from_slice(&(name.len() as u16).to_le_bytes()); // wPropertyNameLength
+                desc.extend_from_slice(&name); // PropertyName
+                desc.extend_from_slice(&(data.len() as u16).to_le_bytes()); // wPropertyDataLength
+                desc.extend_from_slice(&data); // PropertyData
+            }
+        }
+        let len = desc.len() as u16;
+        desc[0..2].copy_from_slice(&len.to_le_bytes());
+        desc
+    }
+}
+
+fn padded_id(id: &str) -> [u8; 8] {
+    let mut result = [0u8; 8];
+    let len = id.len().min(8);
+    result[..len].copy_from_slice(&id.as_bytes()[..len]);
+    result
+}
+
+/// Features applying to a single function of a composite device
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct MsOs20Function {
+    /// bFirstInterface of the function
+    pub first_interface: u8,
+    pub features: Vec<MsOs20Feature>,
+}
+
+/// A MS OS 2.0 descriptor set
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct MsOs20DescriptorSet {
+    /// dwWindowsVersion
+    pub windows_version: u32,
+    /// bMS_VendorCode used to retrieve the descriptor set
+    pub vendor_code: u8,
+    /// Features applying to the whole device
+    pub features: Vec<MsOs20Feature>,
+    /// Features applying to functions of a composite device
+    pub functions: Vec<MsOs20Function>,
+    /// Also answer the legacy MS OS 1.0 string descriptor and extended compat ID requests
+    pub legacy_ms_os_10: bool,
+}
+
+impl MsOs20DescriptorSet {
+    /// An empty descriptor set
+    pub fn new(vendor_code: u8) -> Self {
+        Self {
+            windows_version: WINDOWS_VERSION_8_1,
+            vendor_code,
+            features: vec![],
+            functions: vec![],
+            legacy_ms_os_10: false,
+        }
+    }
+
+    /// A descriptor set binding the whole device to WinUSB
+    pub fn winusb(vendor_code: u8) -> Self {
+        Self::new(vendor_code).with_feature(MsOs20Feature::winusb())
+    }
+
+    pub fn with_feature(mut self, feature: MsOs20Feature) -> Self {
+        self.features.push(feature);
+        self
+    }
+
+    pub fn with_function(mut self, first_interface: u8, features: Vec<MsOs20Feature>) -> Self {
+        self.functions.push(MsOs20Function {
+            first_interface,
+            features,
+        });
+        self
+    }
+
+    /// Enable the legacy MS OS 1.0 descriptors for older Windows versions
+    pub fn with_legacy_ms_os_10(mut self) -> Self {
+        self.legacy_ms_os_10 = true;
+        self
+    }
+
+    /// Converts into the descriptor set returned by the vendor request
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut desc = vec![
+            0x0A, 0x00, // wLength
+        ];
+        desc.extend_from_slice(&(MsOs20DescriptorType::SetHeader as u16).to_le_bytes());
+        desc.extend_from_slice(&self.windows_version.to_le_bytes()); // dwWindowsVersion
+        desc.extend_from_slice(&[0x00, 0x00]); // wTotalLength: to be filled below
+        for feature in &self.features {
+            desc.extend(feature.to_bytes());
+        }
+
+        if !self.functions.is_empty() {
+            // function subsets must be contained in a configuration subset
+            let mut config = vec![
+                0x08, 0x00, // wLength
+            ];
+            config.extend_from_slice(
+                &(MsOs20DescriptorType::SubsetHeaderConfiguration as u16).to_le_bytes(),
+            );
+            config.push(0x00); // bConfigurationValue: index of the first configuration
+            config.push(0x00); // bReserved
+            config.extend_from_slice(&[0x00, 0x00]); // wTotalLength: to be filled below
+            for function in &self.functions {
+                let mut subset = vec![
+                    0x08, 0x00, // wLength
+                ];
+                subset.extend_from_slice(
+                    &(MsOs20DescriptorType::SubsetHeaderFunction as u16).to_le_bytes(),
+                );
+                subset.push(function.first_interface); // bFirstInterface
+                subset.push(0x00); // bReserved
+                subset.extend_from_slice(&[0x00, 0x00]); // wSubsetLength: to be filled below
+                for feature in &function.features {
+                    subset.extend(feature.to_bytes());
+                }
+                let len = subset.len() as u16;
+                subset[6..8].copy_from_slice(&len.to_le_bytes());
+                config.extend(subset);
+            }
+            let len = config.len() as u16;
+            config[6..8].copy_from_slice(&len.to_le_bytes());
+            desc.extend(config);
+        }
+
+        let len = desc.len() as u16;
+        desc[8..10].copy_from_slice(&len.to_le_bytes());
+        desc
+    }
+
+    /// The platform capability to put in the BOS descriptor
+    pub fn capability(&self) -> BosCapability {
+        let mut data = self.windows_version.to_le_bytes().to_vec(); // dwWindowsVersion
+        data.extend_from_slice(&(self.to_bytes().len() as u16).to_le_bytes()); // wMSOSDescriptorSetTotalLength
+        data.push(self.vendor_code); // bMS_VendorCode
+        data.push(0x00); // bAltEnumCode
+        BosCapability::Platform {
+            uuid: MS_OS_20_UUID,
+            data,
+        }
+    }
+
+    /// The MS OS 1.0 string descriptor at index 0xEE
+    pub fn ms_os_10_string_descriptor(&self) -> Vec<u8> {
+        let mut desc = vec![
+            0x12,                         // bLength
+            DescriptorType::String as u8, // bDescriptorType
+        ];
+        for c in "MSFT100".encode_utf16() {
+            desc.extend_from_slice(&c.to_le_bytes()); // qwSignature
+        }
+        desc.push(self.vendor_code); // bMS_VendorCode
+        desc.push(0x00); // bPad
+        desc
+    }
+
+    /// The MS OS 1.0 extended compat ID descriptor, derived from the compatible ID features
+    pub fn ms_os_10_extended_compat_id(&self) -> Vec<u8> {
+        let compat_ids = self
+            .features
+            .iter()
+            .map(|f| (0, f))
+            .chain(
+                self.functions
+                    .iter()
+                    .flat_map(|func| func.features.iter().map(|f| (func.first_interface, f))),
+            )
+            .filter_map(|(interface, feature)| match feature {
+                MsOs20Feature::CompatibleId {
+                    compatible_id,
+                    sub_compatible_id,
+                } => Some((interface, compatible_id, sub_compatible_id)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+
+        let len = 16 + 24 * compat_ids.len() as u32;
+        let mut desc = len.to_le_bytes().to_vec(); // dwLength
+        desc.extend_from_slice(&[0x00, 0x01]); // bcdVersion 1.0
+        desc.extend_from_slice(&MS_OS_10_EXTENDED_COMPAT_ID_INDEX.to_le_bytes()); // wIndex
+        desc.push(compat_ids.len() as u8); // bCount
+        desc.extend_from_slice(&[0; 7]); // reserved
+        for (interface, compatible_id, sub_compatible_id) in compat_ids {
+            desc.push(interface); // bFirstInterfaceNumber
+            desc.push(0x01); // reserved
+            desc.extend_from_slice(&padded_id(compatible_id)); // compatibleID
+            desc.extend_from_slice(&padded_id(sub_compatible_id)); // subCompatibleID
+            desc.extend_from_slice(&[0; 6]); // reserved
+        }
+        desc
+    }
+
+    /// Handle a vendor request, returns `None` if it is not a MS OS descriptor request
+    pub(crate) fn handle_request(&self, setup: &SetupPacket) -> Option<Vec<u8>> {
+        if setup.request != self.vendor_code {
+            return None;
+        }
+        match setup.index {
+            MS_OS_20_DESCRIPTOR_INDEX => {
+                debug!("Get MS OS 2.0 descriptor set");
+                Some(self.to_bytes())
+            }
+            MS_OS_10_EXTENDED_COMPAT_ID_INDEX if self.legacy_ms_os_10 => {
+                debug!("Get MS OS 1.0 extended compat ID descriptor");
+                Some(self.ms_os_10_extended_compat_id())
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::util::tests::*;
+
+    use super::*;
+
+    #[test]
+    fn winusb_descriptor_set() {
+        setup_test_logger();
+        let set = MsOs20DescriptorSet::winusb(0x20);
+        assert_eq!(
+            set.to_bytes(),
+            [
+                0x0A, 0x00, // wLength
+                0x00, 0x00, // wDescriptorType
+                0x00, 0x00, 0x03, 0x06, // dwWindowsVersion
+                0x1E, 0x00, // wTotalLength
+                0x14, 0x00, // wLength
+                0x03, 0x00, // wDescriptorType
+                b'W', b'I', b'N', b'U', b'S', b'B', 0x00, 0x00, // CompatibleID
+                0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // SubCompatibleID
+            ]
+        );
+        assert_eq!(
+            set.capability().to_bytes()[20..],
+            [0x00, 0x00, 0x03, 0x06, 0x1E, 0x00, 0x20, 0x00]
+        );
+    }
+
+    #[test]
+    fn function_subsets() {
+        setup_test_logger();
+        let set = MsOs20DescriptorSet::new(0x20).with_function(
+            1,
+            vec![
+                MsOs20Feature::winusb(),
+                MsOs20Feature::device_interface_guid("{00000000-0000-0000-0000-000000000000}"),
+            ],
+        );
+        let desc = set.to_bytes();
+        assert_eq!(desc.len(), u16::from_le_bytes([desc[8], desc[9]]) as usize);
+        // configuration subset spans the rest of the set
+        assert_eq!(
+            u16::from_le_bytes([desc[16], desc[17]]) as usize,
+            desc.len() - 10
+        );
+        // function subset
+        assert_eq!(desc[22], 1);
+        assert_eq!(
+            u16::from_le_bytes([desc[24], desc[25]]) as usize,
+            desc.len() - 18
+        );
+    }
+
+    #[test]
+    fn legacy_descriptors() {
+        setup_test_logger();
+        let set = MsOs20DescriptorSet::winusb(0x20).with_legacy_ms_os_10();
+        let string = set.ms_os_10_string_descriptor();
+        assert_eq!(string.len(), string[0] as usize);
+        assert_eq!(string[16], 0x20);
+
+        let compat = set.ms_os_10_extended_compat_id();
+        assert_eq!(compat.len(), 40);
+        assert_eq!(&compat[18..24], b"WINUSB");
+    }
+}
@@ -28,4 +28,15 @@ impl SetupPacket {
             length: (setup[7] as u16) << 8 | (setup[6] as u16),
         }
     }
+
+    /// Encode as a raw setup packet
+    pub fn to_bytes(&self) -> [u8; 8] {
+        let mut setup = [0; 8];
+        setup[0] = self.request_type;
+        setup[1] = self.request;
+        setup[2..4].copy_from_slice(&self.value.to_le_bytes());
+        setup[4..6].copy_from_slice(&self.index.to_le_bytes());
+        setup[6..8].copy_from_slice(&self.length.to_le_bytes());
+        setup
+    }
 }
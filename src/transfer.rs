@@ -0,0 +1,141 @@
+//! Transfers completing asynchronously
+use super::*;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::sync::oneshot;
+use tokio::time::Sleep;
+
+/// A transfer submitted to a device and not completed yet
+///
+/// Returned by handlers backed by real hardware, so that a URB waiting for the device does not block
+/// other URBs of the connection. Dropping it before completion cancels the transfer.
+pub struct PendingTransfer {
+    result: oneshot::Receiver<Result<Vec<u8>>>,
+    cancel: Option<Box<dyn FnOnce() + Send>>,
+    timeout: Option<Duration>,
+    /// Started when the transfer is first polled, within the runtime
+    deadline: Option<Pin<Box<Sleep>>>,
+}
+
+impl PendingTransfer {
+    /// Create a transfer completed by sending its result through the other end of `result`
+    pub fn new(result: oneshot::Receiver<Result<Vec<u8>>>) -> Self {
+        Self {
+            result,
+            cancel: None,
+            timeout: None,
+            deadline: None,
+        }
+    }
+
+    /// Set a function called to cancel the transfer when it is dropped before completion
+    pub fn with_cancel<F: FnOnce() + Send + 'static>(mut self, cancel: F) -> Self {
+        self.cancel = Some(Box::new(cancel));
+        self
+    }
+
+    /// Cancel the transfer and fail it with [ErrorKind::TimedOut] unless it completes within `timeout`
+    ///
+    /// A zero timeout waits until it completes.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout).filter(|timeout| !timeout.is_zero());
+        self
+    }
+}
+
+impl Future for PendingTransfer {
+    type Output = Result<Vec<u8>>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let res = match Pin::new(&mut self.result).poll(cx) {
+            Poll::Pending => {
+                if let Some(timeout) = self.timeout.take() {
+                    self.deadline = Some(Box::pin(tokio::time::sleep(timeout)));
+                }
+                match self
+                    .deadline
+                    .as_mut()
+                    .map(|deadline| deadline.as_mut().poll(cx))
+                {
+                    Some(Poll::Ready(())) => {
+                        if let Some(cancel) = self.cancel.take() {
+                            cancel();
+                        }
+                        return Poll::Ready(Err(std::io::Error::new(
+                            ErrorKind::TimedOut,
+                            "transfer timed out",
+                        )));
+                    }
+                    _ => return Poll::Pending,
+                }
+            }
+            Poll::Ready(Ok(res)) => res,
+            Poll::Ready(Err(_)) => Err(std::io::Error::new(
+                ErrorKind::Interrupted,
+                "transfer dropped before completion",
+            )),
+        };
+        // completed, nothing left to cancel
+        self.cancel = None;
+        Poll::Ready(res)
+    }
+}
+
+impl Drop for PendingTransfer {
+    fn drop(&mut self) {
+        if let Some(cancel) = self.cancel.take() {
+            cancel();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::tests::*;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    #[tokio::test]
+    async fn cancel_on_drop() {
+        setup_test_logger();
+        let cancelled = Arc::new(AtomicBool::new(false));
+
+        let (sender, receiver) = oneshot::channel();
+        let flag = cancelled.clone();
+        let transfer =
+            PendingTransfer::new(receiver).with_cancel(move || flag.store(true, Ordering::SeqCst));
+        sender.send(Ok(vec![1, 2, 3])).unwrap();
+        assert_eq!(transfer.await.unwrap(), [1, 2, 3]);
+        assert!(!cancelled.load(Ordering::SeqCst));
+
+        let (_sender, receiver) = oneshot::channel();
+        let flag = cancelled.clone();
+        let transfer =
+            PendingTransfer::new(receiver).with_cancel(move || flag.store(true, Ordering::SeqCst));
+        std::mem::drop(transfer);
+        assert!(cancelled.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn cancel_on_timeout() {
+        setup_test_logger();
+        let cancelled = Arc::new(AtomicBool::new(false));
+
+        let (_sender, receiver) = oneshot::channel();
+        let flag = cancelled.clone();
+        let transfer = PendingTransfer::new(receiver)
+            .with_cancel(move || flag.store(true, Ordering::SeqCst))
+            .with_timeout(Duration::from_millis(10));
+        let err = transfer.await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
+        assert!(cancelled.load(Ordering::SeqCst));
+
+        // no timeout
+        let (sender, receiver) = oneshot::channel();
+        let transfer = PendingTransfer::new(receiver).with_timeout(Duration::ZERO);
+        sender.send(Ok(vec![1])).unwrap();
+        assert_eq!(transfer.await.unwrap(), [1]);
+    }
+}
@@ -7,13 +7,17 @@
 //! They are based on the [Linux kernel documentation](https://docs.kernel.org/usb/usbip_protocol.html).
 
 use log::trace;
-use std::io::Result;
+use std::any::Any;
+use std::io::{ErrorKind, Result};
+use std::sync::{Arc, Mutex};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
 #[cfg(feature = "serde")]
 use serde::{Deserialize, Serialize};
 
-use crate::UsbDevice;
+use crate::device::Version;
+use crate::error::Error;
+use crate::{SetupPacket, UsbDevice, UsbEndpoint, UsbInterface, UsbInterfaceHandler};
 
 /// USB/IP protocol version
 ///
@@ -39,6 +43,65 @@ pub const USBIP_RET_SUBMIT: u16 = 0x0003;
 /// Reply code: Reply for URB unlink
 pub const USBIP_RET_UNLINK: u16 = 0x0004;
 
+/// Operation status: Request completed successfully
+pub const ST_OK: u32 = 0;
+/// Operation status: Request failed, e.g. the device is reserved for another client
+pub const ST_NA: u32 = 1;
+/// Operation status: Device imported by another client
+pub const ST_DEV_BUSY: u32 = 2;
+/// Operation status: Device in error state, e.g. its interfaces could not be claimed
+pub const ST_DEV_ERR: u32 = 3;
+/// Operation status: No device exported under the bus id
+pub const ST_NODEV: u32 = 4;
+/// Operation status: Unexpected error
+pub const ST_ERROR: u32 = 5;
+
+/// Describe the status of an OP_REP_DEVLIST or OP_REP_IMPORT, as the `usbip` tool does
+pub fn op_status_message(status: u32) -> &'static str {
+    match status {
+        ST_OK => "Request Completed Successfully",
+        ST_NA => "Request Failed",
+        ST_DEV_BUSY => "Device busy (exported)",
+        ST_DEV_ERR => "Device in error state",
+        ST_NODEV => "Device not found",
+        _ => "Unexpected response",
+    }
+}
+
+/// Largest transfer buffer accepted in a URB by default, guarding against lengths a peer could use to exhaust memory
+pub const USBIP_MAX_TRANSFER_BUFFER_LENGTH: u32 = 16 * 1024 * 1024;
+/// Most ISO packets accepted in a URB, as limited by the Linux kernel
+pub const USBIP_MAX_ISO_PACKETS: u32 = 1024;
+
+/// URB status: Endpoint stalled
+///
+/// URB status codes are Linux errno values, negated in [UsbIpResponse::UsbIpRetSubmit].
+pub const EPIPE: i32 = 32;
+/// URB status: Protocol error
+pub const EPROTO: i32 = 71;
+/// URB status: Unlinked before completion, reported in [UsbIpResponse::UsbIpRetUnlink]
+pub const ECONNRESET: i32 = 104;
+/// URB status: Not found
+pub const ENOENT: i32 = 2;
+/// URB status: I/O error
+pub const EIO: i32 = 5;
+/// URB status: Out of memory
+pub const ENOMEM: i32 = 12;
+/// URB status: Permission denied
+pub const EACCES: i32 = 13;
+/// URB status: Device or resource busy
+pub const EBUSY: i32 = 16;
+/// URB status: Device disconnected
+pub const ENODEV: i32 = 19;
+/// URB status: Invalid argument
+pub const EINVAL: i32 = 22;
+/// URB status: Device sent more data than requested
+pub const EOVERFLOW: i32 = 75;
+/// URB status: Operation not supported
+pub const EOPNOTSUPP: i32 = 95;
+/// URB status: Transfer timed out
+pub const ETIMEDOUT: i32 = 110;
+
 /// USB/IP direction
 ///
 /// NOTE: Must not be confused with rusb::Direction,
@@ -144,14 +207,21 @@ impl UsbIpCommand {
     ///
     /// This will consume a variable amount of bytes from the socket.
     /// It might fail if the bytes does not follow the USB/IP protocol properly.
-    pub async fn read_from_socket<T: AsyncReadExt + Unpin>(socket: &mut T) -> Result<UsbIpCommand> {
+    pub async fn read_from_socket<T: AsyncReadExt + Unpin>(
+        socket: &mut T,
+    ) -> std::result::Result<UsbIpCommand, Error> {
+        Self::read_from_socket_with_limit(socket, USBIP_MAX_TRANSFER_BUFFER_LENGTH).await
+    }
+
+    /// Like [UsbIpCommand::read_from_socket], accepting transfer buffers of up to `max_transfer_size` bytes
+    pub(crate) async fn read_from_socket_with_limit<T: AsyncReadExt + Unpin>(
+        socket: &mut T,
+        max_transfer_size: u32,
+    ) -> std::result::Result<UsbIpCommand, Error> {
         let version: u16 = socket.read_u16().await?;
 
         if version != 0 && version != USBIP_VERSION {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                format!("Unknown version: {:#04X}", version),
-            ));
+            return Err(Error::UnsupportedVersion(version));
         }
 
         let command: u16 = socket.read_u16().await?;
@@ -196,6 +266,11 @@ impl UsbIpCommand {
                 let mut setup = [0; 8];
                 socket.read_exact(&mut setup).await?;
 
+                check_limit(
+                    "transfer buffer length",
+                    transfer_buffer_length,
+                    max_transfer_size,
+                )?;
                 let data = if header.direction == Direction::In as u32 {
                     vec![]
                 } else {
@@ -209,6 +284,7 @@ impl UsbIpCommand {
                 // https://stackoverflow.com/questions/76899798/usb-ip-what-is-the-size-of-the-iso-packet-descriptor
                 let iso_packet_descriptor =
                     if number_of_packets != 0 && number_of_packets != 0xFFFFFFFF {
+                        check_limit("ISO packet count", number_of_packets, USBIP_MAX_ISO_PACKETS)?;
                         let mut result = vec![0; 16 * number_of_packets as usize];
                         socket.read_exact(&mut result).await?;
                         result
@@ -242,10 +318,7 @@ impl UsbIpCommand {
                     unlink_seqnum,
                 })
             }
-            _ => Err(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                format!("Unknown command: {:#04X}", command),
-            )),
+            _ => Err(Error::UnknownCommand(command.into())),
         }
     }
 
@@ -312,6 +385,7 @@ impl UsbIpCommand {
 /// Server side responses from the USB Host
 #[derive(Clone)]
 #[cfg_attr(feature = "serde", derive(Serialize))]
+#[allow(clippy::large_enum_variant)]
 pub enum UsbIpResponse {
     OpRepDevlist {
         status: u32,
@@ -386,7 +460,7 @@ impl UsbIpResponse {
                 let mut result =
                     Vec::with_capacity(48 + transfer_buffer.len() + iso_packet_descriptor.len());
 
-                debug_assert!(header.command == USBIP_RET_SUBMIT.into());
+                debug_assert!(header.command == u32::from(USBIP_RET_SUBMIT));
                 debug_assert!(if header.direction == Direction::In as u32 {
                     actual_length == transfer_buffer.len() as u32
                 } else {
@@ -407,7 +481,7 @@ impl UsbIpResponse {
             Self::UsbIpRetUnlink { ref header, status } => {
                 let mut result = Vec::with_capacity(48);
 
-                debug_assert!(header.command == USBIP_RET_UNLINK.into());
+                debug_assert!(header.command == u32::from(USBIP_RET_UNLINK));
 
                 result.extend_from_slice(&header.to_bytes());
                 result.extend_from_slice(&status.to_be_bytes());
@@ -421,15 +495,140 @@ impl UsbIpResponse {
         socket.write_all(&self.to_bytes()).await
     }
 
+    /// Constructs a [UsbIpResponse] from a socket, on the client side
+    ///
+    /// USBIP_RET_SUBMIT only carries data for URBs reading from the device. Servers do not always fill in the
+    /// direction of its header, so `is_in` tells it from the header, e.g. by looking up the seqnum of the URB.
+    pub async fn read_from_socket<T, F>(
+        socket: &mut T,
+        is_in: F,
+    ) -> std::result::Result<UsbIpResponse, Error>
+    where
+        T: AsyncReadExt + Unpin,
+        F: FnOnce(&UsbIpHeaderBasic) -> bool,
+    {
+        const RET_SUBMIT: u32 = USBIP_RET_SUBMIT as u32;
+        const RET_UNLINK: u32 = USBIP_RET_UNLINK as u32;
+
+        // replies to operations start with the version, replies to URBs with the command
+        let command = socket.read_u32().await?;
+        trace!("Received reply: {:#08X}, parsing...", command);
+
+        match command {
+            RET_SUBMIT => {
+                let header =
+                    UsbIpHeaderBasic::read_from_socket_with_command(socket, USBIP_RET_SUBMIT)
+                        .await?;
+                let status = socket.read_u32().await?;
+                let actual_length = socket.read_u32().await?;
+                let start_frame = socket.read_u32().await?;
+                let number_of_packets = socket.read_u32().await?;
+                let error_count = socket.read_u32().await?;
+
+                let mut _padding = [0; 8];
+                socket.read_exact(&mut _padding).await?;
+
+                check_limit(
+                    "transfer buffer length",
+                    actual_length,
+                    USBIP_MAX_TRANSFER_BUFFER_LENGTH,
+                )?;
+                let transfer_buffer = if is_in(&header) {
+                    let mut data = vec![0; actual_length as usize];
+                    socket.read_exact(&mut data).await?;
+                    data
+                } else {
+                    vec![]
+                };
+
+                // see UsbIpCommand::read_from_socket
+                let iso_packet_descriptor =
+                    if number_of_packets != 0 && number_of_packets != 0xFFFFFFFF {
+                        check_limit("ISO packet count", number_of_packets, USBIP_MAX_ISO_PACKETS)?;
+                        let mut result = vec![0; 16 * number_of_packets as usize];
+                        socket.read_exact(&mut result).await?;
+                        result
+                    } else {
+                        vec![]
+                    };
+
+                Ok(UsbIpResponse::UsbIpRetSubmit {
+                    header,
+                    status,
+                    actual_length,
+                    start_frame,
+                    number_of_packets,
+                    error_count,
+                    transfer_buffer,
+                    iso_packet_descriptor,
+                })
+            }
+            RET_UNLINK => {
+                let header =
+                    UsbIpHeaderBasic::read_from_socket_with_command(socket, USBIP_RET_UNLINK)
+                        .await?;
+                let status = socket.read_u32().await?;
+
+                let mut _padding = [0; 24];
+                socket.read_exact(&mut _padding).await?;
+
+                Ok(UsbIpResponse::UsbIpRetUnlink { header, status })
+            }
+            _ => {
+                let version = (command >> 16) as u16;
+                if version != USBIP_VERSION {
+                    return Err(Error::UnsupportedVersion(version));
+                }
+
+                match command as u16 {
+                    OP_REP_DEVLIST => {
+                        let status = socket.read_u32().await?;
+                        let device_count = socket.read_u32().await?;
+                        let mut devices = vec![];
+                        for _ in 0..device_count {
+                            devices.push(read_device(socket, true).await?);
+                        }
+
+                        Ok(UsbIpResponse::OpRepDevlist {
+                            status,
+                            device_count,
+                            devices,
+                        })
+                    }
+                    OP_REP_IMPORT => {
+                        let status = socket.read_u32().await?;
+                        let device = if status == 0 {
+                            Some(read_device(socket, false).await?)
+                        } else {
+                            None
+                        };
+
+                        Ok(UsbIpResponse::OpRepImport { status, device })
+                    }
+                    reply => Err(Error::UnknownCommand(reply.into())),
+                }
+            }
+        }
+    }
+
     /// Constructs a OP_REP_DEVLIST response
     pub fn op_rep_devlist(devices: &[UsbDevice]) -> Self {
         Self::OpRepDevlist {
-            status: 0,
+            status: ST_OK,
             device_count: devices.len() as u32,
             devices: devices.to_vec(),
         }
     }
 
+    /// Constructs a failed OP_REP_DEVLIST response, listing no device
+    pub fn op_rep_devlist_fail(status: u32) -> Self {
+        Self::OpRepDevlist {
+            status,
+            device_count: 0,
+            devices: vec![],
+        }
+    }
+
     /// Constructs a successful OP_REP_IMPORT response
     pub fn op_rep_import_success(device: &UsbDevice) -> Self {
         Self::OpRepImport {
@@ -438,10 +637,15 @@ impl UsbIpResponse {
         }
     }
 
-    /// Constructs a failed OP_REP_IMPORT response
+    /// Constructs a failed OP_REP_IMPORT response, with [ST_NA]
     pub fn op_rep_import_fail() -> Self {
+        Self::op_rep_import_fail_with_status(ST_NA)
+    }
+
+    /// Constructs a failed OP_REP_IMPORT response with a status telling why, e.g. [ST_DEV_BUSY]
+    pub fn op_rep_import_fail_with_status(status: u32) -> Self {
         Self::OpRepImport {
-            status: 1,
+            status,
             device: None,
         }
     }
@@ -480,6 +684,20 @@ impl UsbIpResponse {
         }
     }
 
+    /// Constructs a failed USBIP_RET_SUBMIT response with a URB status, e.g. `-EPIPE`
+    pub fn usbip_ret_submit_fail_with_status(header: &UsbIpHeaderBasic, status: i32) -> Self {
+        Self::UsbIpRetSubmit {
+            header: header.clone(),
+            status: status as u32,
+            actual_length: 0,
+            start_frame: 0,
+            number_of_packets: 0,
+            error_count: 0,
+            transfer_buffer: vec![],
+            iso_packet_descriptor: vec![],
+        }
+    }
+
     /// Constructs a successful OP_REP_IMPORT response
     pub fn usbip_ret_unlink_success(header: &UsbIpHeaderBasic) -> Self {
         Self::UsbIpRetUnlink {
@@ -495,6 +713,118 @@ impl UsbIpResponse {
             status: 1,
         }
     }
+
+    /// Constructs a USBIP_RET_UNLINK response with the status of the unlinked URB, e.g. `-ECONNRESET`
+    pub fn usbip_ret_unlink_with_status(header: &UsbIpHeaderBasic, status: i32) -> Self {
+        Self::UsbIpRetUnlink {
+            header: header.clone(),
+            status: status as u32,
+        }
+    }
+}
+
+/// Fail with [Error::LimitExceeded] if a length read from the peer is above `limit`
+fn check_limit(what: &'static str, value: u32, limit: u32) -> std::result::Result<(), Error> {
+    if value > limit {
+        return Err(Error::LimitExceeded {
+            what,
+            value: value.into(),
+            limit: limit.into(),
+        });
+    }
+    Ok(())
+}
+
+/// Read a device as listed in OP_REP_DEVLIST, or without its interface classes as in OP_REP_IMPORT
+///
+/// Its interfaces only keep their class codes, their URBs fail as they can not be served locally.
+async fn read_device<T: AsyncReadExt + Unpin>(
+    socket: &mut T,
+    with_interfaces: bool,
+) -> Result<UsbDevice> {
+    let mut path = [0; 256];
+    socket.read_exact(&mut path).await?;
+    let mut bus_id = [0; 32];
+    socket.read_exact(&mut bus_id).await?;
+
+    let bus_num = socket.read_u32().await?;
+    let dev_num = socket.read_u32().await?;
+    let speed = socket.read_u32().await?;
+    let vendor_id = socket.read_u16().await?;
+    let product_id = socket.read_u16().await?;
+    let device_bcd = socket.read_u16().await?;
+    let mut codes = [0; 6];
+    socket.read_exact(&mut codes).await?;
+    let [device_class, device_subclass, device_protocol, configuration_value, num_configurations, num_interfaces] =
+        codes;
+
+    let mut interfaces = vec![];
+    for _ in 0..num_interfaces {
+        let mut class = [0; 4];
+        if with_interfaces {
+            socket.read_exact(&mut class).await?;
+        }
+        interfaces.push(UsbInterface {
+            interface_class: class[0],
+            interface_subclass: class[1],
+            interface_protocol: class[2],
+            endpoints: vec![],
+            string_interface: 0,
+            class_specific_descriptor: vec![],
+            handler: Arc::new(Mutex::new(Box::new(RemoteInterfaceHandler))),
+        });
+    }
+
+    Ok(UsbDevice {
+        path: c_string(&path),
+        bus_id: c_string(&bus_id),
+        bus_num,
+        dev_num,
+        speed,
+        vendor_id,
+        product_id,
+        device_bcd: Version::from_bcd(device_bcd),
+        device_class,
+        device_subclass,
+        device_protocol,
+        configuration_value,
+        num_configurations,
+        interfaces,
+        ..UsbDevice::default()
+    })
+}
+
+/// Decode a NUL padded string
+fn c_string(bytes: &[u8]) -> String {
+    let len = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..len]).into_owned()
+}
+
+/// Handler of the interfaces of a device read from a remote server
+struct RemoteInterfaceHandler;
+
+impl UsbInterfaceHandler for RemoteInterfaceHandler {
+    fn get_class_specific_descriptor(&self) -> Vec<u8> {
+        vec![]
+    }
+
+    fn handle_urb(
+        &mut self,
+        _interface: &UsbInterface,
+        _ep: UsbEndpoint,
+        _transfer_buffer_length: u32,
+        _setup: SetupPacket,
+        _req: &[u8],
+    ) -> Result<Vec<u8>> {
+        Err(std::io::Error::new(
+            ErrorKind::NotConnected,
+            "interface of a device of a remote server",
+        ))
+    }
+
+    fn as_any(&mut self) -> &mut dyn Any {
+        self
+    }
 }
 
 #[cfg(test)]
@@ -638,6 +968,15 @@ mod tests {
         );
     }
 
+    #[test]
+    fn byte_serialize_device_bcd() {
+        setup_test_logger();
+        let mut device = example_device();
+        device.device_bcd = crate::device::Version::new(1, 2, 3);
+        // after path, bus id, bus number, device number, speed, vendor id and product id
+        assert_eq!(device.to_bytes()[304..306], [0x01, 0x23]);
+    }
+
     #[test]
     fn byte_serialize_op_rep_import() {
         setup_test_logger();
@@ -896,6 +1235,69 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn read_responses_from_socket() -> Result<()> {
+        setup_test_logger();
+        let mut device = example_device();
+        device.bus_id = "1-2".to_string();
+        device.vendor_id = 0x1234;
+        device.device_bcd = Version::new(1, 2, 3);
+        let device = device.with_interface(
+            0x02,
+            0x02,
+            0x00,
+            "Test",
+            vec![],
+            Arc::new(Mutex::new(
+                Box::new(RemoteInterfaceHandler) as Box<dyn UsbInterfaceHandler + Send>
+            )),
+        );
+        let header = UsbIpHeaderBasic {
+            command: USBIP_RET_SUBMIT.into(),
+            seqnum: 1,
+            devid: 2,
+            direction: 1,
+            ep: 3,
+        };
+
+        let responses = [
+            UsbIpResponse::op_rep_devlist(std::slice::from_ref(&device)),
+            UsbIpResponse::op_rep_import_success(&device),
+            UsbIpResponse::op_rep_import_fail(),
+            UsbIpResponse::usbip_ret_submit_success(&header, 0, 0, vec![1, 2, 3], vec![]),
+            UsbIpResponse::usbip_ret_submit_fail_with_status(&header, -EPIPE),
+            UsbIpResponse::usbip_ret_unlink_with_status(
+                &UsbIpHeaderBasic {
+                    command: USBIP_RET_UNLINK.into(),
+                    ..header.clone()
+                },
+                -ECONNRESET,
+            ),
+        ];
+        for response in responses {
+            let bytes = response.to_bytes();
+            // servers do not always fill in the direction of the reply
+            let res = UsbIpResponse::read_from_socket(&mut MockSocket::new(bytes.clone()), |h| {
+                h.seqnum == 1
+            })
+            .await?;
+            assert_eq!(res.to_bytes(), bytes);
+        }
+
+        let bytes = UsbIpResponse::op_rep_devlist(&[device]).to_bytes();
+        match UsbIpResponse::read_from_socket(&mut MockSocket::new(bytes), |_| false).await? {
+            UsbIpResponse::OpRepDevlist { devices, .. } => {
+                assert_eq!(devices[0].bus_id, "1-2");
+                assert_eq!(devices[0].vendor_id, 0x1234);
+                assert_eq!(devices[0].device_bcd.to_bcd(), 0x0123);
+                assert_eq!(devices[0].interfaces[0].interface_class, 0x02);
+            }
+            _ => panic!("expected OP_REP_DEVLIST"),
+        }
+
+        Ok(())
+    }
+
     #[tokio::test]
     async fn byte_serialization_fails_on_old_usbip_version() {
         setup_test_logger();
@@ -906,7 +1308,7 @@ mod tests {
 
         let mut socket = MockSocket::new(bytes);
         let result = UsbIpCommand::read_from_socket(&mut socket).await;
-        assert!(result.is_err());
+        assert!(matches!(result, Err(Error::UnsupportedVersion(0x0110))));
         assert_eq!(
             result.unwrap_err().to_string(),
             "Unknown version: 0x110".to_string()
@@ -923,10 +1325,40 @@ mod tests {
 
         let mut socket = MockSocket::new(bytes);
         let result = UsbIpCommand::read_from_socket(&mut socket).await;
-        assert!(result.is_err());
+        assert!(matches!(result, Err(Error::UnknownCommand(0x1005))));
         assert_eq!(
             result.unwrap_err().to_string(),
             "Unknown command: 0x1005".to_string()
         );
     }
+
+    #[tokio::test]
+    async fn oversized_transfer_buffer_rejected() {
+        setup_test_logger();
+
+        let cmd = UsbIpCommand::UsbIpCmdSubmit {
+            header: UsbIpHeaderBasic {
+                command: USBIP_CMD_SUBMIT.into(),
+                seqnum: 1,
+                devid: 0,
+                direction: Direction::In as u32,
+                ep: 1,
+            },
+            transfer_flags: 0,
+            transfer_buffer_length: USBIP_MAX_TRANSFER_BUFFER_LENGTH + 1,
+            start_frame: 0,
+            number_of_packets: 0,
+            interval: 0,
+            setup: [0; 8],
+            data: vec![],
+            iso_packet_descriptor: vec![],
+        };
+        let mut socket = MockSocket::new(cmd.to_bytes());
+        let result = UsbIpCommand::read_from_socket(&mut socket).await;
+        assert!(matches!(
+            result,
+            Err(Error::LimitExceeded { value, limit, .. })
+                if value == limit + 1 && limit == USBIP_MAX_TRANSFER_BUFFER_LENGTH as u64
+        ));
+    }
 }
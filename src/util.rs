@@ -7,6 +7,13 @@ pub fn verify_descriptor(desc: &[u8]) {
     assert_eq!(offset, desc.len());
 }
 
+/// Error to return from a handler to stall the endpoint
+///
+/// It is reported to the client as `-EPIPE`.
+pub fn stall() -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::BrokenPipe, "stall")
+}
+
 #[cfg(test)]
 pub(crate) mod tests {
     use std::{
@@ -0,0 +1,107 @@
+//! Implement WebUSB platform capability and URL descriptors
+use super::*;
+
+// reference:
+// WebUSB: https://wicg.github.io/webusb/#webusb-platform-capability-descriptor
+
+/// PlatformCapabilityUUID of WebUSB: {3408b638-09a9-47a0-8bfd-a0768815b665}
+pub const WEBUSB_UUID: [u8; 16] = [
+    0x38, 0xB6, 0x08, 0x34, 0xA9, 0x09, 0xA0, 0x47, 0x8B, 0xFD, 0xA0, 0x76, 0x88, 0x15, 0xB6, 0x65,
+];
+
+/// wIndex of the GET_URL vendor request
+pub const WEBUSB_GET_URL: u16 = 0x02;
+
+/// bDescriptorType of the URL descriptor
+pub const WEBUSB_URL_DESCRIPTOR_TYPE: u8 = 0x03;
+
+/// WebUSB support of a device
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct WebUsb {
+    /// bVendorCode used for WebUSB requests
+    pub vendor_code: u8,
+    /// URLs including scheme, the first one is the landing page
+    pub urls: Vec<String>,
+}
+
+impl WebUsb {
+    /// WebUSB with a landing page, e.g. `https://example.com`
+    pub fn new(vendor_code: u8, landing_page: &str) -> Self {
+        Self {
+            vendor_code,
+            urls: vec![landing_page.to_string()],
+        }
+    }
+
+    /// The platform capability to put in the BOS descriptor
+    pub fn capability(&self) -> BosCapability {
+        BosCapability::Platform {
+            uuid: WEBUSB_UUID,
+            data: vec![
+                0x00,
+                0x01,                                     // bcdVersion 1.0
+                self.vendor_code,                         // bVendorCode
+                if self.urls.is_empty() { 0 } else { 1 }, // iLandingPage
+            ],
+        }
+    }
+
+    /// Get the URL descriptor at `index`, starting from 1
+    ///
+    /// Returns `None` for URLs whose descriptor would exceed 255 bytes, i.e. longer than 252 bytes after the scheme.
+    pub fn url_descriptor(&self, index: u8) -> Option<Vec<u8>> {
+        let url = self.urls.get((index as usize).checked_sub(1)?)?;
+        let (scheme, rest) = if let Some(rest) = url.strip_prefix("https://") {
+            (0x01, rest)
+        } else if let Some(rest) = url.strip_prefix("http://") {
+            (0x00, rest)
+        } else {
+            (0xFF, url.as_str())
+        };
+        if rest.len() > 0xFF - 3 {
+            warn!("WebUSB URL {} is too long for its descriptor", url);
+            return None;
+        }
+        let mut desc = vec![
+            (3 + rest.len()) as u8,     // bLength
+            WEBUSB_URL_DESCRIPTOR_TYPE, // bDescriptorType: WebUSB URL
+            scheme,                     // bScheme
+        ];
+        desc.extend_from_slice(rest.as_bytes()); // URL
+        Some(desc)
+    }
+
+    /// Handle a vendor request, returns `None` if it is not a WebUSB request
+    pub(crate) fn handle_request(&self, setup: &SetupPacket) -> Option<Vec<u8>> {
+        if setup.request != self.vendor_code || setup.index != WEBUSB_GET_URL {
+            return None;
+        }
+        debug!("Get WebUSB URL descriptor {}", setup.value);
+        self.url_descriptor(setup.value as u8)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::util::tests::*;
+
+    use super::*;
+
+    #[test]
+    fn url_descriptor() {
+        setup_test_logger();
+        let webusb = WebUsb::new(0x01, "https://example.com");
+        assert_eq!(
+            webusb.url_descriptor(1).unwrap(),
+            [&[0x0E, 0x03, 0x01][..], b"example.com"].concat()
+        );
+        assert!(webusb.url_descriptor(0).is_none());
+        assert!(webusb.url_descriptor(2).is_none());
+        let long = WebUsb::new(0x01, &format!("https://{}", "a".repeat(253)));
+        assert!(long.url_descriptor(1).is_none());
+        let longest = WebUsb::new(0x01, &format!("https://{}", "a".repeat(252)));
+        assert_eq!(longest.url_descriptor(1).unwrap()[0], 0xFF);
+        assert_eq!(webusb.capability().to_bytes().len(), 24);
+    }
+}